@@ -1,14 +1,15 @@
-use server::Server;
-
-mod messages;
-mod server;
+use dn_mess::server::{config::ServerConfig, Server};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Start the logger
     env_logger::init();
 
-    let server = Server::new().await;
+    let server = Server::new(ServerConfig::default(), vec![]).await;
+
+    // Operators flush the cache with SIGHUP, e.g. after a record changes upstream
+    #[cfg(unix)]
+    server.flush_cache_on_hangup()?;
 
     server.listen(8080).await
 }
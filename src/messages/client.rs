@@ -1,50 +1,159 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use rand::prelude::StdRng;
 use rand::{Rng, SeedableRng};
-use tokio::{net::UdpSocket, sync::RwLock};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::{Mutex, RwLock},
+    time,
+};
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
 
-use crate::messages::packets::{Question, QuestionClass, ResponseCode};
+use crate::messages::packets::{EdnsOption, OptRecord, Question};
 
 use super::{
     coding::MessageCoder,
-    network_buffer::NetworkBuffer,
-    packets::{Message, PacketType, ResourceRecordType},
+    network_buffer::{NetworkBuffer, MAX_MESSAGE_SIZE},
+    packets::{Message, ResourceRecordType},
+    stream::StreamConnection,
+    transport::DatagramTransport,
 };
 
 type ClientResult<T> = Result<T, Box<dyn std::error::Error>>;
 
-pub struct Client {
+/// How long a query waits for its response before giving up
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How messages reach the upstream server
+enum Transport<T> {
+    Udp(Arc<T>),
+    /// Length prefixed messages over a TLS session, one query in flight at a time
+    Tls(Box<Mutex<TlsStream<TcpStream>>>),
+}
+
+pub struct Client<T = UdpSocket> {
     addr: SocketAddr,
-    sock: Arc<UdpSocket>,
+    transport: Transport<T>,
     rng: RwLock<StdRng>,
+    timeout: Duration,
+}
+
+/// Check the response answers the query, rather than being late or spoofed
+fn check_id(query: &Message, response: Message) -> ClientResult<Message> {
+    if response.id != query.id {
+        return Err(format!(
+            "Response ID {} doesn't match query ID {}",
+            response.id, query.id
+        )
+        .into());
+    }
+
+    Ok(response)
 }
 
 impl Client {
     /// Dial and connect to a remote address. The client will only read messages from the given remote address.
     pub async fn dial(addr: SocketAddr) -> ClientResult<Client> {
-        // Bind our socket
-        let sock = Arc::new(UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?);
+        Client::dial_with_rng(addr, SeedableRng::from_entropy()).await
+    }
+
+    /// Dial with the given source of query IDs, a seeded RNG makes the IDs predictable for tests
+    pub async fn dial_with_rng(addr: SocketAddr, rng: StdRng) -> ClientResult<Client> {
+        // Bind our socket, of the same address family as the remote
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0)),
+        };
+        let sock = Arc::new(UdpSocket::bind(local).await?);
 
         // Connect socket to address, so we only receive messages from that address
         sock.connect(addr).await?;
 
+        Ok(Client {
+            addr,
+            transport: Transport::Udp(sock),
+            rng: RwLock::new(rng),
+            timeout: QUERY_TIMEOUT,
+        })
+    }
+
+    /// Dial a DNS-over-TLS upstream, verifying its certificate against the web PKI roots
+    pub async fn dial_tls(addr: SocketAddr, server_name: &str) -> ClientResult<Client> {
+        let roots = rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Client::dial_tls_with_config(addr, server_name, Arc::new(config)).await
+    }
+
+    /// Dial a DNS-over-TLS upstream with the given TLS client configuration
+    pub async fn dial_tls_with_config(
+        addr: SocketAddr,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> ClientResult<Client> {
+        let server_name = rustls::pki_types::ServerName::try_from(server_name.to_string())?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await?;
+
         let rng: RwLock<StdRng> = RwLock::new(SeedableRng::from_entropy());
 
-        Ok(Client { addr, sock, rng })
+        Ok(Client {
+            addr,
+            transport: Transport::Tls(Box::new(Mutex::new(stream))),
+            rng,
+            timeout: QUERY_TIMEOUT,
+        })
+    }
+}
+
+impl<T: DatagramTransport> Client<T> {
+    /// Client sending datagrams to the remote address over the given transport
+    pub fn with_transport(addr: SocketAddr, transport: Arc<T>) -> Client<T> {
+        let rng: RwLock<StdRng> = RwLock::new(SeedableRng::from_entropy());
+
+        Client {
+            addr,
+            transport: Transport::Udp(transport),
+            rng,
+            timeout: QUERY_TIMEOUT,
+        }
+    }
+
+    /// Wait at most the given time for each response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
 
     /// Send request to connected upstream server
     pub async fn send(&self, message: &Message, buf: &mut NetworkBuffer) -> ClientResult<()> {
+        let sock = match &self.transport {
+            Transport::Udp(sock) => sock,
+            Transport::Tls(stream) => {
+                StreamConnection::new()
+                    .write_message(&mut *stream.lock().await, message)
+                    .await
+                    .map_err(|err| err.to_string())?;
+
+                return Ok(());
+            }
+        };
+
         // Encode the message, MessageCoder instances should be ephemeral
         MessageCoder::new().encode_message(message, buf)?;
 
         // Only write the length of the buffer
         let buffer_length = buf.write_count();
 
-        self.sock
-            .send_to(&buf.buf[..buffer_length], self.addr)
-            .await?;
+        sock.send_to(&buf.buf[..buffer_length], self.addr).await?;
 
         // Reset the buffer
         buf.reset();
@@ -60,40 +169,506 @@ impl Client {
         &self,
         domain: &str,
         request_type: ResourceRecordType,
+    ) -> ClientResult<Message> {
+        self.query_with_options(domain, request_type, vec![]).await
+    }
+
+    /// Query with the given EDNS options, the query only uses EDNS if there are options to send
+    pub async fn query_with_options(
+        &self,
+        domain: &str,
+        request_type: ResourceRecordType,
+        options: Vec<EdnsOption>,
     ) -> ClientResult<Message> {
         let mut buf = NetworkBuffer::new();
 
-        // Create RNG to generate ID's for queries
+        let mut additional_records = vec![];
+
+        if !options.is_empty() {
+            let mut opt = OptRecord::new(MAX_MESSAGE_SIZE as u16);
+            opt.options = options;
+            additional_records.push(opt.to_resource_record());
+        }
 
+        // Single question
         let message = Message {
-            id: self.generate_id().await,
-            packet_type: PacketType::Query,
+            additional_records,
+            ..Message::query(
+                self.generate_id().await,
+                Question::new(domain, request_type),
+            )
+        };
+
+        let sock = match &self.transport {
+            Transport::Udp(sock) => sock,
+            Transport::Tls(stream) => {
+                // Hold the stream for the whole exchange so responses aren't interleaved
+                let mut stream = stream.lock().await;
+                let mut connection = StreamConnection::new();
+
+                let response = time::timeout(self.timeout, async {
+                    connection
+                        .write_message(&mut *stream, &message)
+                        .await
+                        .map_err(|err| err.to_string())?;
+
+                    connection
+                        .read_message(&mut *stream)
+                        .await
+                        .map_err(|err| err.to_string())?
+                        .ok_or_else(|| "Connection closed before response".to_string())
+                })
+                .await??;
+
+                return check_id(&message, response);
+            }
+        };
+
+        // Send the message
+        self.send(&message, &mut buf).await?;
+
+        let deadline = time::Instant::now() + self.timeout;
+
+        let response = loop {
+            // Read datagram from socket
+            let (len, _) = time::timeout_at(deadline, sock.recv_from(&mut buf.buf)).await??;
+
+            // Mark how much of the buffer holds the datagram
+            buf.set_write_position(len);
+
+            // Decode message
+            let response = MessageCoder::new().decode_message(&mut buf)?;
+
+            // Reset the buffer for the next datagram
+            buf.reset();
+
+            // A late response to an earlier query or a spoofed one, keep waiting for ours
+            if response.id == message.id {
+                break response;
+            }
+        };
+
+        // The full response didn't fit in a datagram, ask again over TCP
+        if response.truncation {
+            return self.query_tcp(&message).await;
+        }
+
+        Ok(response)
+    }
+
+    /// Send the query over a new TCP connection to the upstream
+    async fn query_tcp(&self, message: &Message) -> ClientResult<Message> {
+        let response = time::timeout(self.timeout, async {
+            let mut stream = TcpStream::connect(self.addr)
+                .await
+                .map_err(|err| err.to_string())?;
+            let mut connection = StreamConnection::new();
+
+            connection
+                .write_message(&mut stream, message)
+                .await
+                .map_err(|err| err.to_string())?;
+
+            connection
+                .read_message(&mut stream)
+                .await
+                .map_err(|err| err.to_string())?
+                .ok_or_else(|| "Connection closed before response".to_string())
+        })
+        .await??;
+
+        check_id(message, response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, net::Ipv4Addr, sync::Mutex as StdMutex};
+
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::messages::packets::{
+        PacketType, ResourceRecord, ResourceRecordClass, ResourceRecordData, ResponseCode,
+    };
+
+    #[tokio::test]
+    async fn test_query_over_tls() {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["upstream.test".to_string()]).unwrap();
+
+        let server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certificate.cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::Pkcs8(
+                    certificate.key_pair.serialize_der().into(),
+                ),
+            )
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Upstream answering every query with a canned A record
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = TlsAcceptor::from(Arc::new(server_config))
+                .accept(stream)
+                .await
+                .unwrap();
+
+            let mut connection = StreamConnection::new();
+            let mut message = connection.read_message(&mut stream).await.unwrap().unwrap();
+
+            message.packet_type = PacketType::Response;
+            message.answers.push(ResourceRecord {
+                domain: message.questions[0].domain.clone(),
+                record_type: ResourceRecordType::ARecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 300,
+                data: ResourceRecordData::A(Ipv4Addr::new(1, 2, 3, 4)),
+            });
+
+            connection
+                .write_message(&mut stream, &message)
+                .await
+                .unwrap();
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(certificate.cert.der().clone()).unwrap();
+
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let client = Client::dial_tls_with_config(addr, "upstream.test", Arc::new(client_config))
+            .await
+            .unwrap();
+
+        let response = client
+            .query("example.com.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_seeded_client_query_ids() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 53));
+
+        let client = Client::dial_with_rng(addr, StdRng::seed_from_u64(1130))
+            .await
+            .unwrap();
+
+        let mut expected = StdRng::seed_from_u64(1130);
+
+        for _ in 0..3 {
+            assert_eq!(client.generate_id().await, expected.gen::<u16>());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_ipv6_upstream() {
+        let upstream = UdpSocket::bind("[::1]:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = NetworkBuffer::new();
+            let (len, from) = upstream.recv_from(&mut buf.buf).await.unwrap();
+            buf.set_write_position(len);
+
+            let mut message = MessageCoder::new().decode_message(&mut buf).unwrap();
+            message.packet_type = PacketType::Response;
+
+            let mut buf = NetworkBuffer::new();
+            MessageCoder::new()
+                .encode_message(&message, &mut buf)
+                .unwrap();
+            upstream
+                .send_to(&buf.buf[..buf.write_count()], from)
+                .await
+                .unwrap();
+        });
+
+        let client = Client::dial(addr).await.unwrap();
+
+        let response = client
+            .query("example.com.", ResourceRecordType::AAAARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.packet_type, PacketType::Response);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_response_retried_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+
+        // UDP upstream flagging every response as truncated
+        tokio::spawn(async move {
+            let mut buf = NetworkBuffer::new();
+            let (len, from) = socket.recv_from(&mut buf.buf).await.unwrap();
+            buf.set_write_position(len);
+
+            let mut message = MessageCoder::new().decode_message(&mut buf).unwrap();
+            message.packet_type = PacketType::Response;
+            message.truncation = true;
+
+            let mut buf = NetworkBuffer::new();
+            MessageCoder::new()
+                .encode_message(&message, &mut buf)
+                .unwrap();
+            socket
+                .send_to(&buf.buf[..buf.write_count()], from)
+                .await
+                .unwrap();
+        });
+
+        // TCP upstream with the full answer
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut connection = StreamConnection::new();
+            let mut message = connection.read_message(&mut stream).await.unwrap().unwrap();
+
+            message.packet_type = PacketType::Response;
+            message.answers.push(ResourceRecord {
+                domain: message.questions[0].domain.clone(),
+                record_type: ResourceRecordType::ARecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 300,
+                data: ResourceRecordData::A(Ipv4Addr::new(1, 2, 3, 4)),
+            });
+
+            connection
+                .write_message(&mut stream, &message)
+                .await
+                .unwrap();
+        });
+
+        let client = Client::dial(addr).await.unwrap();
+
+        let response = client
+            .query("example.com.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert!(!response.truncation);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_with_other_id_ignored() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        // Upstream sending a response to another query before the answer
+        tokio::spawn(async move {
+            let mut buf = NetworkBuffer::new();
+            let (len, from) = upstream.recv_from(&mut buf.buf).await.unwrap();
+            buf.set_write_position(len);
+
+            let mut message = MessageCoder::new().decode_message(&mut buf).unwrap();
+            message.packet_type = PacketType::Response;
+
+            let mut other = message.clone();
+            other.id = message.id.wrapping_add(1);
+            other.response_code = ResponseCode::NameError;
+
+            for message in [other, message] {
+                let mut buf = NetworkBuffer::new();
+                MessageCoder::new()
+                    .encode_message(&message, &mut buf)
+                    .unwrap();
+                upstream
+                    .send_to(&buf.buf[..buf.write_count()], from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = Client::dial(addr).await.unwrap();
+
+        let response = client
+            .query("example.com.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.response_code, ResponseCode::None);
+    }
+
+    #[tokio::test]
+    async fn test_query_times_out() {
+        // Upstream that never responds
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let client = Client::dial(upstream.local_addr().unwrap())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+
+        assert!(client
+            .query("example.com.", ResourceRecordType::ARecord)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_response_with_other_id_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+
+        // UDP upstream flagging every response as truncated
+        tokio::spawn(async move {
+            let mut buf = NetworkBuffer::new();
+            let (len, from) = socket.recv_from(&mut buf.buf).await.unwrap();
+            buf.set_write_position(len);
+
+            let mut message = MessageCoder::new().decode_message(&mut buf).unwrap();
+            message.packet_type = PacketType::Response;
+            message.truncation = true;
+
+            let mut buf = NetworkBuffer::new();
+            MessageCoder::new()
+                .encode_message(&message, &mut buf)
+                .unwrap();
+            socket
+                .send_to(&buf.buf[..buf.write_count()], from)
+                .await
+                .unwrap();
+        });
+
+        // TCP upstream answering with the wrong ID
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut connection = StreamConnection::new();
+            let mut message = connection.read_message(&mut stream).await.unwrap().unwrap();
+
+            message.packet_type = PacketType::Response;
+            message.id = message.id.wrapping_add(1);
+
+            connection
+                .write_message(&mut stream, &message)
+                .await
+                .unwrap();
+        });
+
+        let client = Client::dial(addr).await.unwrap();
+
+        assert!(client
+            .query("example.com.", ResourceRecordType::ARecord)
+            .await
+            .is_err());
+    }
+
+    /// In-memory transport recording sent datagrams and answering the last with canned responses
+    struct MockTransport {
+        sent: StdMutex<Vec<Vec<u8>>>,
+        responses: StdMutex<Vec<Vec<u8>>>,
+        remote: SocketAddr,
+    }
+
+    impl DatagramTransport for MockTransport {
+        async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+            self.sent.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let response = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop()
+                .ok_or(io::ErrorKind::WouldBlock)?;
+
+            buf[..response.len()].copy_from_slice(&response);
+
+            // Answer with the ID of the query sent
+            if let Some(query) = self.sent.lock().unwrap().last() {
+                buf[..2].copy_from_slice(&query[..2]);
+            }
+
+            Ok((response.len(), self.remote))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_over_mock_transport() {
+        let remote = SocketAddr::from(([192, 0, 2, 53], 53));
+
+        let response = Message {
+            id: 0,
+            packet_type: PacketType::Response,
             op_code: 0,
             authoritative_answer: false,
             truncation: false,
             recursion_desired: true,
-            recursion_available: false,
+            recursion_available: true,
+            authentic_data: false,
+            checking_disabled: false,
             response_code: ResponseCode::None,
-            // Single question
-            questions: vec![Question {
-                domain: domain.to_string(),
-                question_type: request_type,
-                class: QuestionClass::InternetAddress,
+            questions: vec![Question::new("example.com.", ResourceRecordType::ARecord)],
+            answers: vec![ResourceRecord {
+                domain: "example.com.".to_string(),
+                record_type: ResourceRecordType::ARecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 300,
+                data: ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)),
             }],
-            answers: vec![],
             authorities: vec![],
             additional_records: vec![],
         };
 
-        // Send the message
-        self.send(&message, &mut buf).await?;
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&response, &mut buf)
+            .unwrap();
+
+        let transport = Arc::new(MockTransport {
+            sent: StdMutex::new(vec![]),
+            responses: StdMutex::new(vec![buf.buf[..buf.write_count()].to_vec()]),
+            remote,
+        });
 
-        // Read datagram from socket
-        let (_len, _) = self.sock.recv_from(&mut buf.buf).await?;
+        let client = Client::with_transport(remote, transport.clone());
 
-        // Decode message
-        let message = MessageCoder::new().decode_message(&mut buf)?;
+        let answer = client
+            .query("example.com.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            answer.answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34))
+        );
+
+        // The query was sent through the transport
+        let sent = transport.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+
+        let mut buf = NetworkBuffer::new();
+        buf.buf[..sent[0].len()].copy_from_slice(&sent[0]);
+        buf.set_write_position(sent[0].len());
 
-        return Ok(message);
+        let query = MessageCoder::new().decode_message(&mut buf).unwrap();
+        assert_eq!(query.packet_type, PacketType::Query);
+        assert_eq!(query.questions[0].domain, "example.com.");
     }
 }
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
-use std::{usize, vec};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::vec;
 
 use super::errors::NetworkBufferError;
 use super::network_buffer::NetworkBuffer;
 
 use super::packets::{
-    Message, PacketType, Question, QuestionClass, ResourceRecord, ResourceRecordClass,
-    ResourceRecordData, ResourceRecordType, ResponseCode, SOARecord,
+    txt_chunks, ClientSubnet, EdnsOption, ExtendedErrorCode, Message, OptRecord, PacketType,
+    Question, QuestionClass, ResourceRecord, ResourceRecordClass, ResourceRecordData,
+    ResourceRecordType, ResponseCode, SOARecord, SVCBRecord,
 };
 
 type CodingResult<T> = Result<T, NetworkBufferError>;
@@ -14,23 +16,148 @@ type CodingResult<T> = Result<T, NetworkBufferError>;
 const MAX_NAME_LENGTH: usize = 255;
 const MAX_LABEL_LENGTH: usize = 63;
 
+// Name pointers have fourteen bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+// Smallest possible encodings, a root name followed by the fixed size fields
+const MIN_QUESTION_LENGTH: usize = 5;
+const MIN_RESOURCE_RECORD_LENGTH: usize = 11;
+
+// LOC record data has a fixed layout, for the only version defined (RFC 1876)
+const LOC_RECORD_LENGTH: usize = 16;
+
+/// Raw bytes of a label, `\DDD` escapes are replaced by the byte with that decimal value
+pub(crate) fn label_bytes(label: &str) -> Vec<u8> {
+    let bytes = label.as_bytes();
+    let mut raw = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 4)
+            .filter(|digits| bytes[index] == b'\\' && digits.iter().all(u8::is_ascii_digit))
+            .and_then(|digits| std::str::from_utf8(digits).ok()?.parse::<u8>().ok());
+
+        match escaped {
+            Some(byte) => {
+                raw.push(byte);
+                index += 4;
+            }
+            None => {
+                raw.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    raw
+}
+
+/// Length of a name once written as uncompressed labels, the root label included
+fn wire_name_length(name: &str) -> usize {
+    name.split('.')
+        .filter(|label| !label.is_empty())
+        .map(|label| label_bytes(label).len() + 1)
+        .sum::<usize>()
+        + 1
+}
+
+/// Text of a label's raw bytes, UTF-8 if valid, otherwise non-ASCII bytes are escaped as `\DDD`
+///
+/// Backslashes and dots are always escaped, so the text encodes back to the same bytes and a dot
+/// within a label isn't read as a label separator.
+fn label_text(raw: &[u8]) -> String {
+    let escape = |byte: u8| format!("\\{:03}", byte);
+
+    match std::str::from_utf8(raw) {
+        Ok(text) => text
+            .replace('\\', &escape(b'\\'))
+            .replace('.', &escape(b'.')),
+        Err(_) => raw
+            .iter()
+            .map(|byte| match byte {
+                b'\\' | b'.' | 0x80.. => escape(*byte),
+                _ => (*byte as char).to_string(),
+            })
+            .collect(),
+    }
+}
+
+/// Encode a client subnet option, the address is truncated to the bytes covering the source prefix
+///
+/// ```text
+/// FAMILY encoded as a 16 bit integer, 1 for IPv4 and 2 for IPv6
+/// SOURCE PREFIX-LENGTH encoded as an 8 bit integer
+/// SCOPE PREFIX-LENGTH encoded as an 8 bit integer
+/// ADDRESS encoded as the first ceil(SOURCE PREFIX-LENGTH / 8) bytes
+/// ```
+fn encode_client_subnet(subnet: &ClientSubnet) -> Vec<u8> {
+    let (family, address) = match subnet.address {
+        IpAddr::V4(address) => (1u16, address.octets().to_vec()),
+        IpAddr::V6(address) => (2u16, address.octets().to_vec()),
+    };
+
+    let address_length = (subnet.source_prefix_length as usize).div_ceil(8);
+
+    let mut data = family.to_be_bytes().to_vec();
+    data.push(subnet.source_prefix_length);
+    data.push(subnet.scope_prefix_length);
+    data.extend_from_slice(&address[..address_length.min(address.len())]);
+    data
+}
+
+/// Decode a client subnet option, None if the family is unknown or the address too long
+fn decode_client_subnet(data: &[u8]) -> Option<ClientSubnet> {
+    if data.len() < 4 {
+        return None;
+    }
+
+    let family = u16::from_be_bytes([data[0], data[1]]);
+    let address = &data[4..];
+
+    let address = match family {
+        1 if address.len() <= 4 => {
+            let mut octets = [0u8; 4];
+            octets[..address.len()].copy_from_slice(address);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        2 if address.len() <= 16 => {
+            let mut octets = [0u8; 16];
+            octets[..address.len()].copy_from_slice(address);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    Some(ClientSubnet {
+        source_prefix_length: data[2],
+        scope_prefix_length: data[3],
+        address,
+    })
+}
+
 pub struct MessageCoder {
+    /// Offsets of names already written, filled as names are encoded
     encoded_names: HashMap<String, usize>,
-    decoded_names: HashMap<usize, String>,
 }
 
 impl MessageCoder {
     pub fn new() -> MessageCoder {
         MessageCoder {
-            decoded_names: HashMap::new(),
             encoded_names: HashMap::new(),
         }
     }
 
     // Adds a name to the name cache, to be used to encode pointers.
+    //
+    // Names past the offsets a pointer can hold, only possible in stream messages, aren't added.
     pub fn set_compressed_name(&mut self, name: &str, buf: &NetworkBuffer) {
         let compressed_index = buf.write_count();
 
+        if compressed_index > MAX_POINTER_OFFSET {
+            return;
+        }
+
         self.encoded_names
             .insert(name.to_string(), compressed_index);
     }
@@ -44,24 +171,27 @@ impl MessageCoder {
     ///
     /// A name is made up of multiple labels, for example www.google.com. has labels www, google, com.
     /// A label is encoded with a length byte, that number of bytes and a null terminating byte.
+    /// Labels are arbitrary bytes, written as UTF-8 with `\DDD` escapes for any other byte.
     pub fn encode_label(&mut self, label: &str, buf: &mut NetworkBuffer) -> CodingResult<usize> {
+        let raw = label_bytes(label);
+
         // Check label length limits, error if invalid
-        if label.len() > MAX_LABEL_LENGTH {
+        if raw.len() > MAX_LABEL_LENGTH {
             return Err(NetworkBufferError::InvalidLabelLengthError(
                 label.to_string(),
             ));
         }
 
         // Setting label length
-        buf.put_u8(label.len() as u8)?;
+        buf.put_u8(raw.len() as u8)?;
 
-        // Add each character
-        for character in label.chars() {
-            buf.put_u8(character as u8)?;
+        // Add each byte
+        for byte in &raw {
+            buf.put_u8(*byte)?;
         }
 
         // Returning the number of bytes written
-        Ok(label.len() + 1)
+        Ok(raw.len() + 1)
     }
 
     /// Write the compressed offset to the given buffer
@@ -83,24 +213,42 @@ impl MessageCoder {
     ///
     /// The name is encoded as either as labels, or a pointer to another set of labels previously encoded
     pub fn encode_name(&mut self, name: &str, buf: &mut NetworkBuffer) -> CodingResult<usize> {
-        // Check name length limits, error if invalid
-        if name.len() > MAX_NAME_LENGTH {
+        // Check name length limits on the bytes written rather than the escaped text
+        if wire_name_length(name) > MAX_NAME_LENGTH {
             return Err(NetworkBufferError::InvalidNameLengthError(name.to_string()));
         }
 
-        let starting_index = buf.write_cursor;
+        let starting_index = buf.write_position();
 
         // Check if domain has already been encoded, and we can write a pointer rather than the labels
         if let Some(index) = self.get_compressed_name(name) {
             self.write_compressed_name(*index, buf)?;
 
             // Once a pointer is written, exit.
-            return Ok(buf.write_cursor - starting_index);
+            return Ok(buf.write_position() - starting_index);
         };
 
         // Add name to pointer cache.
         self.set_compressed_name(name, buf);
 
+        self.encode_name_uncompressed(name, buf)
+    }
+
+    /// Encodes the given name as labels, for record data which mustn't be compressed
+    ///
+    /// The name is not added to the pointer cache either, so later names never point into it
+    pub fn encode_name_uncompressed(
+        &mut self,
+        name: &str,
+        buf: &mut NetworkBuffer,
+    ) -> CodingResult<usize> {
+        // Check name length limits on the bytes written rather than the escaped text
+        if wire_name_length(name) > MAX_NAME_LENGTH {
+            return Err(NetworkBufferError::InvalidNameLengthError(name.to_string()));
+        }
+
+        let starting_index = buf.write_position();
+
         // Split the name into labels
         let labels = name.split('.');
 
@@ -111,20 +259,53 @@ impl MessageCoder {
             }
 
             // Add length plus one for length byte
-            self.encode_label(&label.to_string(), buf)?;
+            self.encode_label(label, buf)?;
         }
 
         // Set the null byte
         buf.put_u8(0x00)?;
 
         // Return length for null byte
-        Ok(buf.write_cursor - starting_index)
+        Ok(buf.write_position() - starting_index)
     }
 
-    /// Encode the given resource record
+    /// Encodes the given record type as a 16 bit integer
+    pub fn encode_type(
+        &mut self,
+        record_type: &ResourceRecordType,
+        buf: &mut NetworkBuffer,
+    ) -> CodingResult<usize> {
+        let type_bytes: u16 = match record_type {
+            ResourceRecordType::ARecord => 0x01,
+            ResourceRecordType::AAAARecord => 0x1C,
+            ResourceRecordType::CNameRecord => 0x05,
+            ResourceRecordType::MXRecord => 0x0F,
+            ResourceRecordType::NSRecord => 0x02,
+            ResourceRecordType::PTRRecord => 0x0C,
+            ResourceRecordType::SOARecord => 0x06,
+            ResourceRecordType::SRVRecord => 0x21,
+            ResourceRecordType::TXTRecord => 0x10,
+            ResourceRecordType::SPFRecord => 0x63,
+            ResourceRecordType::LOCRecord => 0x1D,
+            ResourceRecordType::OPTRecord => 0x29,
+            ResourceRecordType::SVCBRecord => 0x40,
+            ResourceRecordType::HTTPSRecord => 0x41,
+            ResourceRecordType::TLSARecord => 0x34,
+            ResourceRecordType::DSRecord => 0x2B,
+            ResourceRecordType::RRSIGRecord => 0x2E,
+            ResourceRecordType::NSECRecord => 0x2F,
+            ResourceRecordType::DNSKEYRecord => 0x30,
+            ResourceRecordType::NSEC3Record => 0x32,
+            ResourceRecordType::Unimplemented(code) => *code,
+        };
+
+        buf.put_u16(type_bytes)
+    }
 
+    /// Encode the given resource record
+    ///
     /// Resource records have the following structure
-    /// ```
+    /// ```text
     /// 0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
     /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     /// |                                               |
@@ -154,44 +335,45 @@ impl MessageCoder {
         self.encode_name(&resource_record.domain, buf)?;
 
         // Parse type
-        let type_bytes: u16 = match resource_record.record_type {
-            ResourceRecordType::ARecord => 0x0001,
-            ResourceRecordType::AAAARecord => 0x001C,
-            ResourceRecordType::NSRecord => 0x0002,
-            ResourceRecordType::CNameRecord => 0x0005,
-            ResourceRecordType::MXRecord => 0x000f,
-            ResourceRecordType::SOARecord => 0x0006,
-            ResourceRecordType::TXTRecord => 0x0010,
-            _ => 0x0000,
-        };
-
-        // Encode the type
-        buf.put_u16(type_bytes)?;
-
-        // Encode class, only support internet class of request
-        buf.put_u16(1)?;
+        self.encode_type(&resource_record.record_type, buf)?;
 
-        // Encode time to live
-        buf.put_u32(resource_record.time_to_live)?;
+        match &resource_record.data {
+            // OPT records reuse the class and TTL fields
+            ResourceRecordData::OPT(record) => {
+                buf.put_u16(record.udp_payload_size)?;
+                buf.put_u32(record.ttl())?;
+            }
+            _ => {
+                // Encode class
+                buf.put_u16(match resource_record.class {
+                    ResourceRecordClass::InternetAddress => 0x0001,
+                    ResourceRecordClass::Chaos => 0x0003,
+                    ResourceRecordClass::Unimplemented(code) => code,
+                })?;
+
+                // Encode time to live
+                buf.put_u32(resource_record.time_to_live)?;
+            }
+        }
 
         // Encode RDdata field
         match &resource_record.data {
             // A Record encoded a 32 bit integer
             ResourceRecordData::A(record) => {
                 buf.put_u16(4)?;
-                buf.put_u32(*record)?;
+                buf.put_u32((*record).into())?;
                 Ok(())
             }
             // AAAA record encoded as a 128 bit integer
             ResourceRecordData::AAAA(record) => {
                 buf.put_u16(16)?;
-                buf.put_u128(*record)
+                buf.put_u128((*record).into())
             }
 
             // CNAME record encoded as a standard name
             ResourceRecordData::CName(domain) => {
                 // Where length should be
-                let length_index = buf.write_cursor;
+                let length_index = buf.write_position();
 
                 // Write blank data to where size is
                 buf.put_u16(0)?;
@@ -207,7 +389,7 @@ impl MessageCoder {
 
             // SOA record encoded.
             ResourceRecordData::SOA(record) => {
-                let length_index = buf.write_cursor;
+                let length_index = buf.write_position();
                 // Write blank data to where size is
                 buf.put_u16(0)?;
 
@@ -216,7 +398,7 @@ impl MessageCoder {
                 buf.set_u16(length_index, length as u16)
             }
             ResourceRecordData::MX(preference, exchange) => {
-                let length_index = buf.write_cursor;
+                let length_index = buf.write_position();
 
                 buf.put_u16(0)?;
 
@@ -227,15 +409,144 @@ impl MessageCoder {
                 buf.set_u16(length_index, length as u16)
             }
 
-            ResourceRecordData::TXT(_) => {
-                // TODO
+            ResourceRecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                let length_index = buf.write_position();
+
+                buf.put_u16(0)?;
+
+                let mut length = buf.put_u16(*priority)?;
+                length += buf.put_u16(*weight)?;
+                length += buf.put_u16(*port)?;
+
+                // The target mustn't be compressed (RFC 2782)
+                length += self.encode_name_uncompressed(target, buf)?;
+
+                buf.set_u16(length_index, length as u16)
+            }
+
+            // TXT and SPF records encoded as a sequence of length prefixed character strings
+            ResourceRecordData::TXT(strings) | ResourceRecordData::SPF(strings) => {
+                let length_index = buf.write_position();
+
+                // Write blank data to where size is
+                buf.put_u16(0)?;
+
+                let mut length = 0;
+
+                // Character strings are limited to 255 bytes, longer strings are split
+                for chunk in strings.iter().flat_map(|string| txt_chunks(string)) {
+                    buf.put_u8(chunk.len() as u8)?;
+
+                    for byte in chunk {
+                        buf.put_u8(*byte)?;
+                    }
+
+                    length += chunk.len() + 1;
+                }
+
+                buf.set_u16(length_index, length as u16)
+            }
+
+            // LOC record encoded as fixed 16 bytes
+            ResourceRecordData::LOC {
+                version,
+                size,
+                horiz_pre,
+                vert_pre,
+                latitude,
+                longitude,
+                altitude,
+            } => {
+                buf.put_u16(LOC_RECORD_LENGTH as u16)?;
+                buf.put_u8(*version)?;
+                buf.put_u8(*size)?;
+                buf.put_u8(*horiz_pre)?;
+                buf.put_u8(*vert_pre)?;
+                buf.put_u32(*latitude)?;
+                buf.put_u32(*longitude)?;
+                buf.put_u32(*altitude)?;
+
                 Ok(())
             }
 
-            // CNAME record encoded as a standard name
-            ResourceRecordData::NS(domain) => {
+            ResourceRecordData::OPT(record) => self.encode_opt_options(record, buf),
+
+            // SVCB and HTTPS records share an encoding
+            ResourceRecordData::SVCB(record) | ResourceRecordData::HTTPS(record) => {
+                let length_index = buf.write_position();
+
+                // Write blank data to where size is
+                buf.put_u16(0)?;
+
+                let length = self.encode_svcb_record(record, buf)?;
+
+                buf.set_u16(length_index, length as u16)
+            }
+
+            // TLSA record encoded as three single byte fields followed by the certificate data
+            ResourceRecordData::TLSA {
+                usage,
+                selector,
+                matching_type,
+                cert_data,
+            } => {
+                buf.put_u16(3 + cert_data.len() as u16)?;
+                buf.put_u8(*usage)?;
+                buf.put_u8(*selector)?;
+                buf.put_u8(*matching_type)?;
+
+                for byte in cert_data {
+                    buf.put_u8(*byte)?;
+                }
+
+                Ok(())
+            }
+
+            // Names in NSEC records mustn't be compressed (RFC 4034)
+            ResourceRecordData::NSEC {
+                next_domain,
+                type_bitmaps,
+            } => {
+                let length_index = buf.write_position();
+
+                buf.put_u16(0)?;
+
+                let mut length = self.encode_name_uncompressed(next_domain, buf)?;
+
+                for byte in type_bitmaps {
+                    buf.put_u8(*byte)?;
+                }
+
+                length += type_bitmaps.len();
+
+                buf.set_u16(length_index, length as u16)
+            }
+
+            // DNSSEC records and records of unknown types are written back exactly as received,
+            // names within them such as the RRSIG signer are never compressed (RFC 4034, RFC 3597)
+            ResourceRecordData::DS(data)
+            | ResourceRecordData::RRSIG(data)
+            | ResourceRecordData::DNSKEY(data)
+            | ResourceRecordData::NSEC3(data)
+            | ResourceRecordData::Unknown(_, data) => {
+                buf.put_u16(data.len() as u16)?;
+
+                for byte in data {
+                    buf.put_u8(*byte)?;
+                }
+
+                Ok(())
+            }
+
+            // NS and PTR records encoded as a standard name
+            ResourceRecordData::NS(domain) | ResourceRecordData::PTR(domain) => {
                 // Where length should be
-                let length_index = buf.write_cursor;
+                let length_index = buf.write_position();
 
                 // Write blank data to where size is
                 buf.put_u16(0)?;
@@ -252,12 +563,12 @@ impl MessageCoder {
     }
 
     // Encodes the given header into the given buffer
-    /// ```
+    /// ```text
     /// 0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
     /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     /// |                      ID                       |
     /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-    /// |QR|   Opcode  |AA|TC|RD|RA|   Z    |   RCODE   |
+    /// |QR|   Opcode  |AA|TC|RD|RA| Z|AD|CD|   RCODE   |
     /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     /// |                    QDCOUNT                    |
     /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -318,15 +629,18 @@ impl MessageCoder {
             0x0
         };
 
-        // Set RCODE, don't set Z should be set to zero.
-        options |= match message.response_code {
-            ResponseCode::None => 0,
-            ResponseCode::FormatError => 1,
-            ResponseCode::ServerError => 2,
-            ResponseCode::NameError => 3,
-            ResponseCode::NotImplemented => 4,
-            ResponseCode::Refused => 5,
-        } & 0x0F; // Truncate to 4 bits
+        // Set AD, bit 10
+        options |= if message.authentic_data { 0x20 } else { 0x00 };
+
+        // Set CD, bit 11
+        options |= if message.checking_disabled {
+            0x10
+        } else {
+            0x00
+        };
+
+        // Set RCODE, don't set Z should be set to zero. Extended bits are carried in the OPT record.
+        options |= message.response_code.to_u16() as u8 & 0x0F; // Truncate to 4 bits
 
         // Write second half of options
         buf.put_u8(options)?;
@@ -349,7 +663,7 @@ impl MessageCoder {
 
     /// Encodes the given question into the given buffer
     ///
-    ///```
+    ///```text
     /// 0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
     /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     /// |                                               |
@@ -374,22 +688,15 @@ impl MessageCoder {
         // Encode domain name
         self.encode_name(&question.domain, buf)?;
 
-        // Encode type
-        let type_bytes: u16 = match question.question_type {
-            ResourceRecordType::ARecord => 0x0001,
-            ResourceRecordType::AAAARecord => 0x001C,
-            ResourceRecordType::NSRecord => 0x0002,
-            ResourceRecordType::CNameRecord => 0x0005,
-            ResourceRecordType::MXRecord => 0x000f,
-            ResourceRecordType::SOARecord => 0x0006,
-            _ => 0x0000,
-        };
-
         // Encode the type
-        buf.put_u16(type_bytes)?;
+        self.encode_type(&question.question_type, buf)?;
 
-        // Encode class, only support IN class questions
-        buf.put_u16(1)?;
+        // Encode class
+        buf.put_u16(match question.class {
+            QuestionClass::InternetAddress => 0x0001,
+            QuestionClass::Chaos => 0x0003,
+            QuestionClass::Unimplemented(code) => code,
+        })?;
 
         Ok(())
     }
@@ -397,7 +704,7 @@ impl MessageCoder {
     /// Encode given SOA record into the given buffer
     ///
     /// SOA record structure
-    ///```
+    ///```text
     /// 0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
     /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     /// |                     MNAME                     |
@@ -430,7 +737,6 @@ impl MessageCoder {
     /// EXPIRE encoded as a 32 bit integer
     /// MINIMUM encoded as a 32 bit integer
     /// ```
-
     pub fn encode_soa_record(
         &mut self,
         soa_record: &SOARecord,
@@ -456,20 +762,13 @@ impl MessageCoder {
         let domain = self.decode_name(buf)?;
 
         // Decode the type
-        let question_type = match buf.get_u16()? {
-            0x0001 => ResourceRecordType::ARecord,
-            0x001C => ResourceRecordType::AAAARecord,
-            0x0002 => ResourceRecordType::NSRecord,
-            0x0005 => ResourceRecordType::CNameRecord,
-            0x000f => ResourceRecordType::MXRecord,
-            0x0006 => ResourceRecordType::SOARecord,
-            _ => ResourceRecordType::Unimplemented,
-        };
+        let question_type = self.decode_type(buf)?;
 
         // Decode the class
         let class = match buf.get_u16()? {
             0x001 => QuestionClass::InternetAddress,
-            _ => QuestionClass::Unimplemented,
+            0x003 => QuestionClass::Chaos,
+            code => QuestionClass::Unimplemented(code),
         };
 
         Ok(Question {
@@ -480,55 +779,59 @@ impl MessageCoder {
     }
 
     pub fn decode_label(&mut self, length: usize, buf: &mut NetworkBuffer) -> CodingResult<String> {
-        let mut label = String::new();
+        let mut raw = Vec::with_capacity(length);
 
-        let mut n = 0;
-
-        while n < length {
-            label.push(buf.get_u8()? as char);
-            n += 1;
+        for _ in 0..length {
+            raw.push(buf.get_u8()?);
         }
 
-        Ok(label)
-    }
-
-    pub fn save_decoded_names(&mut self, domains: &[String], indexes: &[usize]) {
-        // Adding all domains decoded, indexes in reverse order
-        for (domain_index, pointer_index) in (0..domains.len()).zip(indexes.iter()) {
-            // Join all the domains from domain_index, as that corresponds to all labels after the pointer index
-            let full_domain = domains[domain_index..].join(".");
-            self.decoded_names.insert(*pointer_index, full_domain);
-        }
+        Ok(label_text(&raw))
     }
 
     pub fn get_pointer_location(&self, left: u8, right: u8) -> usize {
         (((0x3F & left) as u16) << 8 | right as u16) as usize
     }
 
+    /// Decodes a name, following any pointer back to labels earlier in the buffer
+    ///
+    /// Pointers are resolved by reading the labels they point to, so names without pointers need
+    /// no compression state. Each pointer must point before the labels it was found after, which
+    /// stops pointer loops.
     pub fn decode_name(&mut self, buf: &mut NetworkBuffer) -> CodingResult<String> {
-        // Keep track of the index, so we can cache any pointers
-        let mut starting_index = buf.read_cursor;
-        let mut label_length = buf.get_u8()? as usize;
+        let mut name = String::new();
+
+        // Bytes of the name on the wire, starting with the root label
+        let mut wire_length = 1;
 
-        let mut decoded_names = vec![];
-        let mut decoded_indexes = vec![];
+        // Where reading continues once the name is decoded, after the first pointer if any
+        let mut end_position = None;
+        let mut segment_start = buf.read_position();
 
-        while label_length != 0x00 {
-            // Check for a pointer to existing labels
-            if label_length & 0xC0 > 0 {
+        loop {
+            // Check for a pointer to existing labels before consuming the length byte
+            if buf.peek_u8()? & 0xC0 > 0 {
                 // Get the location of the pointer
-                let pointer_location = self.get_pointer_location(label_length as u8, buf.get_u8()?);
+                let pointer = buf.get_u16()?;
+                let pointer_location =
+                    self.get_pointer_location((pointer >> 8) as u8, pointer as u8);
 
-                // Get from the cached values
-                let name = match self.decoded_names.get(&pointer_location) {
-                    Some(name) => name.clone(),
-                    None => return Err(NetworkBufferError::CompressionError),
-                };
+                if pointer_location >= segment_start {
+                    return Err(NetworkBufferError::CompressionError);
+                }
+
+                end_position.get_or_insert(buf.read_position());
+
+                // Continue with the labels pointed to
+                buf.set_read_position(pointer_location);
+                segment_start = pointer_location;
+
+                continue;
+            }
 
-                // Add to list of domains labels we have parsed
-                decoded_names.push(name);
+            let label_length = buf.get_u8()? as usize;
 
-                // Pointer means we are done, so exit here
+            // Null label terminates the name
+            if label_length == 0x00 {
                 break;
             }
 
@@ -544,24 +847,23 @@ impl MessageCoder {
             // Decode current label
             let label = self.decode_label(label_length, buf)?;
 
-            // Add to list of decoded domains
-            decoded_names.push(label);
-            decoded_indexes.push(starting_index);
+            name.push_str(&label);
+            name.push('.');
 
-            // Setup for the next label
-            starting_index = buf.read_cursor;
-            label_length = buf.get_u8()? as usize;
+            // Check name length limits as labels are added, the text may be longer once escaped
+            wire_length += label_length + 1;
+            if wire_length > MAX_NAME_LENGTH {
+                return Err(NetworkBufferError::InvalidNameLengthError(name));
+            }
         }
 
-        self.save_decoded_names(&decoded_names, &decoded_indexes);
-
-        let mut name = decoded_names.join(".");
-
-        name.push('.');
+        if let Some(position) = end_position {
+            buf.set_read_position(position);
+        }
 
-        // Check name length limits, error if invalid
-        if name.len() > MAX_NAME_LENGTH {
-            return Err(NetworkBufferError::InvalidNameLengthError(name));
+        // The root name is a single dot
+        if name.is_empty() {
+            name.push('.');
         }
 
         Ok(name)
@@ -578,7 +880,18 @@ impl MessageCoder {
             0x06 => ResourceRecordType::SOARecord,
             0x21 => ResourceRecordType::SRVRecord,
             0x10 => ResourceRecordType::TXTRecord,
-            _ => ResourceRecordType::Unimplemented,
+            0x63 => ResourceRecordType::SPFRecord,
+            0x1D => ResourceRecordType::LOCRecord,
+            0x29 => ResourceRecordType::OPTRecord,
+            0x40 => ResourceRecordType::SVCBRecord,
+            0x41 => ResourceRecordType::HTTPSRecord,
+            0x34 => ResourceRecordType::TLSARecord,
+            0x2B => ResourceRecordType::DSRecord,
+            0x2E => ResourceRecordType::RRSIGRecord,
+            0x2F => ResourceRecordType::NSECRecord,
+            0x30 => ResourceRecordType::DNSKEYRecord,
+            0x32 => ResourceRecordType::NSEC3Record,
+            code => ResourceRecordType::Unimplemented(code),
         };
 
         Ok(record_type)
@@ -587,7 +900,8 @@ impl MessageCoder {
     pub fn decode_class(&mut self, buf: &mut NetworkBuffer) -> CodingResult<ResourceRecordClass> {
         let class = match buf.get_u16()? {
             0x001 => ResourceRecordClass::InternetAddress,
-            _ => ResourceRecordClass::Unimplemented,
+            0x003 => ResourceRecordClass::Chaos,
+            code => ResourceRecordClass::Unimplemented(code),
         };
 
         Ok(class)
@@ -600,27 +914,104 @@ impl MessageCoder {
         // Decoding domain name record refers too
         let domain = self.decode_name(buf)?;
         let record_type = self.decode_type(buf)?;
+
+        // OPT records reuse the class and TTL fields
+        if record_type == ResourceRecordType::OPTRecord {
+            return Ok(ResourceRecord {
+                domain,
+                ..self.decode_opt_record(buf)?.to_resource_record()
+            });
+        }
+
         let class = self.decode_class(buf)?;
         let time_to_live = buf.get_u32()?;
 
-        // TODO verify data length here
         let data_length = buf.get_u16()?;
+        let data_start = buf.read_position();
 
         let record_data = match record_type {
-            ResourceRecordType::ARecord => ResourceRecordData::A(buf.get_u32()?),
+            ResourceRecordType::ARecord => ResourceRecordData::A(buf.get_u32()?.into()),
             ResourceRecordType::CNameRecord => ResourceRecordData::CName(self.decode_name(buf)?),
-            ResourceRecordType::AAAARecord => ResourceRecordData::AAAA(buf.get_u128()?),
+            ResourceRecordType::AAAARecord => ResourceRecordData::AAAA(buf.get_u128()?.into()),
             ResourceRecordType::SOARecord => ResourceRecordData::SOA(self.decode_soa_record(buf)?),
             ResourceRecordType::MXRecord => {
                 ResourceRecordData::MX(buf.get_u16()?, self.decode_name(buf)?)
             }
             ResourceRecordType::NSRecord => ResourceRecordData::NS(self.decode_name(buf)?),
+            ResourceRecordType::PTRRecord => ResourceRecordData::PTR(self.decode_name(buf)?),
+            ResourceRecordType::SRVRecord => ResourceRecordData::SRV {
+                priority: buf.get_u16()?,
+                weight: buf.get_u16()?,
+                port: buf.get_u16()?,
+                target: self.decode_name(buf)?,
+            },
             ResourceRecordType::TXTRecord => {
                 ResourceRecordData::TXT(self.decode_txt_record(buf, data_length.into())?)
             }
+            ResourceRecordType::SPFRecord => {
+                ResourceRecordData::SPF(self.decode_txt_record(buf, data_length.into())?)
+            }
+            ResourceRecordType::SVCBRecord => {
+                ResourceRecordData::SVCB(self.decode_svcb_record(buf, data_length.into())?)
+            }
+            ResourceRecordType::HTTPSRecord => {
+                ResourceRecordData::HTTPS(self.decode_svcb_record(buf, data_length.into())?)
+            }
+            ResourceRecordType::TLSARecord => {
+                // The certificate data is the rest of the record data
+                if data_length < 3 {
+                    return Err(NetworkBufferError::InvalidPacket);
+                }
+
+                ResourceRecordData::TLSA {
+                    usage: buf.get_u8()?,
+                    selector: buf.get_u8()?,
+                    matching_type: buf.get_u8()?,
+                    cert_data: (3..data_length)
+                        .map(|_| buf.get_u8())
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            ResourceRecordType::DSRecord => {
+                ResourceRecordData::DS(self.decode_opaque_record(buf, data_length.into())?)
+            }
+            ResourceRecordType::RRSIGRecord => {
+                ResourceRecordData::RRSIG(self.decode_opaque_record(buf, data_length.into())?)
+            }
+            ResourceRecordType::NSECRecord => {
+                let starting_index = buf.read_position();
+                let next_domain = self.decode_name(buf)?;
+
+                // The type bitmaps are the rest of the record data
+                let name_length = buf.read_position() - starting_index;
+                let bitmaps_length = (data_length as usize)
+                    .checked_sub(name_length)
+                    .ok_or(NetworkBufferError::InvalidPacket)?;
+
+                ResourceRecordData::NSEC {
+                    next_domain,
+                    type_bitmaps: self.decode_opaque_record(buf, bitmaps_length)?,
+                }
+            }
+            ResourceRecordType::DNSKEYRecord => {
+                ResourceRecordData::DNSKEY(self.decode_opaque_record(buf, data_length.into())?)
+            }
+            ResourceRecordType::NSEC3Record => {
+                ResourceRecordData::NSEC3(self.decode_opaque_record(buf, data_length.into())?)
+            }
+            ResourceRecordType::LOCRecord => self.decode_loc_record(buf, data_length.into())?,
+            ResourceRecordType::Unimplemented(code) => ResourceRecordData::Unknown(
+                code,
+                self.decode_opaque_record(buf, data_length.into())?,
+            ),
             _ => return Err(NetworkBufferError::InvalidPacket),
         };
 
+        // The record data must fill exactly the length it declared
+        if buf.read_position() - data_start != data_length as usize {
+            return Err(NetworkBufferError::InvalidPacket);
+        }
+
         Ok(ResourceRecord {
             domain,
             record_type,
@@ -630,6 +1021,206 @@ impl MessageCoder {
         })
     }
 
+    /// Encode the given SVCB or HTTPS record data into the given buffer
+    ///
+    /// ```text
+    /// PRIORITY encoded as a 16 bit integer
+    /// TARGET encoded as a name
+    /// PARAMS encoded as a list of 16 bit keys, 16 bit lengths and values
+    /// ```
+    pub fn encode_svcb_record(
+        &mut self,
+        svcb_record: &SVCBRecord,
+        buf: &mut NetworkBuffer,
+    ) -> CodingResult<usize> {
+        let mut write_count = buf.put_u16(svcb_record.priority)?;
+
+        // The target mustn't be compressed (RFC 9460)
+        write_count += self.encode_name_uncompressed(&svcb_record.target, buf)?;
+
+        for (key, value) in svcb_record.params.iter() {
+            write_count += buf.put_u16(*key)?;
+            write_count += buf.put_u16(value.len() as u16)?;
+
+            for byte in value.iter() {
+                buf.put_u8(*byte)?;
+            }
+
+            write_count += value.len();
+        }
+
+        Ok(write_count)
+    }
+
+    /// Decode SVCB or HTTPS record data, the params are bounded by the record data length
+    pub fn decode_svcb_record(
+        &mut self,
+        buf: &mut NetworkBuffer,
+        length: usize,
+    ) -> CodingResult<SVCBRecord> {
+        let starting_index = buf.read_position();
+
+        let priority = buf.get_u16()?;
+        let target = self.decode_name(buf)?;
+
+        let mut params = vec![];
+
+        while buf.read_position() - starting_index < length {
+            let key = buf.get_u16()?;
+            let value_length = buf.get_u16()?;
+
+            let mut value = Vec::with_capacity(value_length as usize);
+
+            for _ in 0..value_length {
+                value.push(buf.get_u8()?);
+            }
+
+            params.push((key, value));
+        }
+
+        // Params overrunning the record data length make the record invalid
+        if buf.read_position() - starting_index != length {
+            return Err(NetworkBufferError::InvalidPacket);
+        }
+
+        Ok(SVCBRecord {
+            priority,
+            target,
+            params,
+        })
+    }
+
+    /// Encode the options of an OPT record as its record data
+    ///
+    /// ```text
+    /// Each option is encoded as a 16 bit code, 16 bit length and the option data
+    /// ```
+    pub fn encode_opt_options(
+        &mut self,
+        opt_record: &OptRecord,
+        buf: &mut NetworkBuffer,
+    ) -> CodingResult<()> {
+        let length_index = buf.write_position();
+
+        // Write blank data to where size is
+        buf.put_u16(0)?;
+
+        let mut length = 0;
+
+        for option in opt_record.options.iter() {
+            let (code, data) = match option {
+                EdnsOption::ExtendedError(info_code, text) => {
+                    let mut data = info_code.to_u16().to_be_bytes().to_vec();
+                    data.extend_from_slice(text.as_bytes());
+                    (15, data)
+                }
+                EdnsOption::Cookie(client, server) => {
+                    let mut data = client.clone();
+                    data.extend_from_slice(server);
+                    (10, data)
+                }
+                EdnsOption::ClientSubnet(subnet) => (8, encode_client_subnet(subnet)),
+                EdnsOption::Unknown(code, data) => (*code, data.clone()),
+            };
+
+            length += buf.put_u16(code)?;
+            length += buf.put_u16(data.len() as u16)?;
+
+            for byte in data.iter() {
+                buf.put_u8(*byte)?;
+            }
+
+            length += data.len();
+        }
+
+        buf.set_u16(length_index, length as u16)
+    }
+
+    /// Decode the fields of an OPT record following the type
+    ///
+    /// ```text
+    /// CLASS encoded as the requestor's UDP payload size
+    /// TTL encoded as the extended RCODE, version, DO bit and zero
+    /// RDATA encoded as a list of options
+    /// ```
+    pub fn decode_opt_record(&mut self, buf: &mut NetworkBuffer) -> CodingResult<OptRecord> {
+        let udp_payload_size = buf.get_u16()?;
+        let extended_rcode = buf.get_u8()?;
+        let version = buf.get_u8()?;
+        let flags = buf.get_u16()?;
+        let data_length = buf.get_u16()? as usize;
+
+        let mut options = vec![];
+        let mut consumed = 0;
+
+        while consumed < data_length {
+            let code = buf.get_u16()?;
+            let length = buf.get_u16()? as usize;
+
+            let mut data = Vec::with_capacity(length);
+
+            for _ in 0..length {
+                data.push(buf.get_u8()?);
+            }
+
+            consumed += length + 4;
+
+            let option = match code {
+                // Extended DNS error needs at least the info code
+                15 if length >= 2 => EdnsOption::ExtendedError(
+                    ExtendedErrorCode::from_u16(u16::from_be_bytes([data[0], data[1]])),
+                    String::from_utf8_lossy(&data[2..]).to_string(),
+                ),
+                // Cookie is an 8 byte client cookie, optionally followed by an 8 to 32 byte server cookie
+                10 if length == 8 || (16..=40).contains(&length) => {
+                    EdnsOption::Cookie(data[..8].to_vec(), data[8..].to_vec())
+                }
+                8 => match decode_client_subnet(&data) {
+                    Some(subnet) => EdnsOption::ClientSubnet(subnet),
+                    None => EdnsOption::Unknown(code, data),
+                },
+                _ => EdnsOption::Unknown(code, data),
+            };
+
+            options.push(option);
+        }
+
+        Ok(OptRecord {
+            udp_payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok: flags & 0x8000 == 0x8000,
+            options,
+        })
+    }
+
+    /// Decode LOC record data, only version 0 is defined so any other version is rejected
+    ///
+    /// ```text
+    /// VERSION, SIZE, HORIZ PRE and VERT PRE encoded as 8 bit integers
+    /// LATITUDE, LONGITUDE and ALTITUDE encoded as 32 bit integers
+    /// ```
+    pub fn decode_loc_record(
+        &mut self,
+        buf: &mut NetworkBuffer,
+        length: usize,
+    ) -> CodingResult<ResourceRecordData> {
+        // The layout of other versions is unknown, so nothing past the version can be read
+        if length != LOC_RECORD_LENGTH || buf.peek_u8()? != 0 {
+            return Err(NetworkBufferError::InvalidPacket);
+        }
+
+        Ok(ResourceRecordData::LOC {
+            version: buf.get_u8()?,
+            size: buf.get_u8()?,
+            horiz_pre: buf.get_u8()?,
+            vert_pre: buf.get_u8()?,
+            latitude: buf.get_u32()?,
+            longitude: buf.get_u32()?,
+            altitude: buf.get_u32()?,
+        })
+    }
+
     pub fn decode_soa_record(&mut self, buf: &mut NetworkBuffer) -> CodingResult<SOARecord> {
         Ok(SOARecord {
             master_name: self.decode_name(buf)?,
@@ -642,20 +1233,32 @@ impl MessageCoder {
         })
     }
 
+    /// Decode record data as raw bytes, without interpreting it
+    pub fn decode_opaque_record(
+        &mut self,
+        buf: &mut NetworkBuffer,
+        length: usize,
+    ) -> CodingResult<Vec<u8>> {
+        (0..length).map(|_| buf.get_u8()).collect()
+    }
+
     pub fn decode_txt_record(
         &mut self,
         buf: &mut NetworkBuffer,
         length: usize,
-    ) -> CodingResult<String> {
-        let mut result = String::new();
+    ) -> CodingResult<Vec<Vec<u8>>> {
+        let mut result = vec![];
 
-        for mut _i in 0..length {
+        // Number of bytes of record data consumed
+        let mut consumed = 0;
+
+        while consumed < length {
             let sequence_length = buf.get_u8()?;
 
-            for _j in 0..sequence_length {
-                result.push(buf.get_u8()? as char);
-                _i += 1;
-            }
+            result.push(self.decode_opaque_record(buf, sequence_length.into())?);
+
+            // Add one for the length byte
+            consumed += sequence_length as usize + 1;
         }
 
         Ok(result)
@@ -666,25 +1269,87 @@ impl MessageCoder {
         message: &Message,
         buf: &mut NetworkBuffer,
     ) -> CodingResult<()> {
-        self.encode_header(&message, buf)?;
+        let header_start = buf.write_position();
+
+        self.encode_header(message, buf)?;
+
+        // Entries written to each section, in header order
+        let mut written = [0; 4];
 
         // Encode question
-        message
-            .questions
-            .iter()
-            .try_for_each(|question| self.encode_question(question, buf))?;
+        for question in &message.questions {
+            self.encode_question(question, buf)?;
+            written[0] += 1;
+        }
 
-        // Encode answers and name servers
-        message
-            .answers
-            .iter()
-            .chain(message.authorities.iter())
-            .try_for_each(|record| self.encode_resource_record(record, buf))?;
+        // Encode answers, name servers and additional records
+        for (section, records) in [
+            &message.answers,
+            &message.authorities,
+            &message.additional_records,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            for record in records {
+                self.encode_resource_record(record, buf)?;
+                written[section + 1] += 1;
+            }
+        }
+
+        // Catch sections falling out of step with the header, such as records left out
+        if cfg!(debug_assertions) {
+            MessageCoder::validate_counts(buf, header_start, written)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check the counts of the header written at the given position match the number of entries
+    /// written to each section, in header order
+    pub fn validate_counts(
+        buf: &NetworkBuffer,
+        header_start: usize,
+        written: [usize; 4],
+    ) -> CodingResult<()> {
+        // The counts are the last four fields of the header
+        for (index, count) in written.into_iter().enumerate() {
+            let offset = header_start + 4 + index * 2;
+            let claimed = u16::from_be_bytes([buf.buf[offset], buf.buf[offset + 1]]);
+
+            if claimed as usize != count {
+                return Err(NetworkBufferError::InvalidPacket);
+            }
+        }
 
         Ok(())
     }
 
+    /// Decode a message, ignoring any bytes after the declared sections such as padding
     pub fn decode_message(&mut self, buf: &mut NetworkBuffer) -> CodingResult<Message> {
+        let message = self.decode_sections(buf, false)?;
+
+        buf.skip_remaining();
+
+        Ok(message)
+    }
+
+    /// Decode a message, rejecting it if bytes remain after the declared sections or the reserved
+    /// Z bit is set
+    pub fn decode_message_strict(&mut self, buf: &mut NetworkBuffer) -> CodingResult<Message> {
+        let message = self.decode_sections(buf, true)?;
+
+        if buf.remaining() > 0 {
+            return Err(NetworkBufferError::InvalidMessageLengthError(format!(
+                "{} trailing bytes after the declared sections",
+                buf.remaining()
+            )));
+        }
+
+        Ok(message)
+    }
+
+    fn decode_sections(&mut self, buf: &mut NetworkBuffer, strict: bool) -> CodingResult<Message> {
         // decode ID field
         let id = buf.get_u16()?;
 
@@ -697,7 +1362,7 @@ impl MessageCoder {
             false => PacketType::Query,
         };
 
-        let op_code = (flag_byte >> 3) as u8 & 0x0F;
+        let op_code = (flag_byte >> 3) & 0x0F;
         let authoritative_answer = flag_byte >> 2 & 0x01 == 1;
         let truncation = flag_byte >> 1 & 0x01 == 1;
         let recursion_desired = flag_byte & 0x01 == 1;
@@ -705,21 +1370,35 @@ impl MessageCoder {
         let flag_byte = buf.get_u8()?;
 
         let recursion_available = flag_byte >> 7 & 0x01 == 1;
-        let response_code = match flag_byte & 0x0F {
-            0 => ResponseCode::None,
-            1 => ResponseCode::FormatError,
-            2 => ResponseCode::ServerError,
-            3 => ResponseCode::NameError,
-            4 => ResponseCode::NotImplemented,
-            5 => ResponseCode::Refused,
-            _ => return Err(NetworkBufferError::InvalidPacket),
-        };
+        let reserved = flag_byte >> 6 & 0x01 == 1;
+        let authentic_data = flag_byte >> 5 & 0x01 == 1;
+        let checking_disabled = flag_byte >> 4 & 0x01 == 1;
+        let response_code_bits = (flag_byte & 0x0F) as u16;
+
+        // Z must be zero, it's only ignored when decoding leniently
+        if strict && reserved {
+            return Err(NetworkBufferError::InvalidPacket);
+        }
 
         let question_count = buf.get_u16()?;
         let answer_count = buf.get_u16()?;
         let name_server_count = buf.get_u16()?;
         let additional_records_count = buf.get_u16()?;
 
+        // Check the declared counts can be satisfied by the remaining bytes
+        let record_count =
+            answer_count as usize + name_server_count as usize + additional_records_count as usize;
+        let minimum_length = question_count as usize * MIN_QUESTION_LENGTH
+            + record_count * MIN_RESOURCE_RECORD_LENGTH;
+        let remaining = buf.remaining();
+
+        if minimum_length > remaining {
+            return Err(NetworkBufferError::InvalidMessageLengthError(format!(
+                "{} questions and {} records need at least {} bytes, {} remaining",
+                question_count, record_count, minimum_length, remaining
+            )));
+        }
+
         let mut questions: Vec<Question> = Vec::new();
         let mut answers: Vec<ResourceRecord> = Vec::new();
         let mut name_servers: Vec<ResourceRecord> = Vec::new();
@@ -749,8 +1428,20 @@ impl MessageCoder {
             additional_records.push(additional_record);
         }
 
-        Ok(Message {
-            id,
+        // Combine with the upper bits of an extended response code
+        let extended_rcode = additional_records
+            .iter()
+            .find_map(|record| match &record.data {
+                ResourceRecordData::OPT(opt) => Some(opt.extended_rcode as u16),
+                _ => None,
+            })
+            .unwrap_or(0);
+
+        let response_code = ResponseCode::from_u16(extended_rcode << 4 | response_code_bits)
+            .ok_or(NetworkBufferError::InvalidPacket)?;
+
+        Ok(Message {
+            id,
             packet_type,
             op_code,
 
@@ -758,6 +1449,8 @@ impl MessageCoder {
             truncation,
             recursion_desired,
             recursion_available,
+            authentic_data,
+            checking_disabled,
             response_code,
 
             questions,
@@ -772,62 +1465,190 @@ impl MessageCoder {
 mod tests {
 
     use super::*;
+    use crate::messages::{idna, network_buffer::MAX_STREAM_MESSAGE_SIZE};
 
     #[test]
-    fn test_decode_single_domain() {
-        let mut coder = MessageCoder::new();
+    fn test_label_bytes_round_trip() {
+        // A label holding a byte which isn't valid UTF-8
+        let bytes = [
+            3, b'a', 0xFF, b'b', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0,
+        ];
+
+        let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+        let name = MessageCoder::new().decode_name(&mut buf).unwrap();
+
+        assert_eq!(name, "a\\255b.example.");
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new().encode_name(&name, &mut buf).unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &bytes);
+
+        // A dot within a label, both in valid UTF-8 and alongside an invalid byte
+        for (bytes, expected) in [
+            (
+                [
+                    3, b'a', b'.', b'b', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0,
+                ],
+                "a\\046b.example.",
+            ),
+            (
+                [
+                    3, b'.', 0xFF, b'b', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0,
+                ],
+                "\\046\\255b.example.",
+            ),
+        ] {
+            let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+            let name = MessageCoder::new().decode_name(&mut buf).unwrap();
+
+            assert_eq!(name, expected);
+
+            let mut buf = NetworkBuffer::new();
+            MessageCoder::new().encode_name(&name, &mut buf).unwrap();
+
+            assert_eq!(&buf.buf[..buf.write_count()], &bytes);
+        }
+
+        // Multibyte UTF-8 is written as its bytes, the length counting bytes rather than characters
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_name("münchen.de.", &mut buf)
+            .unwrap();
+
+        assert_eq!(buf.buf[0], 8);
+        assert_eq!(&buf.buf[1..9], "münchen".as_bytes());
+
+        let name = MessageCoder::new().decode_name(&mut buf).unwrap();
+
+        assert_eq!(name, "münchen.de.");
+
+        // Backslashes are escaped so they can't be mistaken for an escape
+        assert_eq!(label_text(b"a\\065"), "a\\092065");
+        assert_eq!(label_bytes("a\\092065"), b"a\\065");
+    }
+
+    #[test]
+    fn test_unicode_question_round_trip() {
+        let message = Message::query(1, Question::new("münchen.de", ResourceRecordType::ARecord));
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        // Only ASCII is written for the name
+        assert!(buf.buf[..buf.write_count()].is_ascii());
+
+        let decoded = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert_eq!(decoded.questions[0].domain, "xn--mnchen-3ya.de.");
+        assert_eq!(
+            idna::to_unicode(&decoded.questions[0].domain),
+            "münchen.de."
+        );
+    }
+
+    #[test]
+    fn test_encode_rejects_long_label_and_name() {
+        let mut buf = NetworkBuffer::new();
+
+        let label = format!("{}.example.com.", "a".repeat(64));
+        assert!(matches!(
+            MessageCoder::new().encode_name(&label, &mut buf),
+            Err(NetworkBufferError::InvalidLabelLengthError(_))
+        ));
+
+        // Four labels of 63 bytes and their dots
+        let name = format!("{}.", "a".repeat(63)).repeat(4);
+        assert_eq!(name.len(), 256);
+
+        assert!(matches!(
+            MessageCoder::new().encode_name(&name, &mut buf),
+            Err(NetworkBufferError::InvalidNameLengthError(_))
+        ));
+        assert!(matches!(
+            MessageCoder::new().encode_name_uncompressed(&name, &mut buf),
+            Err(NetworkBufferError::InvalidNameLengthError(_))
+        ));
+
+        // Without the final dot the text fits, but the labels take 257 bytes
+        let name = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(name.len(), 255);
+
+        assert!(matches!(
+            MessageCoder::new().encode_name(&name, &mut buf),
+            Err(NetworkBufferError::InvalidNameLengthError(_))
+        ));
+
+        // Escapes make the text longer than the 193 bytes written, which still encode and decode
+        let name = format!("{}.", "\\255".repeat(63)).repeat(3);
+        assert!(name.len() > MAX_NAME_LENGTH);
+
+        let mut buf = NetworkBuffer::new();
+        assert_eq!(
+            MessageCoder::new().encode_name(&name, &mut buf).unwrap(),
+            193
+        );
+        assert_eq!(MessageCoder::new().decode_name(&mut buf).unwrap(), name);
+
+        // The longest allowed label still encodes
         let mut buf = NetworkBuffer::new();
+        let label = format!("{}.example.com.", "a".repeat(63));
 
+        assert_eq!(
+            MessageCoder::new().encode_name(&label, &mut buf).unwrap(),
+            64 + 9 + 4
+        );
+    }
+
+    #[test]
+    fn test_decode_single_domain() {
+        let mut coder = MessageCoder::new();
         let domain_bytes: [u8; 7] = [0x05, b'h', b'e', b'l', b'l', b'o', 0x00];
 
-        buf._put_bytes(&domain_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&domain_bytes).unwrap();
 
         let domain = coder.decode_name(&mut buf).unwrap();
 
-        // A . is appended so include here
-        assert_eq!(domain, String::from(".hello"));
+        // A trailing . is appended so include here
+        assert_eq!(domain, String::from("hello."));
     }
 
     #[test]
     fn test_decode_domain() {
         let mut coder = MessageCoder::new();
-        let mut buf = NetworkBuffer::new();
-
         let domain_bytes: [u8; 11] = [
             0x05, b'h', b'e', b'l', b'l', b'o', 0x03, b'c', b'o', b'm', 0x00,
         ];
 
-        buf._put_bytes(&domain_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&domain_bytes).unwrap();
 
         let domain = coder.decode_name(&mut buf).unwrap();
 
-        // A . is appended so include here
-        assert_eq!(domain, String::from(".hello.com"));
+        // A trailing . is appended so include here
+        assert_eq!(domain, String::from("hello.com."));
     }
 
     #[test]
     fn test_decode_label() {
         let mut coder = MessageCoder::new();
-        let mut buf = NetworkBuffer::new();
-
         let domain_bytes: [u8; 5] = [b'h', b'e', b'l', b'l', b'o'];
 
-        buf._put_bytes(&domain_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&domain_bytes).unwrap();
 
         let domain = coder.decode_label(5, &mut buf).unwrap();
 
-        // A . is appended so include here
+        // A trailing . is appended so include here
         assert_eq!(domain, String::from("hello"));
     }
 
     #[test]
     fn test_decode_header() {
         let mut coder = MessageCoder::new();
-        let mut buf = NetworkBuffer::new();
-
         let header_bytes: [u8; 12] = [112, 181, 151, 132, 0, 0, 0, 0, 0, 0, 0, 0];
 
-        buf._put_bytes(&header_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&header_bytes).unwrap();
 
         let message = coder.decode_message(&mut buf).unwrap();
 
@@ -839,12 +1660,49 @@ mod tests {
         assert!(message.truncation);
         assert!(message.recursion_desired);
         assert!(message.recursion_available);
+        assert!(!message.authentic_data);
+        assert!(!message.checking_disabled);
         assert!(matches!(
             message.response_code,
             ResponseCode::NotImplemented
         ));
     }
 
+    #[test]
+    fn test_decode_ad_and_cd_bits() {
+        // RA, AD and CD set with a name error
+        let header_bytes: [u8; 12] = [0, 1, 0x81, 0xB3, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut buf = NetworkBuffer::from_bytes(&header_bytes).unwrap();
+        let message = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert!(message.recursion_available);
+        assert!(message.authentic_data);
+        assert!(message.checking_disabled);
+        assert_eq!(message.response_code, ResponseCode::NameError);
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &header_bytes);
+
+        // Each bit is decoded on its own
+        for (flags, authentic_data, checking_disabled) in [(0x20, true, false), (0x10, false, true)]
+        {
+            let mut header_bytes = header_bytes;
+            header_bytes[3] = flags;
+
+            let mut buf = NetworkBuffer::from_bytes(&header_bytes).unwrap();
+            let message = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+            assert_eq!(message.authentic_data, authentic_data);
+            assert_eq!(message.checking_disabled, checking_disabled);
+            assert!(!message.recursion_available);
+        }
+    }
+
     #[test]
     fn test_encode_message() {
         let mut coder = MessageCoder::new();
@@ -858,6 +1716,8 @@ mod tests {
             truncation: true,
             recursion_desired: true,
             recursion_available: true,
+            authentic_data: false,
+            checking_disabled: false,
             response_code: ResponseCode::NotImplemented,
             questions: vec![],
             answers: vec![],
@@ -873,19 +1733,139 @@ mod tests {
     }
 
     #[test]
-    fn test_decode_question() {
-        let mut coder = MessageCoder::new();
+    fn test_encode_decode_additional_records() {
+        let record = |domain: &str, data: ResourceRecordData| ResourceRecord {
+            domain: domain.to_string(),
+            record_type: data.get_type(),
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data,
+        };
+
+        // A referral with glue for its name server
+        let message = Message {
+            id: 1,
+            op_code: 0,
+            packet_type: PacketType::Response,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: false,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![Question::new(
+                "www.example.com.",
+                ResourceRecordType::ARecord,
+            )],
+            answers: vec![],
+            authorities: vec![record(
+                "example.com.",
+                ResourceRecordData::NS("ns1.example.com.".to_string()),
+            )],
+            additional_records: vec![record(
+                "ns1.example.com.",
+                ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            )],
+        };
+
         let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        let decoded = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert_eq!(decoded.additional_records, message.additional_records);
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn test_additional_count_matches_records_written() {
+        let mut message = Message {
+            id: 1,
+            op_code: 0,
+            packet_type: PacketType::Response,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: true,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![Question::new("example.com.", ResourceRecordType::ARecord)],
+            answers: vec![],
+            authorities: vec![],
+            additional_records: vec![ResourceRecord {
+                domain: "ns1.example.com.".to_string(),
+                record_type: ResourceRecordType::ARecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 3600,
+                data: ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            }],
+        };
+        message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        // ARCOUNT is the last header field
+        let additional_count = u16::from_be_bytes([buf.buf[10], buf.buf[11]]);
+        assert_eq!(additional_count, 2);
+
+        // Exactly that many records follow, with nothing left over
+        let decoded = MessageCoder::new().decode_message_strict(&mut buf).unwrap();
+        assert_eq!(decoded.additional_records.len(), additional_count as usize);
+    }
+
+    #[test]
+    fn test_validate_counts_catches_desync() {
+        let mut message = Message::query(
+            1,
+            Question::new("example.com.", ResourceRecordType::ARecord),
+        );
+        message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert!(MessageCoder::validate_counts(&buf, 0, [1, 0, 0, 1]).is_ok());
+
+        // A record left out of the additional section
+        assert!(matches!(
+            MessageCoder::validate_counts(&buf, 0, [1, 0, 0, 0]),
+            Err(NetworkBufferError::InvalidPacket)
+        ));
+
+        // A header claiming an answer that was never written
+        buf.set_u16(6, 1).unwrap();
+
+        assert!(matches!(
+            MessageCoder::validate_counts(&buf, 0, [1, 0, 0, 1]),
+            Err(NetworkBufferError::InvalidPacket)
+        ));
+    }
 
+    #[test]
+    fn test_decode_question() {
+        let mut coder = MessageCoder::new();
         let question_bytes: [u8; 20] = [
             3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1,
         ];
 
-        buf._put_bytes(&question_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&question_bytes).unwrap();
 
         let question = coder.decode_question(&mut buf).unwrap();
 
-        assert_eq!(question.domain, String::from(".www.google.com"));
+        assert_eq!(question.domain, String::from("www.google.com."));
         assert!(matches!(
             question.question_type,
             ResourceRecordType::ARecord
@@ -897,18 +1877,16 @@ mod tests {
     #[test]
     fn test_decode_resource_record() {
         let mut coder = MessageCoder::new();
-        let mut buf = NetworkBuffer::new();
-
         let resource_record_bytes: [u8; 30] = [
             3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0,
             0, 0, 255, 0, 4, 8, 8, 8, 8,
         ];
 
-        buf._put_bytes(&resource_record_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&resource_record_bytes).unwrap();
 
         let resource_record = coder.decode_resource_record(&mut buf).unwrap();
 
-        assert_eq!(resource_record.domain, String::from(".www.google.com"));
+        assert_eq!(resource_record.domain, String::from("www.google.com."));
         assert!(matches!(
             resource_record.record_type,
             ResourceRecordType::ARecord
@@ -921,7 +1899,7 @@ mod tests {
 
         assert_eq!(resource_record.time_to_live, 255);
         match resource_record.data {
-            ResourceRecordData::A(value) => assert_eq!(value, 0x08080808),
+            ResourceRecordData::A(value) => assert_eq!(value, Ipv4Addr::new(8, 8, 8, 8)),
             _ => panic!("Bad resource record"),
         }
     }
@@ -929,18 +1907,16 @@ mod tests {
     #[test]
     fn test_decode_resource_record_aaarecord() {
         let mut coder = MessageCoder::new();
-        let mut buf = NetworkBuffer::new();
-
         let resource_record_bytes: [u8; 42] = [
             3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 0, 28, 0, 1, 0,
             0, 0, 255, 0, 16, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
         ];
 
-        buf._put_bytes(&resource_record_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&resource_record_bytes).unwrap();
 
         let resource_record = coder.decode_resource_record(&mut buf).unwrap();
 
-        assert_eq!(resource_record.domain, String::from(".www.google.com"));
+        assert_eq!(resource_record.domain, String::from("www.google.com."));
         assert!(matches!(
             resource_record.record_type,
             ResourceRecordType::AAAARecord
@@ -954,7 +1930,7 @@ mod tests {
         assert_eq!(resource_record.time_to_live, 255);
         match resource_record.data {
             ResourceRecordData::AAAA(value) => {
-                assert_eq!(value, 0x08080808080808080808080808080808)
+                assert_eq!(value, Ipv6Addr::from(0x08080808080808080808080808080808))
             }
             _ => panic!("Bad resource record"),
         }
@@ -963,18 +1939,17 @@ mod tests {
     #[test]
     fn test_decode_resource_record_cname() {
         let mut coder = MessageCoder::new();
-        let mut buf = NetworkBuffer::new();
-
         let resource_record_bytes = [
             3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 0, 5, 0, 1, 0,
-            0, 0, 255, 0, 15, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109,
+            0, 0, 255, 0, 16, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109,
+            0,
         ];
 
-        buf._put_bytes(&resource_record_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&resource_record_bytes).unwrap();
 
         let resource_record = coder.decode_resource_record(&mut buf).unwrap();
 
-        assert_eq!(resource_record.domain, String::from(".www.google.com"));
+        assert_eq!(resource_record.domain, String::from("www.google.com."));
         assert!(matches!(
             resource_record.record_type,
             ResourceRecordType::CNameRecord
@@ -987,7 +1962,7 @@ mod tests {
 
         assert_eq!(resource_record.time_to_live, 255);
         match resource_record.data {
-            ResourceRecordData::CName(value) => assert_eq!(value, ".www.google.com"),
+            ResourceRecordData::CName(value) => assert_eq!(value, "www.google.com."),
             _ => panic!("Bad resource record"),
         }
     }
@@ -995,17 +1970,15 @@ mod tests {
     #[test]
     fn test_decode_pointer_domain() {
         let mut coder = MessageCoder::new();
-        let mut buf = NetworkBuffer::new();
-
         let pointer_domain_bytes: [u8; 18] = [
             3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 192, 0,
         ];
 
-        buf._put_bytes(&pointer_domain_bytes).unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&pointer_domain_bytes).unwrap();
 
         let original = coder.decode_name(&mut buf).unwrap();
 
-        assert_eq!(original, String::from(".www.google.com"));
+        assert_eq!(original, String::from("www.google.com."));
 
         let pointer = coder.decode_name(&mut buf).unwrap();
 
@@ -1013,10 +1986,52 @@ mod tests {
     }
 
     #[test]
-    fn test_decode_double_pointer_cname_request() {
+    fn test_decode_pointers_resolved_from_buffer() {
+        // A query without pointers decodes without any compression state
+        let message = Message::query(
+            7,
+            Question::new("example.com.", ResourceRecordType::ARecord),
+        );
+
         let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(
+            MessageCoder::new().decode_message(&mut buf).unwrap(),
+            message
+        );
+
+        // A pointer into the middle of a name, then a pointer to a pointer
+        let mut buf = NetworkBuffer::from_bytes(&[
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0, 3, b'f', b't',
+            b'p', 192, 4, 192, 13, 0, 1,
+        ])
+        .unwrap();
+
+        let mut coder = MessageCoder::new();
+        assert_eq!(coder.decode_name(&mut buf).unwrap(), "www.example.");
+        assert_eq!(coder.decode_name(&mut buf).unwrap(), "ftp.example.");
+        assert_eq!(coder.decode_name(&mut buf).unwrap(), "ftp.example.");
+
+        // Reading carries on after the first pointer
+        assert_eq!(buf.get_u16().unwrap(), 1);
 
-        buf._put_bytes(&[
+        // Pointers to themselves, or forward, would never end
+        for bytes in [[192, 0, 0, 0], [192, 2, 0, 0], [1, b'a', 192, 0]] {
+            let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+
+            assert!(matches!(
+                MessageCoder::new().decode_name(&mut buf),
+                Err(NetworkBufferError::CompressionError)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_decode_double_pointer_cname_request() {
+        let mut buf = NetworkBuffer::from_bytes(&[
             5, 100, 128, 128, 0, 1, 0, 2, 0, 0, 0, 0, 3, 119, 119, 119, 8, 102, 97, 99, 101, 98,
             111, 111, 107, 3, 99, 111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 5, 0, 1, 0, 0, 9, 125, 0,
             17, 9, 115, 116, 97, 114, 45, 109, 105, 110, 105, 4, 99, 49, 48, 114, 192, 16, 192, 46,
@@ -1028,15 +2043,850 @@ mod tests {
 
         let message = coder.decode_message(&mut buf).unwrap();
 
-        assert!(message.answers.len() > 0);
-        assert_eq!(message.answers[0].domain, ".www.facebook.com");
+        assert!(!message.answers.is_empty());
+        assert_eq!(message.answers[0].domain, "www.facebook.com.");
         assert_eq!(
             message.answers[0].record_type,
             ResourceRecordType::CNameRecord
         );
         assert_eq!(
             message.answers[0].data,
-            ResourceRecordData::CName(".star-mini.c10r.facebook.com".to_string()),
+            ResourceRecordData::CName("star-mini.c10r.facebook.com.".to_string()),
+        );
+    }
+
+    #[test]
+    fn test_decode_chaos_question() {
+        let mut coder = MessageCoder::new();
+        let question_bytes: [u8; 18] = [
+            7, b'v', b'e', b'r', b's', b'i', b'o', b'n', 4, b'b', b'i', b'n', b'd', 0, 0, 16, 0, 3,
+        ];
+
+        let mut buf = NetworkBuffer::from_bytes(&question_bytes).unwrap();
+
+        let question = coder.decode_question(&mut buf).unwrap();
+
+        assert_eq!(question.domain, "version.bind.");
+        assert_eq!(question.question_type, ResourceRecordType::TXTRecord);
+        assert!(matches!(question.class, QuestionClass::Chaos));
+    }
+
+    #[test]
+    fn test_unknown_question_class_round_trip() {
+        let mut coder = MessageCoder::new();
+        let question_bytes: [u8; 17] = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 0xFE,
+        ];
+
+        let mut buf = NetworkBuffer::from_bytes(&question_bytes).unwrap();
+        let question = coder.decode_question(&mut buf).unwrap();
+
+        assert_eq!(question.class, QuestionClass::Unimplemented(0xFE));
+
+        // Encoded back with the class it was asked with
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_question(&question, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &question_bytes);
+    }
+
+    #[test]
+    fn test_encode_decode_txt_record() {
+        let mut buf = NetworkBuffer::new();
+
+        let record = ResourceRecord {
+            domain: "version.bind.".to_string(),
+            record_type: ResourceRecordType::TXTRecord,
+            class: ResourceRecordClass::Chaos,
+            time_to_live: 0,
+            data: ResourceRecordData::TXT(vec![b"dn-mess".to_vec()]),
+        };
+
+        MessageCoder::new()
+            .encode_resource_record(&record, &mut buf)
+            .unwrap();
+
+        let decoded = MessageCoder::new()
+            .decode_resource_record(&mut buf)
+            .unwrap();
+
+        assert!(matches!(decoded.class, ResourceRecordClass::Chaos));
+        assert_eq!(decoded.data, record.data);
+    }
+
+    #[test]
+    fn test_encode_decode_txt_character_strings() {
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::TXTRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::TXT(vec![
+                b"v=DKIM1; k=rsa;".to_vec(),
+                vec![],
+                b"p=MIGfMA0".to_vec(),
+            ]),
+        };
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_resource_record(&record, &mut buf)
+            .unwrap();
+
+        // Name, type, class, TTL and data length, then each string with its own length byte
+        assert_eq!(&buf.buf[21..23], &[0, 27]);
+        assert_eq!(buf.buf[23], 15);
+        assert_eq!(buf.buf[39], 0);
+        assert_eq!(buf.buf[40], 9);
+
+        let decoded = MessageCoder::new()
+            .decode_resource_record(&mut buf)
+            .unwrap();
+
+        assert_eq!(decoded.data, record.data);
+    }
+
+    #[test]
+    fn test_decode_truncated_answers() {
+        let mut coder = MessageCoder::new();
+        // Header claiming 10 answers, with none following
+        let header_bytes: [u8; 12] = [112, 181, 129, 128, 0, 0, 0, 10, 0, 0, 0, 0];
+
+        let mut buf = NetworkBuffer::from_bytes(&header_bytes).unwrap();
+
+        assert!(matches!(
+            coder.decode_message(&mut buf),
+            Err(NetworkBufferError::InvalidMessageLengthError(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_decode_loc_record() {
+        let mut buf = NetworkBuffer::new();
+
+        // 42 21 54 N 71 06 18 W -24m 30m
+        let record = ResourceRecord {
+            domain: "cambridge-net.kei.com.".to_string(),
+            record_type: ResourceRecordType::LOCRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::LOC {
+                version: 0,
+                size: 0x33,
+                horiz_pre: 0x16,
+                vert_pre: 0x13,
+                latitude: 0x89172DD0,
+                longitude: 0x70BE15F0,
+                altitude: 0x00988D20,
+            },
+        };
+
+        MessageCoder::new()
+            .encode_resource_record(&record, &mut buf)
+            .unwrap();
+
+        let decoded = MessageCoder::new()
+            .decode_resource_record(&mut buf)
+            .unwrap();
+
+        assert_eq!(decoded.record_type, ResourceRecordType::LOCRecord);
+        assert_eq!(decoded.data, record.data);
+        assert_eq!(
+            decoded.data.to_string(),
+            "LOCRecord: 42 21 54.000 N 71 6 18.000 W -24.00m 30m 10000m 10m"
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid_loc_record() {
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::LOCRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::LOC {
+                version: 0,
+                size: 0x12,
+                horiz_pre: 0x16,
+                vert_pre: 0x13,
+                latitude: 0x89172DD0,
+                longitude: 0x70BE15F0,
+                altitude: 0x00988D20,
+            },
+        };
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_resource_record(&record, &mut buf)
+            .unwrap();
+
+        let bytes = buf.buf[..buf.write_count()].to_vec();
+
+        // The record data follows its 16 bit length
+        let length_index = bytes.len() - LOC_RECORD_LENGTH - 2;
+
+        // A later version
+        let mut versioned = bytes.clone();
+        versioned[length_index + 2] = 1;
+
+        // Record data shorter than the fixed layout, the rest would be read from the next record
+        let mut short = bytes[..bytes.len() - 4].to_vec();
+        short[length_index + 1] = (LOC_RECORD_LENGTH - 4) as u8;
+        short.extend_from_slice(&bytes[..4]);
+
+        for bytes in [versioned, short] {
+            let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+
+            assert!(matches!(
+                MessageCoder::new().decode_resource_record(&mut buf),
+                Err(NetworkBufferError::InvalidPacket)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_opt_record() {
+        let mut buf = NetworkBuffer::new();
+
+        let mut opt = OptRecord::new(1232);
+        opt.options.push(EdnsOption::ExtendedError(
+            ExtendedErrorCode::NoReachableAuthority,
+            "timeout".to_string(),
+        ));
+
+        MessageCoder::new()
+            .encode_resource_record(&opt.to_resource_record(), &mut buf)
+            .unwrap();
+
+        let decoded = MessageCoder::new()
+            .decode_resource_record(&mut buf)
+            .unwrap();
+
+        assert_eq!(decoded.domain, ".");
+        assert_eq!(decoded.data, ResourceRecordData::OPT(opt));
+    }
+
+    #[test]
+    fn test_from_bytes_decodes_identically() {
+        let question_bytes: [u8; 20] = [
+            3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1,
+        ];
+
+        let mut put_buf = NetworkBuffer::new();
+        put_buf._put_bytes(&question_bytes).unwrap();
+
+        let mut from_buf = NetworkBuffer::from_bytes(&question_bytes).unwrap();
+
+        let put_question = MessageCoder::new().decode_question(&mut put_buf).unwrap();
+        let from_question = MessageCoder::new().decode_question(&mut from_buf).unwrap();
+
+        assert_eq!(put_question.domain, from_question.domain);
+        assert_eq!(put_question.question_type, from_question.question_type);
+        assert_eq!(put_buf.read_position(), from_buf.read_position());
+    }
+
+    #[test]
+    fn test_decode_encode_https_record() {
+        // cloudflare.com. HTTPS 1 . alpn="h3,h2"
+        let resource_record_bytes = [
+            10, b'c', b'l', b'o', b'u', b'd', b'f', b'l', b'a', b'r', b'e', 3, b'c', b'o', b'm', 0,
+            0, 65, 0, 1, 0, 0, 1, 44, 0, 13, 0, 1, 0, 0, 1, 0, 6, 2, b'h', b'3', 2, b'h', b'2',
+        ];
+
+        let mut buf = NetworkBuffer::from_bytes(&resource_record_bytes).unwrap();
+
+        let resource_record = MessageCoder::new()
+            .decode_resource_record(&mut buf)
+            .unwrap();
+
+        assert_eq!(resource_record.record_type, ResourceRecordType::HTTPSRecord);
+        assert_eq!(
+            resource_record.data,
+            ResourceRecordData::HTTPS(SVCBRecord {
+                priority: 1,
+                target: ".".to_string(),
+                params: vec![(1, vec![2, b'h', b'3', 2, b'h', b'2'])],
+            })
+        );
+        assert_eq!(
+            resource_record.data.to_string(),
+            "HTTPSRecord: 1 . alpn=h3,h2"
+        );
+
+        let mut buf = NetworkBuffer::new();
+
+        MessageCoder::new()
+            .encode_resource_record(&resource_record, &mut buf)
+            .unwrap();
+
+        assert_eq!(buf.buf[..buf.write_count()], resource_record_bytes);
+    }
+
+    #[test]
+    fn test_encode_decode_srv_record() {
+        let record = ResourceRecord {
+            domain: "_sip._tcp.example.com.".to_string(),
+            record_type: ResourceRecordType::SRVRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::SRV {
+                priority: 10,
+                weight: 60,
+                port: 5060,
+                target: "sip.example.com.".to_string(),
+            },
+        };
+
+        let mut buf = NetworkBuffer::new();
+
+        MessageCoder::new()
+            .encode_resource_record(&record, &mut buf)
+            .unwrap();
+
+        let decoded = MessageCoder::new()
+            .decode_resource_record(&mut buf)
+            .unwrap();
+
+        assert_eq!(decoded.data, record.data);
+    }
+
+    #[test]
+    fn test_encode_decode_compressed_ns_record() {
+        let ns = |domain: &str| ResourceRecord {
+            domain: domain.to_string(),
+            record_type: ResourceRecordType::NSRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data: ResourceRecordData::NS("ns1.example.com.".to_string()),
+        };
+
+        let message = Message {
+            id: 1,
+            packet_type: PacketType::Response,
+            op_code: 0,
+            authoritative_answer: true,
+            truncation: false,
+            recursion_desired: false,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![],
+            answers: vec![ns("example.com."), ns("example.org.")],
+            authorities: vec![],
+            additional_records: vec![],
+        };
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        // The second record's name server was already written by the first, so its data is a pointer
+        let end = buf.write_count();
+        assert_eq!(&buf.buf[end - 4..end - 2], &[0, 2]);
+        assert_eq!(buf.buf[end - 2] & 0xC0, 0xC0);
+
+        let decoded = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn test_decode_authority_ns_record() {
+        // Referral for www.example.com. to the example.com. name server
+        let bytes = decode_hex(
+            "1234 8000 0001 0000 0001 0000
+             03 777777 07 6578616d706c65 03 636f6d 00 0001 0001
+             c010 0002 0001 0002a300 0006 03 6e7331 c010",
+        );
+
+        let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+        let message = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert!(message.answers.is_empty());
+        assert_eq!(message.authorities.len(), 1);
+
+        let authority = &message.authorities[0];
+        assert_eq!(authority.domain, "example.com.");
+        assert_eq!(authority.record_type, ResourceRecordType::NSRecord);
+        assert_eq!(authority.time_to_live, 172800);
+        assert_eq!(
+            authority.data,
+            ResourceRecordData::NS("ns1.example.com.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_client_subnet_option() {
+        let mut subnet = ClientSubnet::new("192.0.2.77".parse().unwrap(), 20);
+        subnet.scope_prefix_length = 16;
+
+        let mut opt = OptRecord::new(1232);
+        opt.options.push(EdnsOption::ClientSubnet(subnet));
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_resource_record(&opt.to_resource_record(), &mut buf)
+            .unwrap();
+
+        // Root name, type, class, TTL and data length, then the option code, length and data
+        assert_eq!(
+            &buf.buf[11..buf.write_count()],
+            &[0, 8, 0, 7, 0, 1, 20, 16, 192, 0, 0]
+        );
+
+        let record = MessageCoder::new()
+            .decode_resource_record(&mut buf)
+            .unwrap();
+
+        match record.data {
+            ResourceRecordData::OPT(decoded) => assert_eq!(decoded, opt),
+            _ => panic!("Bad resource record"),
+        }
+    }
+
+    /// Decode a hex encoded message, ignoring whitespace and `#` comments
+    fn decode_hex(contents: &str) -> Vec<u8> {
+        let digits: String = contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(|line| line.chars().filter(|character| !character.is_whitespace()))
+            .collect();
+
+        (0..digits.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn corpus_path(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/corpus")
+            .join(name)
+    }
+
+    #[test]
+    fn test_corpus_round_trip() {
+        let corpus = corpus_path("");
+
+        let mut count = 0;
+
+        for entry in std::fs::read_dir(corpus).unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.extension().and_then(|extension| extension.to_str()) != Some("hex") {
+                continue;
+            }
+
+            let bytes = decode_hex(&std::fs::read_to_string(&path).unwrap());
+
+            let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+            let message = MessageCoder::new()
+                .decode_message(&mut buf)
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+
+            let mut buf = NetworkBuffer::new();
+            MessageCoder::new()
+                .encode_message(&message, &mut buf)
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+
+            // Compression may differ, so compare the decoded messages rather than the bytes
+            let redecoded = MessageCoder::new()
+                .decode_message(&mut buf)
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+
+            assert_eq!(message, redecoded, "{}", path.display());
+
+            count += 1;
+        }
+
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn test_tlsa_round_trip() {
+        let bytes = decode_hex(&std::fs::read_to_string(corpus_path("response_tlsa.hex")).unwrap());
+
+        let message = MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&bytes).unwrap())
+            .unwrap();
+
+        match &message.answers[0].data {
+            ResourceRecordData::TLSA {
+                usage,
+                selector,
+                matching_type,
+                cert_data,
+            } => {
+                assert_eq!((*usage, *selector, *matching_type), (3, 1, 1));
+                assert_eq!(cert_data.len(), 32);
+                assert_eq!(cert_data[0], 0xd2);
+            }
+            data => panic!("Unexpected record data {}", data),
+        }
+
+        assert_eq!(
+            message.answers[0].to_presentation(),
+            "_443._tcp.example.com. 3600 IN TLSA 3 1 1 \
+             d2abde240d7cd3ee6b4b28c54df034b97983a1d16e8a410e4561cb106618e971"
+        );
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &bytes[..]);
+    }
+
+    #[test]
+    fn test_spf_round_trip() {
+        let bytes = decode_hex(&std::fs::read_to_string(corpus_path("response_spf.hex")).unwrap());
+
+        let message = MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&bytes).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            message.answers[0].record_type,
+            ResourceRecordType::SPFRecord
+        );
+        assert_eq!(
+            message.answers[0].data,
+            ResourceRecordData::SPF(vec![b"v=spf1 -all".to_vec()])
+        );
+        assert_eq!(
+            message.answers[0].to_presentation(),
+            "example.com. 3600 IN SPF \"v=spf1 -all\""
+        );
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &bytes[..]);
+    }
+
+    #[test]
+    fn test_txt_non_ascii_round_trip() {
+        // "café" in UTF-8 followed by a byte which isn't UTF-8 at all
+        let bytes = decode_hex(
+            "1234 8180 0001 0001 0000 0000
+             07 6578616d706c65 03 636f6d 00 0010 0001
+             c00c 0010 0001 00000e10 0007 06 636166c3a9ff",
         );
+
+        let message = MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&bytes).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            message.answers[0].data,
+            ResourceRecordData::TXT(vec![vec![b'c', b'a', b'f', 0xC3, 0xA9, 0xFF]])
+        );
+        assert_eq!(
+            message.answers[0].to_presentation(),
+            "example.com. 3600 IN TXT \"caf\\195\\169\\255\""
+        );
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &bytes[..]);
+    }
+
+    #[test]
+    fn test_rrsig_passed_through_unchanged() {
+        let rdata = decode_hex(
+            "0001 0d 02 00000e10 65a00000 65800000 3039
+             07 6578616d706c65 03 636f6d 00
+             00112233445566778899aabbccddeeff",
+        );
+
+        // The signer name repeats the question name, but must stay uncompressed
+        let bytes = decode_hex(
+            "1234 8180 0001 0002 0000 0000
+             07 6578616d706c65 03 636f6d 00 0001 0001
+             c00c 0001 0001 00000e10 0004 5db8d822
+             c00c 002e 0001 00000e10 002f
+             0001 0d 02 00000e10 65a00000 65800000 3039
+             07 6578616d706c65 03 636f6d 00
+             00112233445566778899aabbccddeeff",
+        );
+
+        let message = MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&bytes).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            message.answers[1].record_type,
+            ResourceRecordType::RRSIGRecord
+        );
+        assert_eq!(message.answers[1].data, ResourceRecordData::RRSIG(rdata));
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &bytes[..]);
+    }
+
+    #[test]
+    fn test_unknown_type_round_trip() {
+        // A private use type, its data looks like a name pointer but is never interpreted
+        let bytes = decode_hex(
+            "1234 8180 0001 0001 0000 0000
+             07 6578616d706c65 03 636f6d 00 ff01 0001
+             c00c ff01 0001 00000e10 0005 c00c 010203",
+        );
+
+        let message = MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&bytes).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            message.answers[0].record_type,
+            ResourceRecordType::Unimplemented(0xFF01)
+        );
+        assert_eq!(
+            message.answers[0].data,
+            ResourceRecordData::Unknown(0xFF01, vec![0xC0, 0x0C, 1, 2, 3])
+        );
+        assert_eq!(
+            message.answers[0].to_presentation(),
+            "example.com. 3600 IN TYPE65281 \\# 5 c00c010203"
+        );
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &bytes[..]);
+    }
+
+    #[test]
+    fn test_record_data_length_mismatch_rejected() {
+        // An A record claiming five bytes of data for its four byte address
+        let bytes = decode_hex(
+            "1234 8180 0001 0001 0000 0000
+             07 6578616d706c65 03 636f6d 00 0001 0001
+             c00c 0001 0001 00000e10 0005 5db8d822 00",
+        );
+
+        assert!(MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&bytes).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_nsec_next_domain_not_compressed() {
+        let message = Message {
+            id: 0x1234,
+            packet_type: PacketType::Response,
+            op_code: 0,
+            authoritative_answer: true,
+            truncation: false,
+            recursion_desired: false,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::NameError,
+            questions: vec![Question::new("a.example.com.", ResourceRecordType::ARecord)],
+            answers: vec![],
+            authorities: vec![ResourceRecord {
+                domain: "example.com.".to_string(),
+                record_type: ResourceRecordType::NSECRecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 3600,
+                data: ResourceRecordData::NSEC {
+                    // Already written in full as the question name
+                    next_domain: "a.example.com.".to_string(),
+                    // A, NS, SOA, RRSIG and NSEC
+                    type_bitmaps: vec![0x00, 0x06, 0x62, 0x00, 0x00, 0x00, 0x00, 0x03],
+                },
+            }],
+            additional_records: vec![],
+        };
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        let bytes = &buf.buf[..buf.write_count()];
+        let next_domain = decode_hex("01 61 07 6578616d706c65 03 636f6d 00");
+
+        // Header, question, owner name, then type, class, TTL and record data length
+        let rdata_index = 12 + 19 + 13 + 10;
+        assert_eq!(
+            &bytes[rdata_index..rdata_index + next_domain.len()],
+            &next_domain[..]
+        );
+
+        let decoded = MessageCoder::new().decode_message(&mut buf).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(
+            decoded.authorities[0].to_presentation(),
+            "example.com. 3600 IN NSEC a.example.com. TYPE1 TYPE2 TYPE6 TYPE46 TYPE47"
+        );
+    }
+
+    #[test]
+    fn test_decoded_messages_compare_equal() {
+        // www.example.com. CNAME example.com. with compressed names
+        let compressed = decode_hex(
+            "1234 8180 0001 0001 0000 0000
+             03 777777 07 6578616d706c65 03 636f6d 00 0005 0001
+             c00c 0005 0001 00000e10 0002 c010",
+        );
+
+        // The same answer in upper case, without compression and with a different TTL
+        let uncompressed = decode_hex(
+            "1234 8180 0001 0001 0000 0000
+             03 575757 07 4558414d504c45 03 434f4d 00 0005 0001
+             03 575757 07 4558414d504c45 03 434f4d 00 0005 0001 0000012c 000d
+             07 6578616d706c65 03 636f6d 00",
+        );
+
+        let compressed = MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&compressed).unwrap())
+            .unwrap();
+        let uncompressed = MessageCoder::new()
+            .decode_message(&mut NetworkBuffer::from_bytes(&uncompressed).unwrap())
+            .unwrap();
+
+        assert_eq!(compressed, uncompressed);
+
+        let mut different = uncompressed.clone();
+        different.response_code = ResponseCode::NameError;
+
+        assert_ne!(compressed, different);
+    }
+
+    #[test]
+    fn test_names_past_pointer_range_not_compressed() {
+        let record = |domain: &str| ResourceRecord {
+            domain: domain.to_string(),
+            record_type: ResourceRecordType::TXTRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::TXT(vec![b"x".repeat(255)]),
+        };
+
+        // Enough records to push the later names past 16KB
+        let mut message = Message::query(
+            1,
+            Question::new("example.com.", ResourceRecordType::TXTRecord),
+        );
+        message.packet_type = PacketType::Response;
+        message.answers = vec![record("example.com."); 80];
+        message.answers.push(record("late.example.org."));
+        message.answers.push(record("late.example.org."));
+
+        let mut buf = NetworkBuffer::with_max_size(MAX_STREAM_MESSAGE_SIZE);
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert!(buf.write_count() > MAX_POINTER_OFFSET);
+
+        let decoded = MessageCoder::new().decode_message_strict(&mut buf).unwrap();
+
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn test_decode_message_without_questions() {
+        // Header only, every count zero
+        let bytes = [0x12, 0x34, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+        let message = MessageCoder::new().decode_message_strict(&mut buf).unwrap();
+
+        assert_eq!(message.id, 0x1234);
+        assert!(message.is_query());
+        assert!(message.recursion_desired);
+        assert!(message.questions.is_empty());
+        assert!(message.validate().is_ok());
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf.buf[..buf.write_count()], &bytes);
+    }
+
+    #[test]
+    fn test_decode_message_ignores_trailing_bytes() {
+        let mut bytes = decode_hex(&std::fs::read_to_string(corpus_path("query_a.hex")).unwrap());
+        let length = bytes.len();
+        bytes.extend_from_slice(&[0; 16]);
+
+        let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+        let message = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert_eq!(message.questions[0].domain, "example.com.");
+        assert_eq!(buf.remaining(), 0);
+
+        // The same message without padding decodes identically
+        let mut buf = NetworkBuffer::from_bytes(&bytes[..length]).unwrap();
+        assert_eq!(
+            MessageCoder::new().decode_message(&mut buf).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn test_decode_message_strict_rejects_reserved_bit() {
+        // Z set alongside RA, AD and CD
+        let header_bytes: [u8; 12] = [0, 1, 0x81, 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut buf = NetworkBuffer::from_bytes(&header_bytes).unwrap();
+        assert!(matches!(
+            MessageCoder::new().decode_message_strict(&mut buf),
+            Err(NetworkBufferError::InvalidPacket)
+        ));
+
+        // Lenient decoding ignores it, the other bits are unaffected
+        let mut buf = NetworkBuffer::from_bytes(&header_bytes).unwrap();
+        let message = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert!(message.recursion_available);
+        assert!(message.authentic_data);
+        assert!(message.checking_disabled);
+        assert_eq!(message.response_code, ResponseCode::None);
+
+        // Z is never written
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        assert_eq!(buf.buf[3], 0xB0);
+    }
+
+    #[test]
+    fn test_decode_message_strict_rejects_trailing_bytes() {
+        let mut bytes = decode_hex(&std::fs::read_to_string(corpus_path("query_a.hex")).unwrap());
+
+        let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+        assert!(MessageCoder::new().decode_message_strict(&mut buf).is_ok());
+
+        bytes.push(0);
+
+        let mut buf = NetworkBuffer::from_bytes(&bytes).unwrap();
+        assert!(matches!(
+            MessageCoder::new().decode_message_strict(&mut buf),
+            Err(NetworkBufferError::InvalidMessageLengthError(_))
+        ));
     }
 }
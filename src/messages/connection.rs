@@ -1,15 +1,26 @@
 use std::net::SocketAddr;
 
-use tokio::net::UdpSocket;
-
-use super::{coding::MessageCoder, network_buffer::NetworkBuffer, packets::Message};
+use super::{
+    coding::MessageCoder,
+    errors::NetworkBufferError,
+    network_buffer::{NetworkBuffer, MAX_MESSAGE_SIZE},
+    packets::Message,
+    transport::DatagramTransport,
+};
 
 type ConnectionResult<T> = Result<T, Box<dyn std::error::Error>>;
 
+/// Reads and writes messages as datagrams, reusing one buffer for every message
 pub struct Connection {
     buf: NetworkBuffer,
 }
 
+impl Default for Connection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Connection {
     pub fn new() -> Connection {
         // Initializing buffers
@@ -18,20 +29,58 @@ impl Connection {
         Connection { buf }
     }
 
-    pub async fn write_message(
+    pub async fn write_message<T: DatagramTransport>(
+        &mut self,
+        sock: &T,
+        message: &Message,
+        to_addr: &SocketAddr,
+    ) -> ConnectionResult<usize> {
+        self.encode(message, MAX_MESSAGE_SIZE)?;
+
+        self.send(sock, to_addr).await
+    }
+
+    /// Write the message in a datagram of up to the given size, such as the payload size the
+    /// client advertised, sending only its header and questions with TC set if it doesn't fit
+    pub async fn write_message_within<T: DatagramTransport>(
         &mut self,
-        sock: &UdpSocket,
+        sock: &T,
         message: &Message,
         to_addr: &SocketAddr,
+        max_size: usize,
     ) -> ConnectionResult<usize> {
+        match self.encode(message, max_size) {
+            Ok(()) => {}
+            Err(NetworkBufferError::BufferFullError) => {
+                self.encode(&message.truncated(), max_size)?
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        self.send(sock, to_addr).await
+    }
+
+    fn encode(&mut self, message: &Message, max_size: usize) -> Result<(), NetworkBufferError> {
+        // Start from an empty buffer, even if the last message failed to encode part way
+        self.buf.reset();
+        self.buf.set_max_size(max_size);
+
         // Encode the message, MessageCoder instances should be ephemeral
         MessageCoder::new().encode_message(message, &mut self.buf)?;
 
+        Ok(())
+    }
+
+    async fn send<T: DatagramTransport>(
+        &mut self,
+        sock: &T,
+        to_addr: &SocketAddr,
+    ) -> ConnectionResult<usize> {
         // Only write the length of the buffer
         let buffer_length = self.buf.write_count();
 
         let write_count = sock
-            .send_to(&self.buf.buf[..buffer_length], to_addr)
+            .send_to(&self.buf.buf[..buffer_length], *to_addr)
             .await?;
 
         // Reset buffer for reuse
@@ -40,20 +89,258 @@ impl Connection {
         Ok(write_count)
     }
 
-    pub async fn read_message(
+    pub async fn read_message<T: DatagramTransport>(
         &mut self,
-        sock: &UdpSocket,
+        sock: &T,
     ) -> ConnectionResult<(SocketAddr, Message)> {
+        // One byte more than a datagram may hold, so a longer datagram can be told apart from
+        // one of exactly the largest size. Writing may also have left the buffer larger.
+        self.buf.set_max_size(MAX_MESSAGE_SIZE + 1);
+
         // Read datagram from socket
-        let (_len, addr) = sock.recv_from(&mut self.buf.buf).await?;
+        let (len, addr) = sock
+            .recv_from(self.buf.receive_buffer(MAX_MESSAGE_SIZE + 1)?)
+            .await?;
 
-        // Decode message
-        let message = MessageCoder::new().decode_message(&mut self.buf)?;
+        // A longer datagram was cut short, rather than decoding a partial message
+        if len > MAX_MESSAGE_SIZE {
+            return Err(NetworkBufferError::InvalidMessageLengthError(format!(
+                "datagram is longer than {} bytes and was truncated, \
+                 use EDNS or TCP for larger messages",
+                MAX_MESSAGE_SIZE
+            ))
+            .into());
+        }
+
+        // Mark how much of the buffer holds the datagram
+        self.buf.set_write_position(len);
+
+        // Decode message, middleboxes may pad datagrams so trailing bytes are ignored
+        let message = MessageCoder::new().decode_message(&mut self.buf);
 
         // Reset buffer for reuse
         self.buf.reset();
 
-        // Return the remote address and message
-        Ok((addr, message))
+        // Return the remote address and message, whether it is valid is left to the reader so
+        // a rejected query can still be answered
+        Ok((addr, message?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::messages::packets::{
+        OptRecord, Question, ResourceRecord, ResourceRecordClass, ResourceRecordData,
+        ResourceRecordType,
+    };
+
+    #[tokio::test]
+    async fn test_connection_reused_across_messages() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to_addr = receiver.local_addr().unwrap();
+
+        let mut writer = Connection::new();
+        let mut reader = Connection::new();
+
+        // A label too long to encode leaves part of the message in the buffer
+        let invalid = Message::query(
+            1,
+            Question::new(&"a".repeat(64), ResourceRecordType::ARecord),
+        );
+        assert!(writer
+            .write_message(&sender, &invalid, &to_addr)
+            .await
+            .is_err());
+
+        for (id, domain) in [
+            (2, "example.com."),
+            (3, "a.much.longer.example.org."),
+            (4, "b.io."),
+        ] {
+            let message = Message::query(id, Question::new(domain, ResourceRecordType::ARecord));
+
+            writer
+                .write_message(&sender, &message, &to_addr)
+                .await
+                .unwrap();
+
+            let (from, read) = reader.read_message(&receiver).await.unwrap();
+
+            assert_eq!(from, sender.local_addr().unwrap());
+            assert_eq!(read, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_datagram_after_long_one() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to_addr = receiver.local_addr().unwrap();
+
+        let mut reader = Connection::new();
+
+        let earlier = Message::query(
+            1,
+            Question::new(
+                "secretvictimlabel.example.com.",
+                ResourceRecordType::ARecord,
+            ),
+        );
+        Connection::new()
+            .write_message(&sender, &earlier, &to_addr)
+            .await
+            .unwrap();
+        reader.read_message(&receiver).await.unwrap();
+
+        // A header with one question, then a label claiming 63 bytes when only 4 follow
+        let mut datagram = vec![0, 2, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x3F];
+        datagram.extend_from_slice(b"xxxx");
+        sender.send_to(&datagram, to_addr).await.unwrap();
+
+        let err = reader.read_message(&receiver).await.unwrap_err();
+
+        assert!(!err.to_string().contains("victim"));
+        assert!(!reader
+            .buf
+            .buf
+            .windows(b"victim".len())
+            .any(|window| window == b"victim"));
+    }
+
+    #[tokio::test]
+    async fn test_write_message_within_payload_size() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to_addr = receiver.local_addr().unwrap();
+
+        let mut message = Message::response(1);
+        message.questions = vec![Question::new("big.local.", ResourceRecordType::TXTRecord)];
+        message.answers = (0..4)
+            .map(|index| ResourceRecord {
+                domain: "big.local.".to_string(),
+                record_type: ResourceRecordType::TXTRecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 60,
+                data: ResourceRecordData::TXT(vec![index.to_string().repeat(200).into_bytes()]),
+            })
+            .collect();
+        message
+            .additional_records
+            .push(OptRecord::new(4096).to_resource_record());
+
+        let mut writer = Connection::new();
+        let mut datagram = [0; 4096];
+
+        // Fits the larger payload size whole
+        writer
+            .write_message_within(&sender, &message, &to_addr, 4096)
+            .await
+            .unwrap();
+
+        let (len, _) = receiver.recv_from(&mut datagram).await.unwrap();
+        let mut buf = NetworkBuffer::with_max_size(4096)
+            .with_bytes(&datagram[..len])
+            .unwrap();
+
+        assert!(len > MAX_MESSAGE_SIZE);
+        assert_eq!(
+            MessageCoder::new().decode_message(&mut buf).unwrap(),
+            message
+        );
+
+        // Too large for a classic datagram, only the header, question and EDNS record are sent
+        writer
+            .write_message_within(&sender, &message, &to_addr, MAX_MESSAGE_SIZE)
+            .await
+            .unwrap();
+
+        let (len, _) = receiver.recv_from(&mut datagram).await.unwrap();
+        let mut buf = NetworkBuffer::from_bytes(&datagram[..len]).unwrap();
+        let truncated = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert!(truncated.truncation);
+        assert!(truncated.answers.is_empty());
+        assert_eq!(truncated.questions, message.questions);
+        assert!(truncated.opt().is_some());
+
+        // The writer is back to classic datagrams for plain writes
+        assert!(writer
+            .write_message(&sender, &message, &to_addr)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_datagram_rejected_as_truncated() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to_addr = receiver.local_addr().unwrap();
+
+        let mut reader = Connection::new();
+
+        // A valid query padded past the largest datagram, the tail of a larger message would be
+        // lost
+        let mut buf = NetworkBuffer::new();
+        let message = Message::query(
+            1,
+            Question::new("example.com.", ResourceRecordType::ARecord),
+        );
+        MessageCoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+
+        let mut datagram = buf.buf[..buf.write_count()].to_vec();
+
+        // Exactly the largest datagram is read whole
+        datagram.resize(MAX_MESSAGE_SIZE, 0);
+        sender.send_to(&datagram, to_addr).await.unwrap();
+
+        let (_, read) = reader.read_message(&receiver).await.unwrap();
+        assert_eq!(read, message);
+
+        datagram.resize(MAX_MESSAGE_SIZE + 1, 0);
+        sender.send_to(&datagram, to_addr).await.unwrap();
+
+        let err = reader.read_message(&receiver).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NetworkBufferError>(),
+            Some(NetworkBufferError::InvalidMessageLengthError(_))
+        ));
+
+        // The connection still reads the next message
+        datagram.truncate(buf.write_count());
+        sender.send_to(&datagram, to_addr).await.unwrap();
+
+        let (_, read) = reader.read_message(&receiver).await.unwrap();
+        assert_eq!(read, message);
+    }
+
+    #[tokio::test]
+    async fn test_count_mismatch_rejected() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to_addr = receiver.local_addr().unwrap();
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(
+                &Message::query(
+                    1,
+                    Question::new("example.com.", ResourceRecordType::ARecord),
+                ),
+                &mut buf,
+            )
+            .unwrap();
+
+        // The header claims an answer the datagram doesn't hold
+        let mut datagram = buf.buf[..buf.write_count()].to_vec();
+        datagram[7] = 1;
+        sender.send_to(&datagram, to_addr).await.unwrap();
+
+        assert!(Connection::new().read_message(&receiver).await.is_err());
     }
 }
@@ -1,5 +1,7 @@
 use core::fmt;
 
+use super::packets::ResponseCode;
+
 #[derive(Debug)]
 pub enum NetworkBufferError {
     BufferFullError,
@@ -8,6 +10,7 @@ pub enum NetworkBufferError {
     CompressionError,
     InvalidLabelLengthError(String),
     InvalidNameLengthError(String),
+    InvalidMessageLengthError(String),
 }
 
 impl std::error::Error for NetworkBufferError {}
@@ -25,17 +28,51 @@ impl fmt::Display for NetworkBufferError {
             NetworkBufferError::InvalidNameLengthError(value) => {
                 write!(f, "Invalid Name Length: {}", value)
             }
+            NetworkBufferError::InvalidMessageLengthError(value) => {
+                write!(f, "Invalid Message Length: {}", value)
+            }
         }
     }
 }
 
-#[derive(Debug)]
-pub enum ClientError {}
+/// Why a decoded message breaks the basic invariants, see `Message::validate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidMessageError {
+    /// More records in a section than its 16 bit count can hold
+    TooManyRecords,
+    /// A query carrying answers or authorities
+    RecordsInQuery,
+    /// Any opcode other than a standard query
+    UnsupportedOpCode(u8),
+    /// A question in a class other than IN or CH
+    UnsupportedClass,
+}
+
+impl InvalidMessageError {
+    /// Code to answer a query rejected for this reason with
+    pub fn response_code(&self) -> ResponseCode {
+        match self {
+            InvalidMessageError::TooManyRecords | InvalidMessageError::RecordsInQuery => {
+                ResponseCode::FormatError
+            }
+            InvalidMessageError::UnsupportedOpCode(_) | InvalidMessageError::UnsupportedClass => {
+                ResponseCode::NotImplemented
+            }
+        }
+    }
+}
 
-impl std::error::Error for ClientError {}
+impl std::error::Error for InvalidMessageError {}
 
-impl fmt::Display for ClientError {
+impl fmt::Display for InvalidMessageError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Connection error")
+        match self {
+            InvalidMessageError::TooManyRecords => write!(f, "Too Many Records"),
+            InvalidMessageError::RecordsInQuery => write!(f, "Records In Query"),
+            InvalidMessageError::UnsupportedOpCode(op_code) => {
+                write!(f, "Unsupported Opcode: {}", op_code)
+            }
+            InvalidMessageError::UnsupportedClass => write!(f, "Unsupported Class"),
+        }
     }
 }
@@ -0,0 +1,62 @@
+//! Conversion between internationalized domain names and their ASCII form on the wire
+//!
+//! Names with non-ASCII characters are mapped and validated as in UTS #46, with each label carried
+//! as an `xn--` label holding its punycode encoding (RFC 3492).
+
+/// Prefix marking a label as the punycode encoding of a Unicode label
+const ACE_PREFIX: &str = "xn--";
+
+/// Convert a name with non-ASCII labels to its `xn--` form, ASCII names and names which aren't
+/// valid IDNs are unchanged
+pub fn to_ascii(domain: &str) -> String {
+    if domain.is_ascii() {
+        return domain.to_string();
+    }
+
+    ::idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_string())
+}
+
+/// Convert the `xn--` labels of a name back to Unicode, names which don't decode are unchanged
+pub fn to_unicode(domain: &str) -> String {
+    let encoded = domain.split('.').any(|label| {
+        label
+            .get(..ACE_PREFIX.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(ACE_PREFIX))
+    });
+
+    if !encoded {
+        return domain.to_string();
+    }
+
+    match ::idna::domain_to_unicode(domain) {
+        (unicode, Ok(())) => unicode,
+        (_, Err(_)) => domain.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_round_trip() {
+        let ascii = to_ascii("münchen.bücher.de.");
+
+        assert_eq!(ascii, "xn--mnchen-3ya.xn--bcher-kva.de.");
+        assert_eq!(to_unicode(&ascii), "münchen.bücher.de.");
+
+        assert_eq!(to_ascii("例え.テスト."), "xn--r8jz45g.xn--zckzah.");
+
+        // Mapped as in UTS #46 before encoding
+        assert_eq!(to_ascii("MÜNCHEN.de."), "xn--mnchen-3ya.de.");
+
+        // ASCII names are untouched both ways
+        assert_eq!(to_ascii("www.Example.com."), "www.Example.com.");
+        assert_eq!(to_unicode("www.Example.com."), "www.Example.com.");
+    }
+
+    #[test]
+    fn test_invalid_punycode_left_as_is() {
+        assert_eq!(to_unicode("xn--ab!.example."), "xn--ab!.example.");
+    }
+}
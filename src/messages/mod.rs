@@ -1,21 +1,51 @@
-use packets::{Message, PacketType, Question, ResourceRecord};
+use std::{collections::HashSet, net::IpAddr};
 
-use self::packets::ResponseCode;
+use rand::seq::SliceRandom;
+
+use packets::{Message, PacketType, Question, ResourceRecord, ResourceRecordData};
+
+use self::{
+    coding::MessageCoder,
+    network_buffer::{NetworkBuffer, MAX_MESSAGE_SIZE, MAX_STREAM_MESSAGE_SIZE},
+    packets::{EdnsOption, ExtendedErrorCode, OptRecord, ResponseCode},
+};
 
 pub mod client;
 mod coding;
 pub mod connection;
 mod errors;
+pub mod idna;
 mod network_buffer;
 pub mod packets;
+pub mod stream;
+pub mod transport;
+
+pub(crate) use coding::label_bytes;
+
+/// EDNS option code of padding (RFC 7830)
+const PADDING_OPTION_CODE: u16 = 12;
+
+/// EDNS option code of the TCP keepalive timeout (RFC 7828)
+const TCP_KEEPALIVE_OPTION_CODE: u16 = 11;
+
+/// Options describing the connection they arrived on rather than the answer, never passed on
+const HOP_BY_HOP_OPTION_CODES: [u16; 2] = [TCP_KEEPALIVE_OPTION_CODE, PADDING_OPTION_CODE];
 
 #[derive(Clone)]
 pub struct Request {
     message: Message,
+    source: Option<IpAddr>,
 }
 
 pub struct Response {
     message: Message,
+    /// Answered from the cache rather than by recursion or a handler
+    cached: bool,
+}
+
+/// Whether the records belong to the same answer set
+fn same_set(record: &ResourceRecord, other: &ResourceRecord) -> bool {
+    record.record_type == other.record_type && record.domain.eq_ignore_ascii_case(&other.domain)
 }
 
 impl Response {
@@ -23,18 +53,231 @@ impl Response {
         &self.message
     }
 
+    pub fn set_cached(&mut self, cached: bool) {
+        self.cached = cached;
+    }
+
+    pub fn is_cached(&self) -> bool {
+        self.cached
+    }
+
     pub fn set_answers(&mut self, answers: Vec<ResourceRecord>) {
         self.message.answers = answers
     }
 
+    /// Drop repeated answers, keeping the first occurrence of each
+    pub fn dedup_answers(&mut self) {
+        let mut seen = HashSet::new();
+
+        self.message
+            .answers
+            .retain(|answer| seen.insert(answer.clone()));
+    }
+
+    /// Order answers so each CNAME precedes the records of its target, keeping other answers in place
+    pub fn normalize_order(&mut self) {
+        let mut pending: Vec<ResourceRecord> = self.message.answers.drain(..).collect();
+        let mut ordered = Vec::with_capacity(pending.len());
+
+        while !pending.is_empty() {
+            // Take the first record that isn't the target of a CNAME still to be placed
+            let next = pending.iter().position(|record| {
+                !pending.iter().any(|other| match &other.data {
+                    ResourceRecordData::CName(target) => {
+                        !std::ptr::eq(record, other) && target.eq_ignore_ascii_case(&record.domain)
+                    }
+                    _ => false,
+                })
+            });
+
+            // A CNAME loop has no valid order, leave the rest as they were
+            match next {
+                Some(index) => ordered.push(pending.remove(index)),
+                None => ordered.append(&mut pending),
+            }
+        }
+
+        self.message.answers = ordered;
+    }
+
+    /// Rotate each set of answers sharing a name and type left by the offset
+    pub fn rotate_answers(&mut self, offset: usize) {
+        for set in self.message.answers.chunk_by_mut(same_set) {
+            let length = set.len();
+            set.rotate_left(offset % length);
+        }
+    }
+
+    /// Shuffle each set of answers sharing a name and type
+    pub fn shuffle_answers(&mut self) {
+        let mut rng = rand::thread_rng();
+
+        for set in self.message.answers.chunk_by_mut(same_set) {
+            set.shuffle(&mut rng);
+        }
+    }
+
+    /// Drop the authority and additional records of a response with answers, keeping the EDNS
+    /// record. Negative answers and referrals are left whole, those sections are their answer.
+    pub fn minimize(&mut self) {
+        if self.message.answers.is_empty() {
+            return;
+        }
+
+        self.message.authorities.clear();
+        self.message
+            .additional_records
+            .retain(|record| matches!(record.data, ResourceRecordData::OPT(_)));
+    }
+
+    pub fn set_authorities(&mut self, authorities: Vec<ResourceRecord>) {
+        self.message.authorities = authorities;
+    }
+
+    pub fn add_additional_record(&mut self, record: ResourceRecord) {
+        self.message.additional_records.push(record);
+    }
+
+    /// Set the response code, storing any extended bits in the EDNS record
     pub fn set_code(&mut self, code: ResponseCode) {
+        if let Some(opt) = self.message.opt_mut() {
+            opt.extended_rcode = (code.to_u16() >> 4) as u8;
+        }
+
         self.message.response_code = code;
     }
+
+    pub fn set_truncated(&mut self, truncated: bool) {
+        self.message.truncation = truncated;
+    }
+
+    pub fn set_authoritative(&mut self, authoritative: bool) {
+        self.message.authoritative_answer = authoritative;
+    }
+
+    pub fn set_recursion_available(&mut self, recursion_available: bool) {
+        self.message.recursion_available = recursion_available;
+    }
+
+    /// Attach an extended DNS error, only possible when the request used EDNS
+    pub fn set_extended_error(&mut self, code: ExtendedErrorCode, text: &str) {
+        if let Some(opt) = self.message.opt_mut() {
+            opt.options
+                .push(EdnsOption::ExtendedError(code, text.to_string()));
+        }
+    }
+
+    /// Pass on the EDNS options of an upstream response that aren't interpreted here, only
+    /// possible when the request used EDNS. Hop-by-hop options such as padding are dropped
+    pub fn copy_unknown_options(&mut self, upstream: &Message) {
+        let unknown = match upstream.opt() {
+            Some(opt) => opt
+                .options
+                .iter()
+                .filter(|option| match option {
+                    EdnsOption::Unknown(code, _) => !HOP_BY_HOP_OPTION_CODES.contains(code),
+                    _ => false,
+                })
+                .cloned(),
+            None => return,
+        };
+
+        if let Some(opt) = self.message.opt_mut() {
+            opt.options.extend(unknown);
+        }
+    }
+
+    /// Pad the response to a multiple of the block size (RFC 7830), hiding its size on encrypted
+    /// transports, only possible when the request used EDNS. Responses are padded up to the
+    /// largest message a stream transport carries
+    pub fn pad(&mut self, block_size: usize) {
+        if block_size == 0 {
+            return;
+        }
+
+        // Padding passed on from an upstream would throw the length off
+        match self.message.opt_mut() {
+            Some(opt) => opt
+                .options
+                .retain(|option| !matches!(option, EdnsOption::Unknown(PADDING_OPTION_CODE, _))),
+            None => return,
+        }
+
+        let mut buf = NetworkBuffer::with_max_size(MAX_STREAM_MESSAGE_SIZE);
+
+        if MessageCoder::new()
+            .encode_message(&self.message, &mut buf)
+            .is_err()
+        {
+            return;
+        }
+
+        // The option's code and length take four bytes of their own
+        let length = buf.write_count() + 4;
+        let padding = (block_size - length % block_size) % block_size;
+
+        if length + padding > MAX_STREAM_MESSAGE_SIZE {
+            return;
+        }
+
+        if let Some(opt) = self.message.opt_mut() {
+            opt.options
+                .push(EdnsOption::Unknown(PADDING_OPTION_CODE, vec![0; padding]));
+        }
+    }
+
+    /// Advertise the largest UDP message accepted, only possible when the request used EDNS
+    pub fn set_udp_payload_size(&mut self, udp_payload_size: u16) {
+        if let Some(opt) = self.message.opt_mut() {
+            opt.udp_payload_size = udp_payload_size;
+        }
+    }
+
+    /// Attach a DNS cookie, only possible when the request used EDNS
+    pub fn set_cookie(&mut self, client_cookie: &[u8], server_cookie: &[u8]) {
+        if let Some(opt) = self.message.opt_mut() {
+            opt.options.push(EdnsOption::Cookie(
+                client_cookie.to_vec(),
+                server_cookie.to_vec(),
+            ));
+        }
+    }
 }
 
 impl Request {
-    pub fn new(message: Message) -> Request {
-        Request { message }
+    /// Repeated questions are dropped, so a query can't ask for the same work many times
+    pub fn new(mut message: Message) -> Request {
+        let mut seen = HashSet::new();
+        message
+            .questions
+            .retain(|question| seen.insert(question.clone()));
+
+        Request {
+            message,
+            source: None,
+        }
+    }
+
+    /// Record the address the request was received from
+    pub fn with_source(mut self, source: IpAddr) -> Request {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn source(&self) -> Option<IpAddr> {
+        self.source
+    }
+
+    /// Client and server cookies sent with the request
+    pub fn cookie(&self) -> Option<(&[u8], &[u8])> {
+        self.message
+            .opt()?
+            .options
+            .iter()
+            .find_map(|option| match option {
+                EdnsOption::Cookie(client, server) => Some((client.as_slice(), server.as_slice())),
+                _ => None,
+            })
     }
 
     pub fn id(&self) -> u16 {
@@ -49,6 +292,17 @@ impl Request {
         &self.message.questions
     }
 
+    /// Largest UDP response the client accepts, as advertised with EDNS
+    ///
+    /// Clients without EDNS get the classic 512 bytes, larger sizes are capped at `max_size`.
+    pub fn udp_payload_size(&self, max_size: u16) -> usize {
+        self.message
+            .opt()
+            .map_or(MAX_MESSAGE_SIZE, |opt| opt.udp_payload_size as usize)
+            .min(max_size as usize)
+            .max(MAX_MESSAGE_SIZE)
+    }
+
     pub fn recursion_desired(&self) -> bool {
         self.message.recursion_desired
     }
@@ -61,6 +315,241 @@ impl Request {
         message.packet_type = PacketType::Response;
         message.recursion_available = true;
 
-        Response { message }
+        // Answers aren't validated, CD is echoed back as is
+        message.authentic_data = false;
+
+        // Don't echo the additional records, only reply with our own EDNS record if the request used EDNS
+        message.additional_records.clear();
+
+        if self.message.opt().is_some() {
+            message
+                .additional_records
+                .push(OptRecord::new(MAX_MESSAGE_SIZE as u16).to_resource_record());
+        }
+
+        Response {
+            message,
+            cached: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::messages::packets::{ResourceRecordClass, ResourceRecordType};
+
+    fn query() -> Request {
+        Request::new(Message {
+            id: 1,
+            packet_type: PacketType::Query,
+            op_code: 0,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![Question::new(
+                "www.example.com.",
+                ResourceRecordType::ARecord,
+            )],
+            answers: vec![],
+            authorities: vec![],
+            additional_records: vec![],
+        })
+    }
+
+    fn record(domain: &str, data: ResourceRecordData) -> ResourceRecord {
+        ResourceRecord {
+            domain: domain.to_string(),
+            record_type: data.get_type(),
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_normalize_order_puts_cname_first() {
+        let mut response = query().response();
+
+        let cname = record(
+            "www.example.com.",
+            ResourceRecordData::CName("example.com.".to_string()),
+        );
+        let a = record(
+            "example.com.",
+            ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        );
+
+        response.set_answers(vec![a, cname]);
+        response.normalize_order();
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(response.message(), &mut buf)
+            .unwrap();
+
+        let message = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        let types: Vec<ResourceRecordType> = message
+            .answers
+            .iter()
+            .map(|answer| answer.record_type.clone())
+            .collect();
+
+        assert_eq!(
+            types,
+            vec![ResourceRecordType::CNameRecord, ResourceRecordType::ARecord]
+        );
+    }
+
+    #[test]
+    fn test_response_clears_authentic_data() {
+        let mut message = query().message().clone();
+        message.authentic_data = true;
+        message.checking_disabled = true;
+
+        let response = Request::new(message).response();
+
+        assert!(!response.message().authentic_data);
+        assert!(response.message().checking_disabled);
+    }
+
+    #[test]
+    fn test_dedup_answers() {
+        let mut response = query().response();
+
+        let a = record(
+            "www.example.com.",
+            ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        );
+
+        response.set_answers(vec![a.clone(), a]);
+        response.dedup_answers();
+
+        let mut buf = NetworkBuffer::new();
+        MessageCoder::new()
+            .encode_message(response.message(), &mut buf)
+            .unwrap();
+
+        let message = MessageCoder::new().decode_message(&mut buf).unwrap();
+
+        assert_eq!(message.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_pad_to_block_size() {
+        let mut request = query();
+        request
+            .message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        for block_size in [128, 468] {
+            let mut response = request.response();
+            response.set_answers(vec![record(
+                "www.example.com.",
+                ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            )]);
+
+            // Upstream padding is replaced rather than added to
+            response
+                .message
+                .opt_mut()
+                .unwrap()
+                .options
+                .push(EdnsOption::Unknown(PADDING_OPTION_CODE, vec![0; 7]));
+
+            response.pad(block_size);
+
+            let mut buf = NetworkBuffer::new();
+            MessageCoder::new()
+                .encode_message(response.message(), &mut buf)
+                .unwrap();
+
+            assert_eq!(buf.write_count() % block_size, 0);
+            assert_eq!(response.message().opt().unwrap().options.len(), 1);
+        }
+
+        // Without EDNS there is nowhere to put the padding
+        let mut response = query().response();
+        response.pad(128);
+
+        assert!(response.message().additional_records.is_empty());
+    }
+
+    #[test]
+    fn test_pad_response_larger_than_datagram() {
+        let mut request = query();
+        request
+            .message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let mut response = request.response();
+        response.set_answers(
+            (0..60)
+                .map(|index| {
+                    record(
+                        "www.example.com.",
+                        ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, index)),
+                    )
+                })
+                .collect(),
+        );
+
+        response.pad(468);
+
+        let mut buf = NetworkBuffer::with_max_size(MAX_STREAM_MESSAGE_SIZE);
+        MessageCoder::new()
+            .encode_message(response.message(), &mut buf)
+            .unwrap();
+
+        assert!(buf.write_count() > MAX_MESSAGE_SIZE);
+        assert_eq!(buf.write_count() % 468, 0);
+    }
+
+    #[test]
+    fn test_rotate_answers_within_each_set() {
+        let mut response = query().response();
+
+        let cname = record(
+            "www.example.com.",
+            ResourceRecordData::CName("example.com.".to_string()),
+        );
+        let addresses: Vec<ResourceRecord> = (1..=3)
+            .map(|host| {
+                record(
+                    "example.com.",
+                    ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, host)),
+                )
+            })
+            .collect();
+
+        response.set_answers([vec![cname.clone()], addresses.clone()].concat());
+        response.rotate_answers(4);
+
+        assert_eq!(
+            response.message().answers,
+            vec![
+                cname,
+                addresses[1].clone(),
+                addresses[2].clone(),
+                addresses[0].clone()
+            ]
+        );
+
+        response.shuffle_answers();
+
+        assert_eq!(response.message().answers.len(), 4);
+        assert_eq!(
+            response.message().answers[0].record_type,
+            ResourceRecordType::CNameRecord
+        );
     }
 }
@@ -2,28 +2,105 @@ use super::errors::NetworkBufferError;
 
 pub const MAX_MESSAGE_SIZE: usize = 512;
 
+/// Largest message a stream transport can carry, as the length prefix is two bytes
+pub const MAX_STREAM_MESSAGE_SIZE: usize = u16::MAX as usize;
+
 type BufferResult<T> = Result<T, NetworkBufferError>;
 pub struct NetworkBuffer {
-    pub read_cursor: usize,
-    pub write_cursor: usize,
-    pub buf: [u8; 512],
+    read_cursor: usize,
+    write_cursor: usize,
+    /// Bytes the buffer may grow to
+    max_size: usize,
+    pub buf: Vec<u8>,
 }
 
 impl NetworkBuffer {
+    /// Buffer for a single UDP datagram, allocated up front
     pub fn new() -> NetworkBuffer {
         NetworkBuffer {
             read_cursor: 0,
             write_cursor: 0,
-            buf: [0; MAX_MESSAGE_SIZE],
+            max_size: MAX_MESSAGE_SIZE,
+            buf: vec![0; MAX_MESSAGE_SIZE],
         }
     }
 
-    pub fn put_u8(&mut self, byte: u8) -> BufferResult<()> {
-        // Checking bounds
-        if self.write_cursor + 1 >= MAX_MESSAGE_SIZE {
+    /// Buffer growing as it's written to, up to the given size, for transports not limited to a
+    /// single datagram
+    pub fn with_max_size(max_size: usize) -> NetworkBuffer {
+        NetworkBuffer {
+            read_cursor: 0,
+            write_cursor: 0,
+            max_size,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Space to receive a message of the given length into, mark it written with
+    /// `set_write_position` once filled
+    pub fn receive_buffer(&mut self, length: usize) -> BufferResult<&mut [u8]> {
+        if length > self.max_size {
+            return Err(NetworkBufferError::BufferFullError);
+        }
+
+        if self.buf.len() < length {
+            self.buf.resize(length, 0);
+        }
+
+        Ok(&mut self.buf[..length])
+    }
+
+    /// Check there is room to write the given number of bytes, growing the buffer if needed
+    fn reserve(&mut self, length: usize) -> BufferResult<()> {
+        if self.write_cursor + length > self.max_size {
             return Err(NetworkBufferError::BufferFullError);
         }
 
+        if self.buf.len() < self.write_cursor + length {
+            self.buf.resize(self.write_cursor + length, 0);
+        }
+
+        Ok(())
+    }
+
+    /// Check the given number of bytes have been written and can be read
+    fn check_read(&self, length: usize) -> BufferResult<()> {
+        if self.read_cursor + length > self.write_cursor {
+            return Err(NetworkBufferError::BufferEmptyError);
+        }
+
+        Ok(())
+    }
+
+    /// Create a datagram buffer holding the given bytes, ready to be read from the start
+    pub fn from_bytes(bytes: &[u8]) -> BufferResult<NetworkBuffer> {
+        NetworkBuffer::new().with_bytes(bytes)
+    }
+
+    /// Fill the buffer with the given bytes, ready to be read from the start
+    pub fn with_bytes(mut self, bytes: &[u8]) -> BufferResult<NetworkBuffer> {
+        self.reset();
+
+        self.receive_buffer(bytes.len())?.copy_from_slice(bytes);
+        self.write_cursor = bytes.len();
+
+        Ok(self)
+    }
+
+    /// Bytes the buffer may grow to
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Change the bytes the buffer may grow to, e.g. to the payload size a client advertised
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn put_u8(&mut self, byte: u8) -> BufferResult<()> {
+        // Checking bounds
+        self.reserve(1)?;
+
         // Write the byte
         self.buf[self.write_cursor] = byte;
 
@@ -34,9 +111,7 @@ impl NetworkBuffer {
     }
 
     pub fn put_u16(&mut self, value: u16) -> BufferResult<usize> {
-        if self.write_cursor + 2 >= MAX_MESSAGE_SIZE {
-            return Err(NetworkBufferError::BufferFullError);
-        }
+        self.reserve(2)?;
 
         self.buf[self.write_cursor] = (value >> 8) as u8;
         self.buf[self.write_cursor + 1] = (value & 0x00FF) as u8;
@@ -47,7 +122,7 @@ impl NetworkBuffer {
     }
 
     pub fn set_u16(&mut self, index: usize, value: u16) -> BufferResult<()> {
-        if index + 2 >= MAX_MESSAGE_SIZE {
+        if index + 2 > self.max_size.min(self.buf.len()) {
             return Err(NetworkBufferError::BufferFullError);
         }
 
@@ -58,9 +133,7 @@ impl NetworkBuffer {
     }
 
     pub fn put_u32(&mut self, value: u32) -> BufferResult<usize> {
-        if self.write_cursor + 4 >= MAX_MESSAGE_SIZE {
-            return Err(NetworkBufferError::BufferFullError);
-        }
+        self.reserve(4)?;
 
         self.buf[self.write_cursor] = (value >> 24) as u8;
         self.buf[self.write_cursor + 1] = (value >> 16) as u8;
@@ -73,10 +146,9 @@ impl NetworkBuffer {
     }
 
     pub fn put_u128(&mut self, value: u128) -> BufferResult<()> {
-        if self.write_cursor + 16 >= MAX_MESSAGE_SIZE {
-            return Err(NetworkBufferError::BufferFullError);
-        }
+        self.reserve(16)?;
 
+        self.buf[self.write_cursor] = (value >> 120) as u8;
         self.buf[self.write_cursor + 1] = (value >> 112) as u8;
         self.buf[self.write_cursor + 2] = (value >> 104) as u8;
         self.buf[self.write_cursor + 3] = (value >> 96) as u8;
@@ -108,9 +180,7 @@ impl NetworkBuffer {
 
     pub fn get_u8(&mut self) -> BufferResult<u8> {
         // Checking bounds
-        if self.read_cursor + 1 >= MAX_MESSAGE_SIZE {
-            return Err(NetworkBufferError::BufferEmptyError);
-        }
+        self.check_read(1)?;
 
         let byte = self.buf[self.read_cursor];
 
@@ -119,12 +189,25 @@ impl NetworkBuffer {
         Ok(byte)
     }
 
-    pub fn get_u16(&mut self) -> BufferResult<u16> {
+    /// Read the next byte without advancing the read cursor
+    pub fn peek_u8(&self) -> BufferResult<u8> {
         // Checking bounds
+        self.check_read(1)?;
 
-        if self.read_cursor + 2 >= MAX_MESSAGE_SIZE {
-            return Err(NetworkBufferError::BufferEmptyError);
-        }
+        Ok(self.buf[self.read_cursor])
+    }
+
+    /// Read the next two bytes without advancing the read cursor
+    pub fn peek_u16(&self) -> BufferResult<u16> {
+        // Checking bounds
+        self.check_read(2)?;
+
+        Ok((self.buf[self.read_cursor] as u16) << 8 | self.buf[self.read_cursor + 1] as u16)
+    }
+
+    pub fn get_u16(&mut self) -> BufferResult<u16> {
+        // Checking bounds
+        self.check_read(2)?;
 
         let value =
             (self.buf[self.read_cursor] as u16) << 8 | self.buf[self.read_cursor + 1] as u16;
@@ -136,9 +219,7 @@ impl NetworkBuffer {
 
     pub fn get_u32(&mut self) -> BufferResult<u32> {
         // Checking bounds
-        if self.read_cursor + 2 >= MAX_MESSAGE_SIZE {
-            return Err(NetworkBufferError::BufferEmptyError);
-        }
+        self.check_read(4)?;
 
         let value = (self.buf[self.read_cursor] as u32) << 24
             | (self.buf[self.read_cursor + 1] as u32) << 16
@@ -152,9 +233,7 @@ impl NetworkBuffer {
 
     pub fn get_u128(&mut self) -> BufferResult<u128> {
         // Checking bounds
-        if self.read_cursor + 2 >= MAX_MESSAGE_SIZE {
-            return Err(NetworkBufferError::BufferEmptyError);
-        }
+        self.check_read(16)?;
 
         let value = (self.buf[self.read_cursor] as u128) << 120
             | (self.buf[self.read_cursor + 1] as u128) << 112
@@ -178,7 +257,10 @@ impl NetworkBuffer {
         Ok(value)
     }
 
+    /// Clear the buffer's bytes and move both cursors back to the start, so nothing of the
+    /// previous message is left behind
     pub fn reset(&mut self) {
+        self.buf.fill(0);
         self.read_cursor = 0;
         self.write_cursor = 0;
     }
@@ -186,6 +268,36 @@ impl NetworkBuffer {
     pub fn write_count(&self) -> usize {
         self.write_cursor
     }
+
+    /// Index the next byte will be read from
+    pub fn read_position(&self) -> usize {
+        self.read_cursor
+    }
+
+    /// Move to read from the given index, e.g. to follow a name pointer
+    pub fn set_read_position(&mut self, position: usize) {
+        self.read_cursor = position;
+    }
+
+    /// Index the next byte will be written to
+    pub fn write_position(&self) -> usize {
+        self.write_cursor
+    }
+
+    /// Mark the buffer as holding data up to the given index, e.g. after receiving a datagram into `buf`
+    pub fn set_write_position(&mut self, position: usize) {
+        self.write_cursor = position;
+    }
+
+    /// Number of written bytes not yet read
+    pub fn remaining(&self) -> usize {
+        self.write_cursor.saturating_sub(self.read_cursor)
+    }
+
+    /// Discard any written bytes not yet read
+    pub fn skip_remaining(&mut self) {
+        self.read_cursor = self.read_cursor.max(self.write_cursor);
+    }
 }
 
 #[cfg(test)]
@@ -223,6 +335,22 @@ mod tests {
         assert_eq!(buf.write_count(), 4);
     }
 
+    #[test]
+    fn test_put_u128() {
+        let mut buf = NetworkBuffer::new();
+        buf.put_u128(0x20010DB8_00000000_00000000_000000FF).unwrap();
+
+        assert_eq!(
+            buf.buf[..16],
+            [0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF]
+        );
+        assert_eq!(buf.write_count(), 16);
+        assert_eq!(
+            buf.get_u128().unwrap(),
+            0x20010DB8_00000000_00000000_000000FF
+        );
+    }
+
     #[test]
     fn test_put_u8_at_capacity() {
         let mut buf = NetworkBuffer::new();
@@ -231,9 +359,11 @@ mod tests {
 
         assert!(buf.put_u8(0xFF).is_err());
 
+        // The last byte of a full size datagram can be written
         buf.write_cursor = MAX_MESSAGE_SIZE - 1;
 
-        assert!(buf.put_u8(0xFF).is_err());
+        assert!(buf.put_u8(0xFF).is_ok());
+        assert_eq!(buf.write_count(), MAX_MESSAGE_SIZE);
     }
 
     #[test]
@@ -247,7 +377,7 @@ mod tests {
         assert!(buf.put_u16(0xFFFF).is_err());
 
         buf.write_cursor = MAX_MESSAGE_SIZE - 2;
-        assert!(buf.put_u16(0xFFFF).is_err());
+        assert!(buf.put_u16(0xFFFF).is_ok());
     }
 
     #[test]
@@ -266,13 +396,59 @@ mod tests {
 
         assert!(buf.put_u32(0xFFFF).is_err());
         buf.write_cursor = MAX_MESSAGE_SIZE - 4;
-        assert!(buf.put_u32(0xFFFF).is_err());
+        assert!(buf.put_u32(0xFFFF).is_ok());
+    }
+
+    #[test]
+    fn test_growable_buffer() {
+        let mut buf = NetworkBuffer::with_max_size(4096);
+
+        assert!(buf.buf.is_empty());
+
+        for _ in 0..1024 {
+            buf.put_u32(0x12345678).unwrap();
+        }
+
+        assert_eq!(buf.write_count(), 4096);
+        assert_eq!(buf.buf.len(), 4096);
+
+        // Capped at the configured size
+        assert!(buf.put_u8(0xFF).is_err());
+
+        for _ in 0..1024 {
+            assert_eq!(buf.get_u32().unwrap(), 0x12345678);
+        }
+
+        assert!(buf.get_u8().is_err());
+
+        // Reading never runs past what was allocated
+        let mut buf = NetworkBuffer::with_max_size(4096);
+        buf.put_u16(0x1234).unwrap();
+
+        assert!(buf.get_u32().is_err());
+    }
+
+    #[test]
+    fn test_with_bytes_respects_max_size() {
+        let bytes = [0xAB; 2048];
+
+        let mut buf = NetworkBuffer::with_max_size(4096)
+            .with_bytes(&bytes)
+            .unwrap();
+
+        assert_eq!(buf.remaining(), 2048);
+        assert_eq!(buf.get_u8().unwrap(), 0xAB);
+
+        assert!(NetworkBuffer::with_max_size(1024)
+            .with_bytes(&bytes)
+            .is_err());
+        assert!(NetworkBuffer::new().with_bytes(&bytes).is_err());
+        assert_eq!(NetworkBuffer::new().max_size(), MAX_MESSAGE_SIZE);
     }
 
     #[test]
     fn test_get_u8() {
-        let mut buf = NetworkBuffer::new();
-        buf.buf[0] = 0xFF;
+        let mut buf = NetworkBuffer::from_bytes(&[0xFF]).unwrap();
 
         let value = buf.get_u8().unwrap();
 
@@ -282,9 +458,7 @@ mod tests {
 
     #[test]
     fn test_get_u16() {
-        let mut buf = NetworkBuffer::new();
-        buf.buf[0] = 0xFF;
-        buf.buf[1] = 0x11;
+        let mut buf = NetworkBuffer::from_bytes(&[0xFF, 0x11]).unwrap();
 
         let value = buf.get_u16().unwrap();
 
@@ -294,15 +468,129 @@ mod tests {
 
     #[test]
     fn test_get_u32() {
-        let mut buf = NetworkBuffer::new();
-        buf.buf[0] = 0xFF;
-        buf.buf[1] = 0x11;
-        buf.buf[2] = 0x22;
-        buf.buf[3] = 0x33;
+        let mut buf = NetworkBuffer::from_bytes(&[0xFF, 0x11, 0x22, 0x33]).unwrap();
 
         let value = buf.get_u32().unwrap();
 
         assert_eq!(value, 0xFF112233);
         assert_eq!(buf.read_cursor, 4);
     }
+
+    #[test]
+    fn test_positions_and_remaining() {
+        let mut buf = NetworkBuffer::new();
+
+        buf.put_u16(0x1234).unwrap();
+        buf.put_u32(0x12345678).unwrap();
+
+        assert_eq!(buf.write_position(), 6);
+        assert_eq!(buf.read_position(), 0);
+        assert_eq!(buf.remaining(), 6);
+
+        buf.get_u16().unwrap();
+
+        assert_eq!(buf.read_position(), 2);
+        assert_eq!(buf.remaining(), 4);
+
+        buf.get_u32().unwrap();
+
+        assert_eq!(buf.read_position(), 6);
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let mut buf = NetworkBuffer::from_bytes(&[0xFF, 0x11, 0x22, 0x33]).unwrap();
+
+        assert_eq!(buf.write_position(), 4);
+        assert_eq!(buf.get_u32().unwrap(), 0xFF112233);
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn test_from_bytes_over_length() {
+        assert!(NetworkBuffer::from_bytes(&[0; MAX_MESSAGE_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn test_peek_u8() {
+        let mut buf = NetworkBuffer::from_bytes(&[0xC0, 0x0C]).unwrap();
+
+        assert_eq!(buf.peek_u8().unwrap(), 0xC0);
+        assert_eq!(buf.read_position(), 0);
+
+        assert_eq!(buf.get_u8().unwrap(), 0xC0);
+        assert_eq!(buf.read_position(), 1);
+    }
+
+    #[test]
+    fn test_peek_u16() {
+        let mut buf = NetworkBuffer::from_bytes(&[0xC0, 0x0C]).unwrap();
+
+        assert_eq!(buf.peek_u16().unwrap(), 0xC00C);
+        assert_eq!(buf.peek_u16().unwrap(), 0xC00C);
+        assert_eq!(buf.read_position(), 0);
+
+        assert_eq!(buf.get_u16().unwrap(), 0xC00C);
+        assert_eq!(buf.read_position(), 2);
+    }
+
+    #[test]
+    fn test_peek_out_of_bounds() {
+        let mut buf = NetworkBuffer::from_bytes(&[0; MAX_MESSAGE_SIZE]).unwrap();
+
+        for _ in 0..MAX_MESSAGE_SIZE - 1 {
+            buf.get_u8().ok();
+        }
+
+        assert!(buf.peek_u8().is_ok());
+        assert!(buf.peek_u16().is_err());
+
+        buf.get_u8().unwrap();
+
+        assert!(buf.peek_u8().is_err());
+    }
+
+    #[test]
+    fn test_read_past_written_length() {
+        // A fresh datagram buffer is allocated but holds nothing to read
+        let mut buf = NetworkBuffer::new();
+
+        assert!(buf.get_u8().is_err());
+        assert!(buf.peek_u8().is_err());
+
+        buf.put_u16(0x1234).unwrap();
+
+        assert!(buf.get_u32().is_err());
+        assert_eq!(buf.get_u16().unwrap(), 0x1234);
+        assert!(buf.get_u8().is_err());
+    }
+
+    #[test]
+    fn test_read_past_written_length_after_reset() {
+        let mut buf = NetworkBuffer::with_max_size(4096);
+
+        buf.put_u32(0x12345678).unwrap();
+        buf.put_u32(0x9ABCDEF0).unwrap();
+        buf.reset();
+
+        // Only the bytes written since the reset can be read
+        buf.put_u16(0xFFFF).unwrap();
+
+        assert!(buf.get_u32().is_err());
+        assert_eq!(buf.get_u16().unwrap(), 0xFFFF);
+        assert!(buf.peek_u8().is_err());
+        assert!(buf.get_u8().is_err());
+
+        // Receiving a shorter message doesn't expose the rest of the previous one
+        buf.reset();
+        buf.receive_buffer(2)
+            .unwrap()
+            .copy_from_slice(&[0xAB, 0xCD]);
+        buf.set_write_position(2);
+
+        assert_eq!(buf.get_u16().unwrap(), 0xABCD);
+        assert!(buf.get_u8().is_err());
+        assert!(buf.buf[2..].iter().all(|byte| *byte == 0));
+    }
 }
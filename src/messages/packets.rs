@@ -1,19 +1,24 @@
 use std::{
+    cmp::Ordering,
     fmt,
-    net::{Ipv4Addr, Ipv6Addr},
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-#[derive(Debug, Clone)]
+use super::{errors::InvalidMessageError, idna};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PacketType {
     Query,
     Response,
 }
 
-#[derive(Debug, Clone)]
-
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum QuestionClass {
     InternetAddress,
-    Unimplemented,
+    Chaos,
+    /// A class without its own variant, holding the class code
+    Unimplemented(u16),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -27,24 +32,81 @@ pub enum ResourceRecordType {
     SOARecord,
     SRVRecord,
     TXTRecord,
-    Unimplemented,
+    SPFRecord,
+    LOCRecord,
+    OPTRecord,
+    SVCBRecord,
+    HTTPSRecord,
+    TLSARecord,
+    DSRecord,
+    RRSIGRecord,
+    NSECRecord,
+    DNSKEYRecord,
+    NSEC3Record,
+    /// A type without its own variant, holding the type code
+    Unimplemented(u16),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ResourceRecordClass {
     InternetAddress,
-    Unimplemented,
+    Chaos,
+    /// A class without its own variant, holding the class code
+    Unimplemented(u16),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ResourceRecordData {
-    A(u32),
-    AAAA(u128),
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
     CName(String),
     SOA(SOARecord),
     MX(u16, String),
-    TXT(String),
+    /// Each character-string, in order, as raw bytes as they needn't be text
+    TXT(Vec<Vec<u8>>),
+    /// Legacy SPF record (RFC 4408), character strings as for TXT
+    SPF(Vec<Vec<u8>>),
     NS(String),
+    PTR(String),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    LOC {
+        version: u8,
+        size: u8,
+        horiz_pre: u8,
+        vert_pre: u8,
+        latitude: u32,
+        longitude: u32,
+        altitude: u32,
+    },
+    OPT(OptRecord),
+    SVCB(SVCBRecord),
+    HTTPS(SVCBRecord),
+    /// DANE certificate association (RFC 6698)
+    TLSA {
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        cert_data: Vec<u8>,
+    },
+    /// DNSSEC records are passed through unvalidated, kept as their raw record data so
+    /// signatures still verify downstream
+    DS(Vec<u8>),
+    RRSIG(Vec<u8>),
+    /// The next name is kept apart so it can be written without compression
+    NSEC {
+        next_domain: String,
+        type_bitmaps: Vec<u8>,
+    },
+    DNSKEY(Vec<u8>),
+    NSEC3(Vec<u8>),
+    /// Record of a type without its own variant, kept as its type code and raw record data
+    /// (RFC 3597)
+    Unknown(u16, Vec<u8>),
 }
 
 impl ResourceRecordData {
@@ -57,12 +119,50 @@ impl ResourceRecordData {
             ResourceRecordData::SOA(_) => ResourceRecordType::SOARecord,
             ResourceRecordData::MX(_, _) => ResourceRecordType::MXRecord,
             ResourceRecordData::NS(_) => ResourceRecordType::NSRecord,
+            ResourceRecordData::PTR(_) => ResourceRecordType::PTRRecord,
             ResourceRecordData::TXT(_) => ResourceRecordType::TXTRecord,
+            ResourceRecordData::SPF(_) => ResourceRecordType::SPFRecord,
+            ResourceRecordData::LOC { .. } => ResourceRecordType::LOCRecord,
+            ResourceRecordData::OPT(_) => ResourceRecordType::OPTRecord,
+            ResourceRecordData::SRV { .. } => ResourceRecordType::SRVRecord,
+            ResourceRecordData::SVCB(_) => ResourceRecordType::SVCBRecord,
+            ResourceRecordData::HTTPS(_) => ResourceRecordType::HTTPSRecord,
+            ResourceRecordData::TLSA { .. } => ResourceRecordType::TLSARecord,
+            ResourceRecordData::DS(_) => ResourceRecordType::DSRecord,
+            ResourceRecordData::RRSIG(_) => ResourceRecordType::RRSIGRecord,
+            ResourceRecordData::NSEC { .. } => ResourceRecordType::NSECRecord,
+            ResourceRecordData::DNSKEY(_) => ResourceRecordType::DNSKEYRecord,
+            ResourceRecordData::NSEC3(_) => ResourceRecordType::NSEC3Record,
+            ResourceRecordData::Unknown(code, _) => ResourceRecordType::Unimplemented(*code),
+        }
+    }
+
+    pub fn from_ipv4(addr: Ipv4Addr) -> ResourceRecordData {
+        ResourceRecordData::A(addr)
+    }
+
+    pub fn from_ipv6(addr: Ipv6Addr) -> ResourceRecordData {
+        ResourceRecordData::AAAA(addr)
+    }
+
+    /// Address of an A record, `None` for any other data
+    pub fn as_ipv4(&self) -> Option<Ipv4Addr> {
+        match self {
+            ResourceRecordData::A(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Address of an AAAA record, `None` for any other data
+    pub fn as_ipv6(&self) -> Option<Ipv6Addr> {
+        match self {
+            ResourceRecordData::AAAA(value) => Some(*value),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ResponseCode {
     None,
     FormatError,
@@ -70,6 +170,36 @@ pub enum ResponseCode {
     NameError,
     NotImplemented,
     Refused,
+    /// Missing or invalid server cookie (RFC 7873), an extended code carried partly in the OPT record
+    BadCookie,
+}
+
+impl ResponseCode {
+    /// Full 12 bit code, the upper 8 bits are carried in the OPT record
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            ResponseCode::None => 0,
+            ResponseCode::FormatError => 1,
+            ResponseCode::ServerError => 2,
+            ResponseCode::NameError => 3,
+            ResponseCode::NotImplemented => 4,
+            ResponseCode::Refused => 5,
+            ResponseCode::BadCookie => 23,
+        }
+    }
+
+    pub fn from_u16(value: u16) -> Option<ResponseCode> {
+        match value {
+            0 => Some(ResponseCode::None),
+            1 => Some(ResponseCode::FormatError),
+            2 => Some(ResponseCode::ServerError),
+            3 => Some(ResponseCode::NameError),
+            4 => Some(ResponseCode::NotImplemented),
+            5 => Some(ResponseCode::Refused),
+            23 => Some(ResponseCode::BadCookie),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -79,6 +209,25 @@ pub struct Question {
     pub class: QuestionClass,
 }
 
+/// Domain names compare case-insensitively
+impl PartialEq for Question {
+    fn eq(&self, other: &Self) -> bool {
+        self.domain.eq_ignore_ascii_case(&other.domain)
+            && self.question_type == other.question_type
+            && self.class == other.class
+    }
+}
+
+impl Eq for Question {}
+
+impl Hash for Question {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.domain.to_ascii_lowercase().hash(state);
+        self.question_type.hash(state);
+        self.class.hash(state);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ResourceRecord {
     pub domain: String,
@@ -88,7 +237,28 @@ pub struct ResourceRecord {
     pub data: ResourceRecordData,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Records are equal when they hold the same data for the same name, regardless of TTL or the name's case
+impl PartialEq for ResourceRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.domain.eq_ignore_ascii_case(&other.domain)
+            && self.record_type == other.record_type
+            && self.class == other.class
+            && self.data == other.data
+    }
+}
+
+impl Eq for ResourceRecord {}
+
+impl Hash for ResourceRecord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.domain.to_ascii_lowercase().hash(state);
+        self.record_type.hash(state);
+        self.class.hash(state);
+        self.data.hash(state);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct SOARecord {
     pub master_name: String,
     pub mail_name: String,
@@ -99,7 +269,165 @@ pub struct SOARecord {
     pub minimum: u32,
 }
 
-#[derive(Debug, Clone)]
+impl SOARecord {
+    /// Whether this SOA's serial is behind the other's, so a copy of the zone at this serial is
+    /// stale
+    pub fn is_older_than(&self, other: &SOARecord) -> bool {
+        serial_compare(self.serial, other.serial) == Some(Ordering::Less)
+    }
+}
+
+/// Compare two SOA serials using RFC 1982 sequence space arithmetic, so serials may wrap around
+///
+/// Returns an `Option` rather than an `Ordering` as serials exactly 2^31 apart are left
+/// undefined by the RFC (section 3.2), so have no order. Picking either answer would make a
+/// secondary treat a zone as stale, or as current, on no evidence.
+pub fn serial_compare(a: u32, b: u32) -> Option<Ordering> {
+    const HALF: u32 = 1 << 31;
+
+    match a.wrapping_sub(b) {
+        0 => Some(Ordering::Equal),
+        HALF => None,
+        distance if distance < HALF => Some(Ordering::Greater),
+        _ => Some(Ordering::Less),
+    }
+}
+
+/// Service binding record data, shared by the SVCB and HTTPS types
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct SVCBRecord {
+    pub priority: u16,
+    pub target: String,
+    /// SvcParam keys and their raw values, in wire order
+    pub params: Vec<(u16, Vec<u8>)>,
+}
+
+/// EDNS pseudo record, carried in the additional section with the class and TTL fields
+/// repurposed for the payload size, extended response code, version and flags.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct OptRecord {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl OptRecord {
+    pub fn new(udp_payload_size: u16) -> OptRecord {
+        OptRecord {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+
+    /// The TTL field, the extended RCODE, version and DO bit
+    pub fn ttl(&self) -> u32 {
+        (self.extended_rcode as u32) << 24
+            | (self.version as u32) << 16
+            | if self.dnssec_ok { 0x8000 } else { 0x0000 }
+    }
+
+    /// Wrap in a resource record for the additional section
+    pub fn to_resource_record(&self) -> ResourceRecord {
+        ResourceRecord {
+            domain: ".".to_string(),
+            record_type: ResourceRecordType::OPTRecord,
+            class: ResourceRecordClass::Unimplemented(self.udp_payload_size),
+            time_to_live: self.ttl(),
+            data: ResourceRecordData::OPT(self.clone()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum EdnsOption {
+    /// Extended DNS error (RFC 8914), an info code and optional text
+    ExtendedError(ExtendedErrorCode, String),
+    /// DNS cookie (RFC 7873), the client cookie and the server cookie if known
+    Cookie(Vec<u8>, Vec<u8>),
+    /// Client subnet (RFC 7871), the querying client's network
+    ClientSubnet(ClientSubnet),
+    Unknown(u16, Vec<u8>),
+}
+
+/// Network of the client a query is made on behalf of, and the network an answer applies to
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct ClientSubnet {
+    pub source_prefix_length: u8,
+    /// Set by the upstream, zero if the answer is the same for every client
+    pub scope_prefix_length: u8,
+    pub address: IpAddr,
+}
+
+impl ClientSubnet {
+    /// Truncate the client's address to the prefix length, so no more of it is revealed upstream
+    pub fn new(address: IpAddr, source_prefix_length: u8) -> ClientSubnet {
+        let (address, source_prefix_length) = match address {
+            IpAddr::V4(address) => {
+                let length = source_prefix_length.min(32);
+                let mask = u32::MAX.checked_shl(32 - length as u32).unwrap_or(0);
+
+                (IpAddr::V4((u32::from(address) & mask).into()), length)
+            }
+            IpAddr::V6(address) => {
+                let length = source_prefix_length.min(128);
+                let mask = u128::MAX.checked_shl(128 - length as u32).unwrap_or(0);
+
+                (IpAddr::V6((u128::from(address) & mask).into()), length)
+            }
+        };
+
+        ClientSubnet {
+            source_prefix_length,
+            scope_prefix_length: 0,
+            address,
+        }
+    }
+}
+
+/// Extended DNS error info codes
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum ExtendedErrorCode {
+    Other,
+    StaleAnswer,
+    Blocked,
+    Prohibited,
+    NoReachableAuthority,
+    NetworkError,
+    Unknown(u16),
+}
+
+impl ExtendedErrorCode {
+    pub fn from_u16(value: u16) -> ExtendedErrorCode {
+        match value {
+            0 => ExtendedErrorCode::Other,
+            3 => ExtendedErrorCode::StaleAnswer,
+            15 => ExtendedErrorCode::Blocked,
+            18 => ExtendedErrorCode::Prohibited,
+            22 => ExtendedErrorCode::NoReachableAuthority,
+            23 => ExtendedErrorCode::NetworkError,
+            value => ExtendedErrorCode::Unknown(value),
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            ExtendedErrorCode::Other => 0,
+            ExtendedErrorCode::StaleAnswer => 3,
+            ExtendedErrorCode::Blocked => 15,
+            ExtendedErrorCode::Prohibited => 18,
+            ExtendedErrorCode::NoReachableAuthority => 22,
+            ExtendedErrorCode::NetworkError => 23,
+            ExtendedErrorCode::Unknown(value) => *value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
     pub id: u16,
     pub packet_type: PacketType,
@@ -108,6 +436,10 @@ pub struct Message {
     pub truncation: bool,
     pub recursion_desired: bool,
     pub recursion_available: bool,
+    /// The answers were validated with DNSSEC, only set by a validating server
+    pub authentic_data: bool,
+    /// The client asks for answers without DNSSEC validation
+    pub checking_disabled: bool,
     pub response_code: ResponseCode,
 
     pub questions: Vec<Question>,
@@ -116,7 +448,148 @@ pub struct Message {
     pub additional_records: Vec<ResourceRecord>,
 }
 
+impl Question {
+    /// Create an internet class question, normalizing the domain to lowercase with a single trailing dot
+    ///
+    /// Labels with non-ASCII characters are converted to their `xn--` form.
+    pub fn new(domain: &str, question_type: ResourceRecordType) -> Question {
+        let mut domain = idna::to_ascii(&domain.trim_end_matches('.').to_lowercase());
+        domain.push('.');
+
+        Question {
+            domain,
+            question_type,
+            class: QuestionClass::InternetAddress,
+        }
+    }
+}
+
+impl Default for Message {
+    /// A standard query with no flags set and empty sections
+    fn default() -> Self {
+        Message {
+            id: 0,
+            packet_type: PacketType::Query,
+            op_code: 0,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: false,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additional_records: vec![],
+        }
+    }
+}
+
 impl Message {
+    /// A standard query for the question, asking for recursion
+    pub fn query(id: u16, question: Question) -> Message {
+        Message {
+            id,
+            recursion_desired: true,
+            questions: vec![question],
+            ..Message::default()
+        }
+    }
+
+    /// A response with no error and empty sections
+    pub fn response(id: u16) -> Message {
+        Message {
+            id,
+            packet_type: PacketType::Response,
+            ..Message::default()
+        }
+    }
+
+    /// Get the EDNS record from the additional section if present
+    pub fn opt(&self) -> Option<&OptRecord> {
+        self.additional_records
+            .iter()
+            .find_map(|record| match &record.data {
+                ResourceRecordData::OPT(opt) => Some(opt),
+                _ => None,
+            })
+    }
+
+    /// Client subnet option carried in the EDNS record
+    pub fn client_subnet(&self) -> Option<&ClientSubnet> {
+        self.opt()?.options.iter().find_map(|option| match option {
+            EdnsOption::ClientSubnet(subnet) => Some(subnet),
+            _ => None,
+        })
+    }
+
+    pub fn opt_mut(&mut self) -> Option<&mut OptRecord> {
+        self.additional_records
+            .iter_mut()
+            .find_map(|record| match &mut record.data {
+                ResourceRecordData::OPT(opt) => Some(opt),
+                _ => None,
+            })
+    }
+
+    pub fn is_query(&self) -> bool {
+        matches!(self.packet_type, PacketType::Query)
+    }
+
+    pub fn is_response(&self) -> bool {
+        matches!(self.packet_type, PacketType::Response)
+    }
+
+    /// Check the basic invariants of a message, only standard queries in a supported class are
+    /// accepted. The reason a message is rejected says how a query should be answered.
+    pub fn validate(&self) -> Result<(), InvalidMessageError> {
+        // Counts are encoded as 16 bit integers
+        let max_count = u16::MAX as usize;
+
+        if self.questions.len() > max_count
+            || self.answers.len() > max_count
+            || self.authorities.len() > max_count
+            || self.additional_records.len() > max_count
+        {
+            return Err(InvalidMessageError::TooManyRecords);
+        }
+
+        // Only standard queries are supported
+        if self.op_code != 0 {
+            return Err(InvalidMessageError::UnsupportedOpCode(self.op_code));
+        }
+
+        // A query has no answers or authorities to carry
+        if self.is_query() && !(self.answers.is_empty() && self.authorities.is_empty()) {
+            return Err(InvalidMessageError::RecordsInQuery);
+        }
+
+        if self
+            .questions
+            .iter()
+            .any(|question| matches!(question.class, QuestionClass::Unimplemented(_)))
+        {
+            return Err(InvalidMessageError::UnsupportedClass);
+        }
+
+        Ok(())
+    }
+
+    /// Get every matching record from answers, authorities and additional records in that order
+    pub fn get_records(
+        &self,
+        record_type: &ResourceRecordType,
+        domain: &str,
+    ) -> Vec<&ResourceRecord> {
+        self.answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additional_records.iter())
+            .filter(|record| record.record_type.eq(record_type) && record.domain.eq(domain))
+            .collect()
+    }
+
     /// Get a record from answers first or additional records second
     pub fn get_record(
         &self,
@@ -149,6 +622,44 @@ impl Message {
             .iter()
             .find(|record| record.record_type.eq(record_type) && record.domain.eq(domain))
     }
+
+    /// Every answer of the given type, whatever its name
+    pub fn answers_of_type(&self, record_type: ResourceRecordType) -> Vec<&ResourceRecord> {
+        self.answers
+            .iter()
+            .filter(|record| record.record_type == record_type)
+            .collect()
+    }
+
+    pub fn first_answer(&self) -> Option<&ResourceRecord> {
+        self.answers.first()
+    }
+
+    /// Copy of the message with only the header, questions and EDNS record, TC set, to send in
+    /// place of a message too large for the transport
+    pub fn truncated(&self) -> Message {
+        Message {
+            id: self.id,
+            packet_type: self.packet_type.clone(),
+            op_code: self.op_code,
+            authoritative_answer: self.authoritative_answer,
+            truncation: true,
+            recursion_desired: self.recursion_desired,
+            recursion_available: self.recursion_available,
+            authentic_data: self.authentic_data,
+            checking_disabled: self.checking_disabled,
+            response_code: self.response_code.clone(),
+            questions: self.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additional_records: self
+                .additional_records
+                .iter()
+                .filter(|record| record.record_type == ResourceRecordType::OPTRecord)
+                .cloned()
+                .collect(),
+        }
+    }
 }
 
 impl fmt::Display for Message {
@@ -202,23 +713,225 @@ impl fmt::Display for ResourceRecordType {
             ResourceRecordType::SOARecord => write!(f, "SOARecord"),
             ResourceRecordType::SRVRecord => write!(f, "SRVRecord"),
             ResourceRecordType::TXTRecord => write!(f, "TXTRecord"),
-            ResourceRecordType::Unimplemented => write!(f, "Unimplemented"),
+            ResourceRecordType::SPFRecord => write!(f, "SPFRecord"),
+            ResourceRecordType::LOCRecord => write!(f, "LOCRecord"),
+            ResourceRecordType::OPTRecord => write!(f, "OPTRecord"),
+            ResourceRecordType::SVCBRecord => write!(f, "SVCBRecord"),
+            ResourceRecordType::HTTPSRecord => write!(f, "HTTPSRecord"),
+            ResourceRecordType::TLSARecord => write!(f, "TLSARecord"),
+            ResourceRecordType::DSRecord => write!(f, "DSRecord"),
+            ResourceRecordType::RRSIGRecord => write!(f, "RRSIGRecord"),
+            ResourceRecordType::NSECRecord => write!(f, "NSECRecord"),
+            ResourceRecordType::DNSKEYRecord => write!(f, "DNSKEYRecord"),
+            ResourceRecordType::NSEC3Record => write!(f, "NSEC3Record"),
+            ResourceRecordType::Unimplemented(code) => write!(f, "Unimplemented({})", code),
         }
     }
 }
 
 impl fmt::Display for Question {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "domain:{} type:{} ", self.domain, self.question_type)
+        write!(
+            f,
+            "domain:{} type:{} ",
+            idna::to_unicode(&self.domain),
+            self.question_type
+        )
+    }
+}
+
+impl ResourceRecordType {
+    /// Mnemonic used in master files
+    ///
+    /// Types without one are written in the generic form, e.g. `TYPE65281` (RFC 3597).
+    pub fn mnemonic(&self) -> String {
+        let mnemonic = match self {
+            ResourceRecordType::ARecord => "A",
+            ResourceRecordType::AAAARecord => "AAAA",
+            ResourceRecordType::CNameRecord => "CNAME",
+            ResourceRecordType::MXRecord => "MX",
+            ResourceRecordType::NSRecord => "NS",
+            ResourceRecordType::PTRRecord => "PTR",
+            ResourceRecordType::SOARecord => "SOA",
+            ResourceRecordType::SRVRecord => "SRV",
+            ResourceRecordType::TXTRecord => "TXT",
+            ResourceRecordType::SPFRecord => "SPF",
+            ResourceRecordType::LOCRecord => "LOC",
+            ResourceRecordType::OPTRecord => "OPT",
+            ResourceRecordType::SVCBRecord => "SVCB",
+            ResourceRecordType::HTTPSRecord => "HTTPS",
+            ResourceRecordType::TLSARecord => "TLSA",
+            ResourceRecordType::DSRecord => "DS",
+            ResourceRecordType::RRSIGRecord => "RRSIG",
+            ResourceRecordType::NSECRecord => "NSEC",
+            ResourceRecordType::DNSKEYRecord => "DNSKEY",
+            ResourceRecordType::NSEC3Record => "NSEC3",
+            ResourceRecordType::Unimplemented(code) => return format!("TYPE{}", code),
+        };
+
+        mnemonic.to_string()
+    }
+}
+
+impl ResourceRecordClass {
+    /// Mnemonic used in master files, or the generic form such as `CLASS32` (RFC 3597)
+    pub fn mnemonic(&self) -> String {
+        match self {
+            ResourceRecordClass::InternetAddress => "IN".to_string(),
+            ResourceRecordClass::Chaos => "CH".to_string(),
+            ResourceRecordClass::Unimplemented(code) => format!("CLASS{}", code),
+        }
     }
 }
 
+impl ResourceRecord {
+    /// Render the record in master file presentation format
+    ///
+    /// ```text
+    /// www.example.com. 300 IN A 1.2.3.4
+    /// ```
+    pub fn to_presentation(&self) -> String {
+        let rdata = match &self.data {
+            ResourceRecordData::A(value) => value.to_string(),
+            ResourceRecordData::AAAA(value) => value.to_string(),
+            ResourceRecordData::CName(value)
+            | ResourceRecordData::NS(value)
+            | ResourceRecordData::PTR(value) => value.clone(),
+            ResourceRecordData::MX(preference, exchange) => format!("{} {}", preference, exchange),
+            ResourceRecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => format!("{} {} {} {}", priority, weight, port, target),
+            ResourceRecordData::TXT(value) | ResourceRecordData::SPF(value) => present_txt(value),
+            // Timers are split over lines and annotated
+            ResourceRecordData::SOA(value) => format!(
+                "{} {} (\n\t{} ; serial\n\t{} ; refresh\n\t{} ; retry\n\t{} ; expire\n\t{} ; minimum\n)",
+                value.master_name,
+                value.mail_name,
+                value.serial,
+                value.refresh,
+                value.retry,
+                value.expire,
+                value.minimum
+            ),
+            ResourceRecordData::SVCB(value) | ResourceRecordData::HTTPS(value) => {
+                value.to_string()
+            }
+            // Types in the generic form, there's no mnemonic for every type
+            ResourceRecordData::NSEC {
+                next_domain,
+                type_bitmaps,
+            } => nsec_types(type_bitmaps)
+                .iter()
+                .fold(next_domain.clone(), |rdata, record_type| {
+                    format!("{} TYPE{}", rdata, record_type)
+                }),
+            // Opaque record data in the generic form (RFC 3597)
+            ResourceRecordData::DS(value)
+            | ResourceRecordData::RRSIG(value)
+            | ResourceRecordData::DNSKEY(value)
+            | ResourceRecordData::NSEC3(value)
+            | ResourceRecordData::Unknown(_, value) => {
+                let hex: String = value.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+                format!("\\# {} {}", value.len(), hex)
+            }
+            data => {
+                // Fall back to the display form without its type prefix
+                let display = data.to_string();
+
+                match display.split_once(": ") {
+                    Some((_, rdata)) => rdata.to_string(),
+                    None => display,
+                }
+            }
+        };
+
+        format!(
+            "{} {} {} {} {}",
+            self.domain,
+            self.time_to_live,
+            self.class.mnemonic(),
+            self.record_type.mnemonic(),
+            rdata
+        )
+    }
+}
+
+/// Quote TXT data as character strings of at most 255 bytes, escaping quotes, backslashes and
+/// unprintable bytes
+fn present_txt(values: &[Vec<u8>]) -> String {
+    let strings: Vec<String> = values
+        .iter()
+        .flat_map(|value| txt_chunks(value))
+        .map(|chunk| {
+            let mut string = String::from("\"");
+
+            for byte in chunk {
+                match byte {
+                    b'"' | b'\\' => {
+                        string.push('\\');
+                        string.push(*byte as char);
+                    }
+                    0x20..=0x7E => string.push(*byte as char),
+                    _ => string.push_str(&format!("\\{:03}", byte)),
+                }
+            }
+
+            string.push('"');
+            string
+        })
+        .collect();
+
+    if strings.is_empty() {
+        return String::from("\"\"");
+    }
+
+    strings.join(" ")
+}
+
+/// Types present in NSEC type bitmaps, each a window number, bitmap length and bitmap (RFC 4034)
+fn nsec_types(type_bitmaps: &[u8]) -> Vec<u16> {
+    let mut types = vec![];
+    let mut index = 0;
+
+    while index + 2 <= type_bitmaps.len() {
+        let window = type_bitmaps[index] as u16;
+        let end = (index + 2 + type_bitmaps[index + 1] as usize).min(type_bitmaps.len());
+
+        for (offset, byte) in type_bitmaps[index + 2..end].iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(window * 256 + offset as u16 * 8 + bit);
+                }
+            }
+        }
+
+        index = end;
+    }
+
+    types
+}
+
+/// Split a string into character strings of at most 255 bytes, an empty string is kept as one
+pub(crate) fn txt_chunks(value: &[u8]) -> Vec<&[u8]> {
+    if value.is_empty() {
+        return vec![&[]];
+    }
+
+    value.chunks(255).collect()
+}
+
 impl fmt::Display for ResourceRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
             "domain:{} type:{} data:{} ",
-            self.domain, &self.record_type, &self.data
+            idna::to_unicode(&self.domain),
+            &self.record_type,
+            &self.data
         )
     }
 }
@@ -226,30 +939,8 @@ impl fmt::Display for ResourceRecord {
 impl fmt::Display for ResourceRecordData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ResourceRecordData::A(value) => write!(
-                f,
-                "ARecord: {}",
-                Ipv4Addr::new(
-                    (*value >> 24) as u8,
-                    (*value >> 16) as u8,
-                    (*value >> 8) as u8,
-                    *value as u8
-                )
-            ),
-            ResourceRecordData::AAAA(value) => write!(
-                f,
-                "AAAARecord: {}",
-                Ipv6Addr::new(
-                    (value >> 112) as u16,
-                    (value >> 96) as u16,
-                    (value >> 80) as u16,
-                    (value >> 64) as u16,
-                    (value >> 48) as u16,
-                    (value >> 32) as u16,
-                    (value >> 16) as u16,
-                    (value & 0x00FF) as u16,
-                )
-            ),
+            ResourceRecordData::A(value) => write!(f, "ARecord: {}", value),
+            ResourceRecordData::AAAA(value) => write!(f, "AAAARecord: {}", value),
             ResourceRecordData::CName(value) => write!(f, "CName: {}", value),
             ResourceRecordData::SOA(value) => write!(f, "SOARecord: {:?}", value),
             ResourceRecordData::MX(preference, exchange) => write!(
@@ -257,8 +948,573 @@ impl fmt::Display for ResourceRecordData {
                 "MXRecord: preference {:?}, exchange {:?}",
                 preference, exchange
             ),
-            ResourceRecordData::TXT(value) => write!(f, "TXTRecord: {:?}", value),
+            ResourceRecordData::TXT(value) => write!(f, "TXTRecord: {}", present_txt(value)),
+            ResourceRecordData::SPF(value) => write!(f, "SPFRecord: {}", present_txt(value)),
             ResourceRecordData::NS(value) => write!(f, "NSRecord: {:?}", value),
+            ResourceRecordData::PTR(value) => write!(f, "PTRRecord: {:?}", value),
+            ResourceRecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(
+                f,
+                "SRVRecord: priority {} weight {} port {} target {:?}",
+                priority, weight, port, target
+            ),
+            ResourceRecordData::OPT(value) => write!(
+                f,
+                "OPTRecord: payload {} version {} options {:?}",
+                value.udp_payload_size, value.version, value.options
+            ),
+            ResourceRecordData::SVCB(value) => write!(f, "SVCBRecord: {}", value),
+            ResourceRecordData::HTTPS(value) => write!(f, "HTTPSRecord: {}", value),
+            ResourceRecordData::TLSA {
+                usage,
+                selector,
+                matching_type,
+                cert_data,
+            } => {
+                write!(f, "TLSARecord: {} {} {} ", usage, selector, matching_type)?;
+
+                for byte in cert_data.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+
+                Ok(())
+            }
+            ResourceRecordData::NSEC {
+                next_domain,
+                type_bitmaps,
+            } => write!(
+                f,
+                "NSECRecord: next {:?} types {:?}",
+                next_domain,
+                nsec_types(type_bitmaps)
+            ),
+            ResourceRecordData::DS(value)
+            | ResourceRecordData::RRSIG(value)
+            | ResourceRecordData::DNSKEY(value)
+            | ResourceRecordData::NSEC3(value)
+            | ResourceRecordData::Unknown(_, value) => {
+                write!(f, "{}: ", self.get_type())?;
+
+                for byte in value.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+
+                Ok(())
+            }
+            ResourceRecordData::LOC {
+                size,
+                horiz_pre,
+                vert_pre,
+                latitude,
+                longitude,
+                altitude,
+                ..
+            } => {
+                write!(f, "LOCRecord: ")?;
+                write_loc_coordinate(f, *latitude, 'N', 'S')?;
+                write!(f, " ")?;
+                write_loc_coordinate(f, *longitude, 'E', 'W')?;
+
+                // Altitude is in centimeters from a base 100000m below the WGS 84 spheroid
+                let altitude = *altitude as i64 - 10_000_000;
+
+                write!(
+                    f,
+                    " {}{}.{:02}m {} {} {}",
+                    if altitude < 0 { "-" } else { "" },
+                    altitude.abs() / 100,
+                    altitude.abs() % 100,
+                    LocPrecision(*size),
+                    LocPrecision(*horiz_pre),
+                    LocPrecision(*vert_pre)
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for SVCBRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.priority, self.target)?;
+
+        for (key, value) in self.params.iter() {
+            match key {
+                // ALPN ids as a comma separated list
+                1 => {
+                    let mut ids = vec![];
+                    let mut index = 0;
+
+                    while index < value.len() {
+                        let length = value[index] as usize;
+                        let end = (index + 1 + length).min(value.len());
+                        ids.push(String::from_utf8_lossy(&value[index + 1..end]).to_string());
+                        index = end;
+                    }
+
+                    write!(f, " alpn={}", ids.join(","))?;
+                }
+                3 if value.len() == 2 => {
+                    write!(f, " port={}", u16::from_be_bytes([value[0], value[1]]))?
+                }
+                _ => {
+                    write!(f, " key{}=", key)?;
+
+                    for byte in value.iter() {
+                        write!(f, "{:02x}", byte)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Write a LOC coordinate, stored as thousandths of an arc second offset by 2^31, in degrees minutes seconds
+fn write_loc_coordinate(
+    f: &mut fmt::Formatter<'_>,
+    value: u32,
+    positive: char,
+    negative: char,
+) -> fmt::Result {
+    let offset = value as i64 - (1 << 31);
+    let hemisphere = if offset < 0 { negative } else { positive };
+    let offset = offset.abs();
+
+    let degrees = offset / 3_600_000;
+    let minutes = offset % 3_600_000 / 60_000;
+    let seconds = offset % 60_000;
+
+    write!(
+        f,
+        "{} {} {}.{:03} {}",
+        degrees,
+        minutes,
+        seconds / 1000,
+        seconds % 1000,
+        hemisphere
+    )
+}
+
+/// LOC size and precision, a mantissa and power of ten exponent in centimeters
+struct LocPrecision(u8);
+
+impl fmt::Display for LocPrecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let centimeters = (self.0 >> 4) as u64 * 10u64.pow((self.0 & 0x0F) as u32);
+
+        if centimeters % 100 == 0 {
+            write!(f, "{}m", centimeters / 100)
+        } else {
+            write!(f, "{}.{:02}m", centimeters / 100, centimeters % 100)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query() -> Message {
+        Message::query(
+            1,
+            Question::new("example.com.", ResourceRecordType::ARecord),
+        )
+    }
+
+    #[test]
+    fn test_query_constructor() {
+        let message = query();
+
+        assert_eq!(message.id, 1);
+        assert_eq!(message.packet_type, PacketType::Query);
+        assert_eq!(message.op_code, 0);
+        assert!(message.recursion_desired);
+        assert!(!message.recursion_available);
+        assert!(!message.authoritative_answer);
+        assert!(!message.truncation);
+        assert_eq!(message.response_code, ResponseCode::None);
+        assert_eq!(message.questions.len(), 1);
+        assert!(message.answers.is_empty());
+        assert!(message.authorities.is_empty());
+        assert!(message.additional_records.is_empty());
+    }
+
+    #[test]
+    fn test_response_constructor() {
+        let message = Message::response(7);
+
+        assert_eq!(message.id, 7);
+        assert_eq!(message.packet_type, PacketType::Response);
+        assert!(!message.recursion_desired);
+        assert_eq!(message.response_code, ResponseCode::None);
+        assert!(message.questions.is_empty());
+
+        assert_eq!(Message::default().packet_type, PacketType::Query);
+        assert!(!Message::default().recursion_desired);
+    }
+
+    #[test]
+    fn test_validate_query() {
+        let message = query();
+
+        assert!(message.is_query());
+        assert!(!message.is_response());
+        assert!(message.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_query_with_answers() {
+        let mut message = query();
+
+        message.answers.push(ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        });
+
+        assert_eq!(message.validate(), Err(InvalidMessageError::RecordsInQuery));
+        assert_eq!(
+            message.validate().unwrap_err().response_code(),
+            ResponseCode::FormatError
+        );
+    }
+
+    #[test]
+    fn test_validate_unsupported_query() {
+        let mut message = query();
+        message.op_code = 2;
+
+        assert_eq!(
+            message.validate(),
+            Err(InvalidMessageError::UnsupportedOpCode(2))
+        );
+
+        let mut message = query();
+        message.questions[0].class = QuestionClass::Unimplemented(0x00FF);
+
+        assert_eq!(
+            message.validate(),
+            Err(InvalidMessageError::UnsupportedClass)
+        );
+        assert_eq!(
+            message.validate().unwrap_err().response_code(),
+            ResponseCode::NotImplemented
+        );
+    }
+
+    #[test]
+    fn test_record_accessors() {
+        let cname = ResourceRecord {
+            domain: "www.example.com.".to_string(),
+            record_type: ResourceRecordType::CNameRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::CName("example.com.".to_string()),
+        };
+        let address = |host| ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, host)),
+        };
+        let glue = ResourceRecord {
+            domain: "ns1.example.com.".to_string(),
+            ..address(53)
+        };
+
+        let mut message = Message::response(1);
+        message.answers = vec![cname.clone(), address(1), address(2)];
+        message.additional_records = vec![glue.clone()];
+
+        assert_eq!(message.first_answer(), Some(&cname));
+        assert_eq!(
+            message.answers_of_type(ResourceRecordType::ARecord),
+            vec![&address(1), &address(2)]
+        );
+        assert!(message
+            .answers_of_type(ResourceRecordType::AAAARecord)
+            .is_empty());
+
+        // Only records for the exact name, answers before additional records
+        assert_eq!(
+            message.get_record(&ResourceRecordType::ARecord, "example.com."),
+            Some(&address(1))
+        );
+        assert_eq!(
+            message.get_record(&ResourceRecordType::ARecord, "ns1.example.com."),
+            Some(&glue)
+        );
+        assert_eq!(
+            message.get_record(&ResourceRecordType::ARecord, "www.example.com."),
+            None
+        );
+
+        assert_eq!(Message::response(1).first_answer(), None);
+    }
+
+    #[test]
+    fn test_question_new_adds_trailing_dot() {
+        let question = Question::new("www.Example.COM", ResourceRecordType::ARecord);
+
+        assert_eq!(question.domain, "www.example.com.");
+        assert!(matches!(question.class, QuestionClass::InternetAddress));
+    }
+
+    #[test]
+    fn test_question_new_keeps_single_trailing_dot() {
+        let question = Question::new("www.example.com..", ResourceRecordType::ARecord);
+
+        assert_eq!(question.domain, "www.example.com.");
+    }
+
+    #[test]
+    fn test_question_new_converts_unicode_labels() {
+        let question = Question::new("www.München.de", ResourceRecordType::ARecord);
+
+        assert_eq!(question.domain, "www.xn--mnchen-3ya.de.");
+        assert_eq!(question.to_string(), "domain:www.münchen.de. type:ARecord ");
+    }
+
+    #[test]
+    fn test_address_accessors() {
+        let v4 = Ipv4Addr::new(93, 184, 216, 34);
+        let v6: Ipv6Addr = "2606:2800:220:1:248:1893:25c8:1946".parse().unwrap();
+
+        assert_eq!(ResourceRecordData::from_ipv4(v4), ResourceRecordData::A(v4));
+        assert_eq!(
+            ResourceRecordData::from_ipv6(v6),
+            ResourceRecordData::AAAA(v6)
+        );
+
+        assert_eq!(ResourceRecordData::from_ipv4(v4).as_ipv4(), Some(v4));
+        assert_eq!(ResourceRecordData::from_ipv6(v6).as_ipv6(), Some(v6));
+
+        // Each accessor only matches its own record type
+        assert_eq!(ResourceRecordData::from_ipv4(v4).as_ipv6(), None);
+        assert_eq!(ResourceRecordData::from_ipv6(v6).as_ipv4(), None);
+        assert_eq!(
+            ResourceRecordData::NS("ns.example.com.".to_string()).as_ipv4(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_display_aaaa_keeps_every_group() {
+        let data = ResourceRecordData::AAAA("2606:2800:220:1:248:1893:25c8:1946".parse().unwrap());
+
+        assert_eq!(
+            data.to_string(),
+            "AAAARecord: 2606:2800:220:1:248:1893:25c8:1946"
+        );
+    }
+
+    fn record(domain: &str, data: ResourceRecordData) -> ResourceRecord {
+        ResourceRecord {
+            domain: domain.to_string(),
+            record_type: data.get_type(),
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_presentation_a() {
+        let record = record(
+            "www.example.com.",
+            ResourceRecordData::A(Ipv4Addr::new(1, 2, 3, 4)),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "www.example.com. 300 IN A 1.2.3.4"
+        );
+    }
+
+    #[test]
+    fn test_presentation_aaaa() {
+        let record = record(
+            "www.example.com.",
+            ResourceRecordData::AAAA("2001:db8::1".parse().unwrap()),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "www.example.com. 300 IN AAAA 2001:db8::1"
+        );
+    }
+
+    #[test]
+    fn test_presentation_cname() {
+        let record = record(
+            "www.example.com.",
+            ResourceRecordData::CName("example.com.".to_string()),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "www.example.com. 300 IN CNAME example.com."
+        );
+    }
+
+    #[test]
+    fn test_presentation_mx() {
+        let record = record(
+            "example.com.",
+            ResourceRecordData::MX(10, "mail.example.com.".to_string()),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "example.com. 300 IN MX 10 mail.example.com."
+        );
+    }
+
+    #[test]
+    fn test_presentation_ns() {
+        let record = record(
+            "example.com.",
+            ResourceRecordData::NS("ns1.example.com.".to_string()),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "example.com. 300 IN NS ns1.example.com."
+        );
+    }
+
+    #[test]
+    fn test_presentation_soa() {
+        let record = record(
+            "example.com.",
+            ResourceRecordData::SOA(SOARecord {
+                master_name: "ns1.example.com.".to_string(),
+                mail_name: "hostmaster.example.com.".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "example.com. 300 IN SOA ns1.example.com. hostmaster.example.com. (\n\
+             \t2024010101 ; serial\n\
+             \t7200 ; refresh\n\
+             \t3600 ; retry\n\
+             \t1209600 ; expire\n\
+             \t300 ; minimum\n\
+             )"
+        );
+    }
+
+    #[test]
+    fn test_presentation_txt() {
+        let record = record(
+            "example.com.",
+            ResourceRecordData::TXT(vec![b"say \"hi\" \\o/".to_vec(), vec![]]),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "example.com. 300 IN TXT \"say \\\"hi\\\" \\\\o/\" \"\""
+        );
+    }
+
+    #[test]
+    fn test_presentation_long_txt() {
+        let record = record(
+            "example.com.",
+            ResourceRecordData::TXT(vec![b"a".repeat(300)]),
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            format!(
+                "example.com. 300 IN TXT \"{}\" \"{}\"",
+                "a".repeat(255),
+                "a".repeat(45)
+            )
+        );
+    }
+
+    #[test]
+    fn test_presentation_srv() {
+        let record = record(
+            "_sip._tcp.example.com.",
+            ResourceRecordData::SRV {
+                priority: 10,
+                weight: 60,
+                port: 5060,
+                target: "sip.example.com.".to_string(),
+            },
+        );
+
+        assert_eq!(
+            record.to_presentation(),
+            "_sip._tcp.example.com. 300 IN SRV 10 60 5060 sip.example.com."
+        );
+    }
+
+    #[test]
+    fn test_serial_compare() {
+        assert_eq!(serial_compare(1, 1), Some(Ordering::Equal));
+        assert_eq!(serial_compare(1, 2), Some(Ordering::Less));
+        assert_eq!(serial_compare(2, 1), Some(Ordering::Greater));
+
+        // Largest increment allowed by the RFC
+        assert_eq!(serial_compare(0, (1 << 31) - 1), Some(Ordering::Less));
+        assert_eq!(serial_compare((1 << 31) - 1, 0), Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn test_serial_compare_wraparound() {
+        // Incrementing past the maximum wraps to small serials which are still greater
+        assert_eq!(serial_compare(u32::MAX, 0), Some(Ordering::Less));
+        assert_eq!(serial_compare(0, u32::MAX), Some(Ordering::Greater));
+        assert_eq!(serial_compare(u32::MAX - 10, 5), Some(Ordering::Less));
+        assert_eq!(serial_compare(5, u32::MAX - 10), Some(Ordering::Greater));
+
+        // The RFC's example with 2 bit serials, scaled up: 0 < 1 < 2 < 3 < 0
+        let quarter = 1 << 30;
+        for step in 0..4u32 {
+            let a = step.wrapping_mul(quarter);
+            let b = a.wrapping_add(quarter);
+            assert_eq!(serial_compare(a, b), Some(Ordering::Less));
+        }
+
+        // Exactly half the space apart is undefined, in either order
+        assert_eq!(serial_compare(1 << 31, 0), None);
+        assert_eq!(serial_compare(0, 1 << 31), None);
+        assert_eq!(serial_compare(5, (1 << 31) + 5), None);
+    }
+
+    #[test]
+    fn test_soa_is_older_than() {
+        let soa = |serial| SOARecord {
+            master_name: "ns1.example.com.".to_string(),
+            mail_name: "hostmaster.example.com.".to_string(),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+        };
+
+        assert!(soa(2024010101).is_older_than(&soa(2024010102)));
+        assert!(!soa(2024010101).is_older_than(&soa(2024010101)));
+        assert!(soa(u32::MAX - 3).is_older_than(&soa(3)));
+        assert!(!soa(3).is_older_than(&soa(u32::MAX - 3)));
+        assert!(!soa(0).is_older_than(&soa(1 << 31)));
+    }
+}
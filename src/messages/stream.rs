@@ -0,0 +1,220 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    coding::MessageCoder,
+    network_buffer::{NetworkBuffer, MAX_STREAM_MESSAGE_SIZE},
+    packets::Message,
+};
+
+type StreamResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Reads and writes messages over a stream transport such as TCP or TLS, where each
+/// message is prefixed with a two byte length.
+///
+/// Messages aren't limited to the size of a UDP datagram, so large answers can be sent in full.
+pub struct StreamConnection {
+    buf: NetworkBuffer,
+}
+
+impl Default for StreamConnection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamConnection {
+    pub fn new() -> StreamConnection {
+        StreamConnection {
+            buf: NetworkBuffer::with_max_size(MAX_STREAM_MESSAGE_SIZE),
+        }
+    }
+
+    pub async fn write_message<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        message: &Message,
+    ) -> StreamResult<usize> {
+        // Encode the message, MessageCoder instances should be ephemeral
+        let encoded = MessageCoder::new().encode_message(message, &mut self.buf);
+
+        let buffer_length = self.buf.write_count();
+
+        // Write the length prefix and message together
+        let mut frame = Vec::with_capacity(buffer_length + 2);
+        frame.extend_from_slice(&(buffer_length as u16).to_be_bytes());
+        frame.extend_from_slice(&self.buf.buf[..buffer_length]);
+
+        // Reset buffer for reuse
+        self.buf.reset();
+
+        encoded?;
+
+        stream.write_all(&frame).await?;
+        stream.flush().await?;
+
+        Ok(buffer_length)
+    }
+
+    /// Read the next message, returns None if the stream is closed before a message starts
+    ///
+    /// Reads may return any part of the message, so reading carries on until the length prefix
+    /// and the whole message it declares have arrived. Closing part way through is an error.
+    pub async fn read_message<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> StreamResult<Option<Message>> {
+        let mut length_bytes = [0u8; 2];
+        let mut length_read = 0;
+
+        while length_read < length_bytes.len() {
+            match stream.read(&mut length_bytes[length_read..]).await? {
+                0 if length_read == 0 => return Ok(None),
+                0 => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "stream closed within a length prefix",
+                    )))
+                }
+                read => length_read += read,
+            }
+        }
+
+        let length = u16::from_be_bytes(length_bytes) as usize;
+
+        // Fills the whole message or fails if the stream closes first
+        stream.read_exact(self.buf.receive_buffer(length)?).await?;
+
+        // Mark how much of the buffer holds the message
+        self.buf.set_write_position(length);
+
+        // The length prefix is exact, so leftover bytes mean the counts were wrong
+        let message = MessageCoder::new().decode_message_strict(&mut self.buf);
+
+        // Reset buffer for reuse
+        self.buf.reset();
+
+        Ok(Some(message?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::messages::packets::{
+        PacketType, Question, ResourceRecord, ResourceRecordClass, ResourceRecordData,
+        ResourceRecordType,
+    };
+
+    #[tokio::test]
+    async fn test_message_reassembled_from_partial_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let first = Message::query(
+            1,
+            Question::new("example.com.", ResourceRecordType::ARecord),
+        );
+        let second = Message::query(
+            2,
+            Question::new("www.example.org.", ResourceRecordType::AAAARecord),
+        );
+
+        // Both messages framed back to back, then split into small chunks
+        let mut frames = vec![];
+        StreamConnection::new()
+            .write_message(&mut frames, &first)
+            .await
+            .unwrap();
+        StreamConnection::new()
+            .write_message(&mut frames, &second)
+            .await
+            .unwrap();
+
+        let writer = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.set_nodelay(true).unwrap();
+
+            // The first chunk splits the length prefix
+            for chunk in frames.chunks(3) {
+                stream.write_all(chunk).await.unwrap();
+                stream.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+
+            // Closed part way through a third length prefix
+            stream.write_all(&[0]).await.unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut connection = StreamConnection::new();
+
+        assert_eq!(
+            connection.read_message(&mut stream).await.unwrap(),
+            Some(first)
+        );
+        assert_eq!(
+            connection.read_message(&mut stream).await.unwrap(),
+            Some(second)
+        );
+
+        writer.await.unwrap();
+
+        assert!(connection.read_message(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_closed_stream_between_messages() {
+        let (client, mut server) = io::duplex(64);
+        drop(client);
+
+        assert!(StreamConnection::new()
+            .read_message(&mut server)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_message_larger_than_datagram() {
+        let mut message = Message::query(
+            1,
+            Question::new("example.com.", ResourceRecordType::TXTRecord),
+        );
+        message.packet_type = PacketType::Response;
+
+        // Eight records of 255 byte strings, over 2KB once encoded
+        message.answers = (0..8)
+            .map(|index| ResourceRecord {
+                domain: "example.com.".to_string(),
+                record_type: ResourceRecordType::TXTRecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 300,
+                data: ResourceRecordData::TXT(vec![index.to_string().repeat(255).into_bytes()]),
+            })
+            .collect();
+
+        let (mut client, mut server) = io::duplex(4096);
+
+        let written = StreamConnection::new()
+            .write_message(&mut server, &message)
+            .await
+            .unwrap();
+
+        assert!(written > 2048);
+
+        let read = StreamConnection::new()
+            .read_message(&mut client)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(read, message);
+    }
+}
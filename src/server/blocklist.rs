@@ -0,0 +1,380 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use crate::messages::{
+    idna,
+    packets::{
+        ExtendedErrorCode, ResourceRecord, ResourceRecordClass, ResourceRecordData,
+        ResourceRecordType, ResponseCode,
+    },
+    Request, Response,
+};
+
+use super::{
+    config::{ServerConfig, DEFAULT_SYNTHESIZED_TTL},
+    errors::BlocklistError,
+    handler::{Handled, Handler, HandlerFuture},
+};
+
+/// Names found in most hosts files which shouldn't be blocked
+const HOSTS_FILE_NAMES: [&str; 6] = [
+    "localhost.",
+    "localhost.localdomain.",
+    "local.",
+    "broadcasthost.",
+    "ip6-localhost.",
+    "ip6-loopback.",
+];
+
+/// How queries for blocked names are answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockAction {
+    /// Answer with NXDOMAIN
+    NameError,
+
+    /// Answer A and AAAA queries with these addresses, other types with no records
+    Sink(Ipv4Addr, Ipv6Addr),
+}
+
+impl Default for BlockAction {
+    fn default() -> Self {
+        BlockAction::Sink(Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED)
+    }
+}
+
+/// Answers queries for blocked names without recursing, passing every other query on
+#[derive(Debug, Clone)]
+pub struct BlocklistHandler {
+    names: HashSet<String>,
+    suffixes: HashSet<String>,
+    action: BlockAction,
+    /// TTL of sink address answers, the server's synthesized TTL unless set
+    ttl: Option<u32>,
+}
+
+impl Default for BlocklistHandler {
+    fn default() -> Self {
+        BlocklistHandler::new(BlockAction::default())
+    }
+}
+
+fn normalize(name: &str) -> String {
+    let mut name = idna::to_ascii(&name.trim_end_matches('.').to_lowercase());
+    name.push('.');
+    name
+}
+
+impl BlocklistHandler {
+    pub fn new(action: BlockAction) -> BlocklistHandler {
+        BlocklistHandler {
+            names: HashSet::new(),
+            suffixes: HashSet::new(),
+            action,
+            ttl: None,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Block the name only
+    pub fn block(&mut self, name: &str) {
+        self.names.insert(normalize(name));
+    }
+
+    /// Block the name and every name below it
+    pub fn block_suffix(&mut self, name: &str) {
+        self.suffixes.insert(normalize(name));
+    }
+
+    /// Load blocked names from a file in hosts format, or a plain list of names
+    ///
+    /// ```text
+    /// # hosts format, the address is ignored
+    /// 0.0.0.0 ads.example.com tracker.example.com
+    ///
+    /// # plain names, a leading `*.` blocks every name below as well
+    /// ads.example.org
+    /// *.tracker.example.org
+    /// ```
+    pub fn load(path: &Path, action: BlockAction) -> Result<BlocklistHandler, BlocklistError> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| BlocklistError::ReadError(err.to_string()))?;
+
+        BlocklistHandler::parse(&contents, action)
+    }
+
+    pub fn parse(contents: &str, action: BlockAction) -> Result<BlocklistHandler, BlocklistError> {
+        let mut blocklist = BlocklistHandler::new(action);
+
+        for (index, line) in contents.lines().enumerate() {
+            // Strip comments
+            let line = line.split('#').next().unwrap_or_default();
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+
+            match tokens.as_slice() {
+                [] => continue,
+                [name] => match name.strip_prefix("*.") {
+                    Some(name) => blocklist.block_suffix(name),
+                    None => blocklist.block(name),
+                },
+                [addr, names @ ..] if addr.parse::<IpAddr>().is_ok() => {
+                    for name in names {
+                        if !HOSTS_FILE_NAMES.contains(&normalize(name).as_str()) {
+                            blocklist.block(name);
+                        }
+                    }
+                }
+                _ => {
+                    return Err(BlocklistError::InvalidLineError(
+                        index + 1,
+                        line.trim().to_string(),
+                    ))
+                }
+            }
+        }
+
+        Ok(blocklist)
+    }
+
+    /// Check the name and each of its parents against the blocked names
+    pub fn is_blocked(&self, name: &str) -> bool {
+        let name = normalize(name);
+
+        if self.names.contains(&name) {
+            return true;
+        }
+
+        let mut parent = name.as_str();
+
+        loop {
+            if self.suffixes.contains(parent) {
+                return true;
+            }
+
+            match parent.split_once('.') {
+                Some((_, rest)) if !rest.is_empty() => parent = rest,
+                _ => return false,
+            }
+        }
+    }
+
+    fn sink_record(
+        &self,
+        domain: &str,
+        record_type: &ResourceRecordType,
+    ) -> Option<ResourceRecord> {
+        let data = match (self.action, record_type) {
+            (BlockAction::Sink(addr, _), ResourceRecordType::ARecord) => {
+                ResourceRecordData::from_ipv4(addr)
+            }
+            (BlockAction::Sink(_, addr), ResourceRecordType::AAAARecord) => {
+                ResourceRecordData::from_ipv6(addr)
+            }
+            _ => return None,
+        };
+
+        Some(ResourceRecord {
+            domain: domain.to_string(),
+            record_type: record_type.clone(),
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: self.ttl.unwrap_or(DEFAULT_SYNTHESIZED_TTL),
+            data,
+        })
+    }
+}
+
+impl Handler for BlocklistHandler {
+    fn configure(&mut self, config: &ServerConfig) {
+        self.ttl.get_or_insert(config.synthesized_ttl);
+    }
+
+    fn handle<'a>(&'a self, request: &'a Request, mut response: Response) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let question = match request.questions().first() {
+                Some(question) if self.is_blocked(&question.domain) => question,
+                _ => return Ok(Handled::Pass(response)),
+            };
+
+            match self.action {
+                BlockAction::NameError => response.set_code(ResponseCode::NameError),
+                BlockAction::Sink(..) => response.set_answers(
+                    self.sink_record(&question.domain, &question.question_type)
+                        .into_iter()
+                        .collect(),
+                ),
+            }
+
+            response.set_extended_error(ExtendedErrorCode::Blocked, "");
+
+            Ok(Handled::Answered(response))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::packets::{Message, PacketType, Question};
+
+    fn query(domain: &str, question_type: ResourceRecordType) -> Request {
+        Request::new(Message {
+            id: 1,
+            packet_type: PacketType::Query,
+            op_code: 0,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![Question::new(domain, question_type)],
+            answers: vec![],
+            authorities: vec![],
+            additional_records: vec![],
+        })
+    }
+
+    async fn handle(blocklist: &BlocklistHandler, request: &Request) -> Handled {
+        blocklist.handle(request, request.response()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_exact_block() {
+        let mut blocklist = BlocklistHandler::new(BlockAction::NameError);
+        blocklist.block("ads.example.com");
+
+        let request = query("ads.example.com.", ResourceRecordType::ARecord);
+
+        match handle(&blocklist, &request).await {
+            Handled::Answered(response) => {
+                assert_eq!(response.message().response_code, ResponseCode::NameError);
+                assert!(response.message().answers.is_empty());
+            }
+            Handled::Pass(_) => panic!("Blocked name passed"),
+        }
+
+        // Only the exact name is blocked
+        let request = query("www.ads.example.com.", ResourceRecordType::ARecord);
+        assert!(matches!(
+            handle(&blocklist, &request).await,
+            Handled::Pass(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_suffix_block_answers_sink_address() {
+        let mut blocklist = BlocklistHandler::new(BlockAction::default());
+        blocklist.block_suffix("tracker.example.com.");
+
+        for (question_type, data) in [
+            (
+                ResourceRecordType::ARecord,
+                ResourceRecordData::A(Ipv4Addr::UNSPECIFIED),
+            ),
+            (
+                ResourceRecordType::AAAARecord,
+                ResourceRecordData::AAAA(Ipv6Addr::UNSPECIFIED),
+            ),
+        ] {
+            let request = query("a.b.tracker.example.com.", question_type);
+
+            match handle(&blocklist, &request).await {
+                Handled::Answered(response) => {
+                    assert_eq!(response.message().response_code, ResponseCode::None);
+                    assert_eq!(response.message().answers[0].data, data);
+                }
+                Handled::Pass(_) => panic!("Blocked name passed"),
+            }
+        }
+
+        assert!(blocklist.is_blocked("tracker.example.com."));
+
+        // Internationalized names match their `xn--` form on the wire
+        blocklist.block_suffix("bücher.example.");
+        assert!(blocklist.is_blocked("www.xn--bcher-kva.example."));
+        assert!(!blocklist.is_blocked("example.com."));
+        assert!(!blocklist.is_blocked("nottracker.example.com."));
+    }
+
+    #[tokio::test]
+    async fn test_sink_ttl_from_config() {
+        let request = query("ads.example.com.", ResourceRecordType::ARecord);
+        let config = ServerConfig::default().with_synthesized_ttl(120);
+
+        let ttl = |blocklist: &BlocklistHandler| {
+            blocklist
+                .sink_record("ads.example.com.", &ResourceRecordType::ARecord)
+                .unwrap()
+                .time_to_live
+        };
+
+        let mut blocklist = BlocklistHandler::default();
+        blocklist.block("ads.example.com.");
+
+        assert_eq!(ttl(&blocklist), DEFAULT_SYNTHESIZED_TTL);
+
+        // The server's synthesized TTL is used, unless the blocklist has its own
+        blocklist.configure(&config);
+        assert_eq!(ttl(&blocklist), 120);
+
+        let mut blocklist = BlocklistHandler::default().with_ttl(30);
+        blocklist.block("ads.example.com.");
+        blocklist.configure(&config);
+
+        match handle(&blocklist, &request).await {
+            Handled::Answered(response) => {
+                assert_eq!(response.message().answers[0].time_to_live, 30);
+            }
+            Handled::Pass(_) => panic!("Blocked name passed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unblocked_name_passes() {
+        let mut blocklist = BlocklistHandler::new(BlockAction::NameError);
+        blocklist.block_suffix("tracker.example.com.");
+
+        let request = query("www.example.com.", ResourceRecordType::ARecord);
+
+        match handle(&blocklist, &request).await {
+            Handled::Pass(response) => {
+                assert_eq!(response.message().response_code, ResponseCode::None);
+            }
+            Handled::Answered(_) => panic!("Unblocked name answered"),
+        }
+    }
+
+    #[test]
+    fn test_parse_hosts_file() {
+        let blocklist = BlocklistHandler::parse(
+            "# Blocked hosts\n\
+             127.0.0.1 localhost\n\
+             0.0.0.0 ads.example.com tracker.example.com # trackers\n\
+             \n\
+             plain.example.org\n\
+             *.wildcard.example.org\n",
+            BlockAction::NameError,
+        )
+        .unwrap();
+
+        assert!(blocklist.is_blocked("ads.example.com."));
+        assert!(blocklist.is_blocked("TRACKER.example.com."));
+        assert!(blocklist.is_blocked("plain.example.org."));
+        assert!(blocklist.is_blocked("a.wildcard.example.org."));
+        assert!(!blocklist.is_blocked("localhost."));
+        assert!(!blocklist.is_blocked("www.ads.example.com."));
+
+        assert!(matches!(
+            BlocklistHandler::parse("not an address", BlockAction::NameError),
+            Err(BlocklistError::InvalidLineError(1, _))
+        ));
+    }
+}
@@ -1,24 +1,113 @@
-use std::{collections::HashMap, vec};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 
 use chrono::Utc;
 use tokio::sync::RwLock;
 
 use crate::messages::packets::{
-    ResourceRecord, ResourceRecordClass, ResourceRecordData, ResourceRecordType,
+    Message, Question, ResourceRecord, ResourceRecordClass, ResourceRecordData, ResourceRecordType,
+    ResponseCode,
 };
 
-type CacheKey = (String, ResourceRecordType);
+/// Records are cached per name, type and class, so classes never clobber each other
+type CacheKey = (String, ResourceRecordType, ResourceRecordClass);
+
+/// Key of an internet class entry, the class of everything resolved
+fn internet_key(record_type: ResourceRecordType, domain: &str) -> CacheKey {
+    (
+        domain.to_string(),
+        record_type,
+        ResourceRecordClass::InternetAddress,
+    )
+}
+
+/// Key of a negative answer, NXDOMAIN covers every type of the name so has no type
+type NegativeKey = (String, Option<ResourceRecordType>);
+
+/// TTL of stale answers, as recommended by RFC 8767
+const STALE_ANSWER_TTL: u32 = 30;
+
+/// Fraction of the TTL left at which a popular entry is refreshed ahead of expiry
+const PREFETCH_REMAINING_DIVISOR: i64 = 10;
 
 #[derive(Debug)]
 pub struct HashCache {
     map: RwLock<HashMap<CacheKey, Vec<CacheValue>>>,
+
+    /// Entries with a background refresh in flight
+    prefetching: Mutex<HashSet<CacheKey>>,
+
+    /// NXDOMAIN and NODATA answers
+    negatives: RwLock<HashMap<NegativeKey, NegativeValue>>,
+
+    /// Seconds past expiry entries may still be served stale, disabled if not set
+    stale_window: Option<i64>,
+}
+
+/// An NXDOMAIN or NODATA answer, with the SOA record it was given with
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegativeAnswer {
+    pub response_code: ResponseCode,
+
+    /// SOA record from the authority section, its TTL is the negative TTL
+    pub soa: ResourceRecord,
 }
 
-#[derive(Debug, PartialEq)]
+impl NegativeAnswer {
+    /// Negative answer from an upstream response, only responses with no answers and an SOA in the
+    /// authority section can be cached
+    ///
+    /// The negative TTL is the SOA minimum, bounded by the SOA record's own TTL (RFC 2308).
+    pub fn from_response(message: &Message) -> Option<NegativeAnswer> {
+        if !message.answers.is_empty() {
+            return None;
+        }
+
+        if !matches!(
+            message.response_code,
+            ResponseCode::None | ResponseCode::NameError
+        ) {
+            return None;
+        }
+
+        let mut soa = message
+            .authorities
+            .iter()
+            .find(|record| record.record_type == ResourceRecordType::SOARecord)?
+            .clone();
+
+        soa.time_to_live = match &soa.data {
+            ResourceRecordData::SOA(value) => value.minimum.min(soa.time_to_live),
+            _ => return None,
+        };
+
+        Some(NegativeAnswer {
+            response_code: message.response_code.clone(),
+            soa,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct NegativeValue {
+    answer: NegativeAnswer,
+    expiration: i64,
+}
+
+#[derive(Debug)]
 struct CacheValue {
     data: ResourceRecordData,
     time_to_live: u32,
     expiration: i64,
+
+    /// Number of times served since cached
+    hits: AtomicU32,
 }
 
 impl CacheValue {
@@ -26,102 +115,811 @@ impl CacheValue {
         Utc::now().timestamp() > self.expiration
     }
 
+    /// Within the last tenth of the TTL
+    pub fn is_near_expiry(&self) -> bool {
+        (self.expiration - Utc::now().timestamp()) * PREFETCH_REMAINING_DIVISOR
+            <= self.time_to_live as i64
+    }
+
     pub fn from_resource_record(record: &ResourceRecord) -> CacheValue {
         CacheValue {
             data: record.data.clone(),
             time_to_live: record.time_to_live,
             expiration: Utc::now().timestamp() + record.time_to_live as i64,
+            hits: AtomicU32::new(0),
         }
     }
 
-    pub fn to_resource_record(&self, domain: &str) -> ResourceRecord {
+    pub fn to_resource_record(&self, domain: &str, class: &ResourceRecordClass) -> ResourceRecord {
         ResourceRecord {
             domain: domain.to_string(),
             record_type: self.data.get_type(),
-            class: ResourceRecordClass::InternetAddress,
+            class: class.clone(),
             time_to_live: self.time_to_live,
             data: self.data.clone(),
         }
     }
 }
 
+impl Default for HashCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HashCache {
     pub fn new() -> HashCache {
         HashCache {
             map: RwLock::new(HashMap::new()),
+            prefetching: Mutex::new(HashSet::new()),
+            negatives: RwLock::new(HashMap::new()),
+            stale_window: None,
         }
     }
 
+    /// Allow entries to be served up to the window past expiry, see `get_stale`
+    pub fn with_serve_stale(mut self, window: Duration) -> Self {
+        self.stale_window = Some(window.as_secs() as i64);
+        self
+    }
+
+    /// Internet class records for the name and type
     pub async fn get(
         &self,
         record_type: ResourceRecordType,
         domain: &str,
+    ) -> Option<Vec<ResourceRecord>> {
+        self.get_in_class(record_type, ResourceRecordClass::InternetAddress, domain)
+            .await
+    }
+
+    pub async fn get_in_class(
+        &self,
+        record_type: ResourceRecordType,
+        class: ResourceRecordClass,
+        domain: &str,
     ) -> Option<Vec<ResourceRecord>> {
         // Get a read lock
         let map = self.map.read().await;
 
-        // Find the value in the cache return none if it doesn't exist
-        let results = map.get(&(domain.to_string(), record_type));
+        HashCache::lookup(&map, &(domain.to_string(), record_type, class))
+    }
+
+    /// Like `get`, but returns None straight away rather than waiting if the cache is being written
+    pub fn try_get(
+        &self,
+        record_type: ResourceRecordType,
+        domain: &str,
+    ) -> Option<Vec<ResourceRecord>> {
+        let map = self.map.try_read().ok()?;
+
+        HashCache::lookup(&map, &internet_key(record_type, domain))
+    }
 
-        match results {
-            Some(results) => {
-                // Filter out all the expired values
+    pub fn serves_stale(&self) -> bool {
+        self.stale_window.is_some()
+    }
 
-                let return_results: Vec<ResourceRecord> = results
-                    .iter()
-                    .filter_map(|value| {
-                        if value.is_expired() {
-                            return None;
-                        }
+    /// Internet class records for the name and type expired within the serve-stale window, for
+    /// answering when they can't be resolved again (RFC 8767)
+    ///
+    /// Expired entries are kept until replaced, stale answers are given a short TTL so clients
+    /// ask again soon.
+    pub async fn get_stale(
+        &self,
+        record_type: ResourceRecordType,
+        domain: &str,
+    ) -> Option<Vec<ResourceRecord>> {
+        let stale_window = self.stale_window?;
+        let now = Utc::now().timestamp();
 
-                        Some(value.to_resource_record(domain))
-                    })
-                    .collect();
+        let map = self.map.read().await;
+        let values = map.get(&internet_key(record_type, domain))?;
 
-                // If empty, just return None
-                if return_results.is_empty() {
+        let records: Vec<ResourceRecord> = values
+            .iter()
+            .filter(|value| now <= value.expiration + stale_window)
+            .map(|value| ResourceRecord {
+                time_to_live: STALE_ANSWER_TTL,
+                ..value.to_resource_record(domain, &ResourceRecordClass::InternetAddress)
+            })
+            .collect();
+
+        if records.is_empty() {
+            return None;
+        }
+
+        Some(records)
+    }
+
+    /// Split questions into the cached answers for those the cache can satisfy, and the
+    /// questions still needing resolution
+    pub async fn get_intersection(
+        &self,
+        questions: &[Question],
+    ) -> (Vec<ResourceRecord>, Vec<Question>) {
+        let map = self.map.read().await;
+
+        let mut answers = vec![];
+        let mut remaining = vec![];
+
+        for question in questions {
+            let cache_key = internet_key(question.question_type.clone(), &question.domain);
+
+            match HashCache::lookup(&map, &cache_key) {
+                Some(records) => answers.extend(records),
+                None => remaining.push(question.clone()),
+            }
+        }
+
+        (answers, remaining)
+    }
+
+    fn lookup(
+        map: &HashMap<CacheKey, Vec<CacheValue>>,
+        cache_key: &CacheKey,
+    ) -> Option<Vec<ResourceRecord>> {
+        // Find the value in the cache return none if it doesn't exist
+        let results = map.get(cache_key)?;
+        let (domain, _, class) = cache_key;
+
+        // Filter out all the expired values
+        let return_results: Vec<ResourceRecord> = results
+            .iter()
+            .filter_map(|value| {
+                if value.is_expired() {
                     return None;
                 }
 
-                return Some(return_results);
-            }
+                value.hits.fetch_add(1, Ordering::Relaxed);
+
+                Some(value.to_resource_record(domain, class))
+            })
+            .collect();
+
+        // If empty, just return None
+        if return_results.is_empty() {
+            return None;
+        }
+
+        Some(return_results)
+    }
+
+    /// Claim a background refresh of an entry served more than `min_hits` times and close to
+    /// expiry, only the first claim succeeds until the claim is released
+    pub async fn claim_prefetch(
+        &self,
+        record_type: ResourceRecordType,
+        domain: &str,
+        min_hits: u32,
+    ) -> bool {
+        let cache_key = internet_key(record_type, domain);
 
-            // Just return an empty vector
-            None => return None,
+        let due = match self.map.read().await.get(&cache_key) {
+            Some(values) => values.iter().any(|value| {
+                !value.is_expired()
+                    && value.is_near_expiry()
+                    && value.hits.load(Ordering::Relaxed) > min_hits
+            }),
+            None => false,
+        };
+
+        if !due {
+            return false;
+        }
+
+        match self.prefetching.lock() {
+            Ok(mut prefetching) => prefetching.insert(cache_key),
+            Err(_) => false,
         }
     }
 
-    pub async fn put_resource_records(&self, resource_records: &Vec<ResourceRecord>) {
+    /// Release the claim on refreshing an entry once the refresh is done, whether it succeeded
+    pub fn release_prefetch(&self, record_type: ResourceRecordType, domain: &str) {
+        if let Ok(mut prefetching) = self.prefetching.lock() {
+            prefetching.remove(&internet_key(record_type, domain));
+        }
+    }
+
+    /// Cache records of any names and types, each under its own name and type
+    pub async fn put_resource_records(&self, resource_records: &[ResourceRecord]) {
         // Get write lock
         let mut map = self.map.write().await;
 
         // Add all records to the cache
         resource_records.iter().for_each(|record| {
             // Make key
-            let cache_key: CacheKey = (record.domain.clone(), record.record_type.clone());
+            let cache_key: CacheKey = (
+                record.domain.clone(),
+                record.record_type.clone(),
+                record.class.clone(),
+            );
             let cache_value = CacheValue::from_resource_record(record);
 
-            // Check if already in cache
-            if !map.contains_key(&cache_key) {
-                // Insert the value, we are done
-                map.insert(cache_key, vec![cache_value]);
-                return;
-            }
+            // Get the existing list of records, or insert an empty one
+            let values = map.entry(cache_key).or_default();
 
-            // Add to the list of existing records if not already contained
-            match map.get_mut(&cache_key) {
-                Some(value) => {
-                    // Already cached, ignore it
-                    if value.contains(&cache_value) {
-                        return;
-                    }
+            // A zone has a single SOA. One with an earlier serial than the cached SOA comes from
+            // a secondary that hasn't caught up, so is stale and doesn't replace it
+            if let ResourceRecordData::SOA(soa) = &record.data {
+                let cached = values.iter().find_map(|value| match &value.data {
+                    ResourceRecordData::SOA(cached) if !value.is_expired() => Some(cached),
+                    _ => None,
+                });
 
-                    // Otherwise add to the list of values
-                    value.push(cache_value)
+                if !cached.is_some_and(|cached| soa.is_older_than(cached)) {
+                    *values = vec![cache_value];
                 }
-                // Do nothing
-                None => return,
+
+                return;
             }
+
+            // Already cached, refresh its expiry
+            if let Some(value) = values
+                .iter_mut()
+                .find(|value| value.data == cache_value.data)
+            {
+                *value = cache_value;
+                return;
+            }
+
+            // Otherwise add to the list of values
+            values.push(cache_value)
         })
     }
+
+    /// NXDOMAIN for the name or NODATA for the type, with the SOA's TTL counted down to the time
+    /// left (RFC 2308)
+    pub async fn get_negative(
+        &self,
+        record_type: ResourceRecordType,
+        domain: &str,
+    ) -> Option<NegativeAnswer> {
+        let negatives = self.negatives.read().await;
+        let now = Utc::now().timestamp();
+
+        [None, Some(record_type)]
+            .into_iter()
+            .filter_map(|record_type| negatives.get(&(domain.to_string(), record_type)))
+            .find(|value| now <= value.expiration)
+            .map(|value| {
+                let mut answer = value.answer.clone();
+                answer.soa.time_to_live = (value.expiration - now) as u32;
+                answer
+            })
+    }
+
+    /// Cache a negative answer for the question, NXDOMAIN is cached for every type of the name
+    pub async fn put_negative(
+        &self,
+        record_type: ResourceRecordType,
+        domain: &str,
+        answer: NegativeAnswer,
+    ) {
+        // Nothing to cache for a zero TTL
+        if answer.soa.time_to_live == 0 {
+            return;
+        }
+
+        let now = Utc::now().timestamp();
+        let expiration = now + answer.soa.time_to_live as i64;
+
+        let record_type = match answer.response_code {
+            ResponseCode::NameError => None,
+            _ => Some(record_type),
+        };
+
+        let mut negatives = self.negatives.write().await;
+
+        // Expired answers are never served, drop them rather than letting them pile up
+        negatives.retain(|_, value| now <= value.expiration);
+
+        negatives.insert(
+            (domain.to_string(), record_type),
+            NegativeValue { answer, expiration },
+        );
+    }
+
+    /// Remove every entry, positive and negative
+    pub async fn clear(&self) {
+        self.map.write().await.clear();
+        self.negatives.write().await.clear();
+
+        if let Ok(mut prefetching) = self.prefetching.lock() {
+            prefetching.clear();
+        }
+    }
+
+    /// Remove the entries of every type and class for the name
+    pub async fn flush_name(&self, domain: &str) {
+        let matches = |cache_key: &CacheKey| cache_key.0.eq_ignore_ascii_case(domain);
+
+        self.map
+            .write()
+            .await
+            .retain(|cache_key, _| !matches(cache_key));
+        self.negatives
+            .write()
+            .await
+            .retain(|(name, _), _| !name.eq_ignore_ascii_case(domain));
+
+        if let Ok(mut prefetching) = self.prefetching.lock() {
+            prefetching.retain(|cache_key| !matches(cache_key));
+        }
+    }
+
+    /// Cache a record as if it had been cached long enough ago to have `remaining` seconds left
+    #[cfg(test)]
+    pub async fn put_expiring(&self, record: &ResourceRecord, remaining: i64) {
+        let mut cache_value = CacheValue::from_resource_record(record);
+        cache_value.expiration = Utc::now().timestamp() + remaining;
+
+        self.map
+            .write()
+            .await
+            .entry((
+                record.domain.clone(),
+                record.record_type.clone(),
+                record.class.clone(),
+            ))
+            .or_default()
+            .push(cache_value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::messages::packets::{PacketType, SOARecord};
+
+    fn record() -> ResourceRecord {
+        ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_get() {
+        let cache = HashCache::new();
+        cache.put_resource_records(&[record()]).await;
+
+        assert_eq!(
+            cache.try_get(ResourceRecordType::ARecord, "example.com."),
+            Some(vec![record()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stale_soa_not_cached() {
+        let cache = HashCache::new();
+
+        let soa = |serial| ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::SOARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::SOA(SOARecord {
+                master_name: "ns1.example.com.".to_string(),
+                mail_name: "hostmaster.example.com.".to_string(),
+                serial,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        };
+        let cached = || cache.try_get(ResourceRecordType::SOARecord, "example.com.");
+
+        cache.put_resource_records(&[soa(u32::MAX - 1)]).await;
+
+        // A secondary still serving an earlier serial doesn't roll the zone back
+        cache.put_resource_records(&[soa(u32::MAX - 2)]).await;
+        assert_eq!(cached(), Some(vec![soa(u32::MAX - 1)]));
+
+        // A later serial replaces it, including once the serial wraps around
+        cache.put_resource_records(&[soa(3)]).await;
+        assert_eq!(cached(), Some(vec![soa(3)]));
+    }
+
+    #[tokio::test]
+    async fn test_try_get_skips_while_writing() {
+        let cache = HashCache::new();
+        cache.put_resource_records(&[record()]).await;
+
+        let _writer = cache.map.write().await;
+
+        assert_eq!(
+            cache.try_get(ResourceRecordType::ARecord, "example.com."),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mixed_records_cached_by_name_and_type() {
+        let cache = HashCache::new();
+
+        let record = |domain: &str, data: ResourceRecordData| ResourceRecord {
+            domain: domain.to_string(),
+            record_type: data.get_type(),
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data,
+        };
+
+        let records = vec![
+            record(
+                "www.example.com.",
+                ResourceRecordData::CName("example.com.".to_string()),
+            ),
+            record(
+                "example.com.",
+                ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)),
+            ),
+            record(
+                "example.com.",
+                ResourceRecordData::AAAA(Ipv6Addr::LOCALHOST),
+            ),
+            record(
+                "example.com.",
+                ResourceRecordData::MX(10, "mail.example.com.".to_string()),
+            ),
+            record(
+                "example.com.",
+                ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 35)),
+            ),
+        ];
+
+        cache.put_resource_records(&records).await;
+
+        for (record_type, domain, expected) in [
+            (
+                ResourceRecordType::CNameRecord,
+                "www.example.com.",
+                vec![records[0].clone()],
+            ),
+            (
+                ResourceRecordType::ARecord,
+                "example.com.",
+                vec![records[1].clone(), records[4].clone()],
+            ),
+            (
+                ResourceRecordType::AAAARecord,
+                "example.com.",
+                vec![records[2].clone()],
+            ),
+            (
+                ResourceRecordType::MXRecord,
+                "example.com.",
+                vec![records[3].clone()],
+            ),
+        ] {
+            assert_eq!(cache.get(record_type, domain).await, Some(expected));
+        }
+
+        assert_eq!(
+            cache
+                .get(ResourceRecordType::ARecord, "www.example.com.")
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_records_keyed_by_own_domain() {
+        let cache = HashCache::new();
+
+        let mut other = record();
+        other.domain = "example.org.".to_string();
+        other.data = ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 2));
+
+        cache.put_resource_records(&[record(), other.clone()]).await;
+
+        let map = cache.map.read().await;
+        let mut keys: Vec<&CacheKey> = map.keys().collect();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(
+            keys,
+            vec![
+                &internet_key(ResourceRecordType::ARecord, "example.com."),
+                &internet_key(ResourceRecordType::ARecord, "example.org."),
+            ]
+        );
+        drop(map);
+
+        assert_eq!(
+            cache.get(ResourceRecordType::ARecord, "example.org.").await,
+            Some(vec![other])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_intersection() {
+        let cache = HashCache::new();
+        cache.put_resource_records(&[record()]).await;
+
+        let cached = Question::new("example.com.", ResourceRecordType::ARecord);
+        let uncached = Question::new("example.com.", ResourceRecordType::AAAARecord);
+
+        let (answers, remaining) = cache.get_intersection(&[cached, uncached.clone()]).await;
+
+        assert_eq!(answers, vec![record()]);
+        assert_eq!(remaining, vec![uncached]);
+    }
+
+    #[tokio::test]
+    async fn test_classes_cached_separately() {
+        let cache = HashCache::new();
+
+        let mut chaos = record();
+        chaos.class = ResourceRecordClass::Chaos;
+        chaos.data = ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 2));
+
+        cache.put_resource_records(&[record(), chaos.clone()]).await;
+
+        assert_eq!(
+            cache.get(ResourceRecordType::ARecord, "example.com.").await,
+            Some(vec![record()])
+        );
+        assert_eq!(
+            cache
+                .get_in_class(
+                    ResourceRecordType::ARecord,
+                    ResourceRecordClass::Chaos,
+                    "example.com."
+                )
+                .await,
+            Some(vec![chaos])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stale_entries_within_window() {
+        let cache = HashCache::new().with_serve_stale(Duration::from_secs(60));
+
+        let mut expired = record();
+        expired.domain = "expired.example.com.".to_string();
+
+        cache.put_expiring(&record(), -10).await;
+        cache.put_expiring(&expired, -120).await;
+
+        assert_eq!(
+            cache.get(ResourceRecordType::ARecord, "example.com.").await,
+            None
+        );
+
+        let stale = cache
+            .get_stale(ResourceRecordType::ARecord, "example.com.")
+            .await
+            .unwrap();
+        assert_eq!(stale[0].time_to_live, STALE_ANSWER_TTL);
+
+        // Past the window
+        assert_eq!(
+            cache
+                .get_stale(ResourceRecordType::ARecord, "expired.example.com.")
+                .await,
+            None
+        );
+
+        // Disabled by default
+        let cache = HashCache::new();
+        cache.put_expiring(&record(), -10).await;
+        assert_eq!(
+            cache
+                .get_stale(ResourceRecordType::ARecord, "example.com.")
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clear() {
+        let cache = HashCache::new();
+        cache.put_resource_records(&[record()]).await;
+        cache
+            .put_negative(
+                ResourceRecordType::AAAARecord,
+                "example.com.",
+                NegativeAnswer::from_response(&nodata_response(3600, 60)).unwrap(),
+            )
+            .await;
+
+        cache.clear().await;
+
+        assert_eq!(
+            cache.get(ResourceRecordType::ARecord, "example.com.").await,
+            None
+        );
+        assert_eq!(
+            cache
+                .get_negative(ResourceRecordType::AAAARecord, "example.com.")
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_name_leaves_other_names() {
+        let cache = HashCache::new();
+
+        let mut mail = record();
+        mail.record_type = ResourceRecordType::MXRecord;
+        mail.data = ResourceRecordData::MX(10, "mail.example.com.".to_string());
+
+        let mut other = record();
+        other.domain = "example.org.".to_string();
+
+        cache
+            .put_resource_records(&[record(), mail, other.clone()])
+            .await;
+
+        cache.flush_name("EXAMPLE.com.").await;
+
+        assert_eq!(
+            cache.get(ResourceRecordType::ARecord, "example.com.").await,
+            None
+        );
+        assert_eq!(
+            cache
+                .get(ResourceRecordType::MXRecord, "example.com.")
+                .await,
+            None
+        );
+        assert_eq!(
+            cache.get(ResourceRecordType::ARecord, "example.org.").await,
+            Some(vec![other])
+        );
+    }
+
+    fn nodata_response(soa_ttl: u32, minimum: u32) -> Message {
+        Message {
+            id: 1,
+            packet_type: PacketType::Response,
+            op_code: 0,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: true,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![ResourceRecord {
+                domain: "example.com.".to_string(),
+                record_type: ResourceRecordType::SOARecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: soa_ttl,
+                data: ResourceRecordData::SOA(SOARecord {
+                    master_name: "ns1.example.com.".to_string(),
+                    mail_name: "hostmaster.example.com.".to_string(),
+                    serial: 1,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum,
+                }),
+            }],
+            additional_records: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_negative_ttl_from_soa_minimum() {
+        let cache = HashCache::new();
+        let negative = NegativeAnswer::from_response(&nodata_response(3600, 60)).unwrap();
+
+        assert_eq!(negative.response_code, ResponseCode::None);
+        assert_eq!(negative.soa.time_to_live, 60);
+
+        cache
+            .put_negative(ResourceRecordType::AAAARecord, "example.com.", negative)
+            .await;
+
+        let cached = cache
+            .get_negative(ResourceRecordType::AAAARecord, "example.com.")
+            .await
+            .unwrap();
+        assert_eq!(cached.soa.time_to_live, 60);
+
+        // Cached for 60 seconds
+        let expiration = cache.negatives.read().await[&(
+            "example.com.".to_string(),
+            Some(ResourceRecordType::AAAARecord),
+        )]
+            .expiration;
+        assert!((59..=60).contains(&(expiration - Utc::now().timestamp())));
+
+        // Other types of the name are unaffected
+        assert_eq!(
+            cache
+                .get_negative(ResourceRecordType::ARecord, "example.com.")
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_negative_ttl_counts_down() {
+        let cache = HashCache::new();
+
+        cache
+            .put_negative(
+                ResourceRecordType::AAAARecord,
+                "example.com.",
+                NegativeAnswer::from_response(&nodata_response(3600, 60)).unwrap(),
+            )
+            .await;
+
+        // As if cached 50 seconds ago
+        for value in cache.negatives.write().await.values_mut() {
+            value.expiration -= 50;
+        }
+
+        let cached = cache
+            .get_negative(ResourceRecordType::AAAARecord, "example.com.")
+            .await
+            .unwrap();
+        assert!((9..=10).contains(&cached.soa.time_to_live));
+
+        // Expired answers are dropped once another is cached
+        for value in cache.negatives.write().await.values_mut() {
+            value.expiration -= 20;
+        }
+
+        cache
+            .put_negative(
+                ResourceRecordType::AAAARecord,
+                "example.org.",
+                NegativeAnswer::from_response(&nodata_response(3600, 60)).unwrap(),
+            )
+            .await;
+
+        assert_eq!(cache.negatives.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_name_error_covers_every_type() {
+        let cache = HashCache::new();
+
+        let mut response = nodata_response(3600, 60);
+        response.response_code = ResponseCode::NameError;
+
+        cache
+            .put_negative(
+                ResourceRecordType::AAAARecord,
+                "example.com.",
+                NegativeAnswer::from_response(&response).unwrap(),
+            )
+            .await;
+
+        for record_type in [ResourceRecordType::ARecord, ResourceRecordType::MXRecord] {
+            assert_eq!(
+                cache
+                    .get_negative(record_type, "example.com.")
+                    .await
+                    .unwrap()
+                    .response_code,
+                ResponseCode::NameError
+            );
+        }
+    }
+
+    #[test]
+    fn test_negative_ttl_bounded_by_soa_ttl() {
+        let negative = NegativeAnswer::from_response(&nodata_response(30, 60)).unwrap();
+        assert_eq!(negative.soa.time_to_live, 30);
+
+        // Responses with answers aren't negative
+        let mut response = nodata_response(3600, 60);
+        response.answers.push(record());
+        assert_eq!(NegativeAnswer::from_response(&response), None);
+    }
 }
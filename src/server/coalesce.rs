@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast;
+
+use crate::messages::packets::{Message, ResourceRecordType};
+
+type PendingKey = (String, ResourceRecordType);
+
+/// Outcome of a shared resolution, errors are shared as their description
+pub type PendingResult = Result<Message, String>;
+
+/// Resolutions in progress, so concurrent identical cache misses share a single resolution
+#[derive(Debug, Default)]
+pub struct InFlight {
+    pending: Mutex<HashMap<PendingKey, broadcast::Sender<PendingResult>>>,
+}
+
+/// How a caller takes part in resolving a question
+pub enum Joined {
+    /// No resolution of the question was in progress, the caller resolves it for everyone
+    Leader(Leader),
+
+    /// Another caller is resolving the question, its result arrives on the receiver
+    Follower(broadcast::Receiver<PendingResult>),
+}
+
+/// Held by the caller resolving a question, the entry is removed when dropped even if the
+/// resolution is abandoned, followers then see the channel close
+pub struct Leader {
+    in_flight: Arc<InFlight>,
+    key: PendingKey,
+    sender: broadcast::Sender<PendingResult>,
+    finished: bool,
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight::default()
+    }
+
+    /// Lead the resolution of the name and type, or follow the one in progress
+    pub fn join(self: &Arc<Self>, domain: &str, record_type: ResourceRecordType) -> Joined {
+        let key = (domain.to_lowercase(), record_type);
+
+        let mut pending = match self.pending.lock() {
+            Ok(pending) => pending,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(sender) = pending.get(&key) {
+            return Joined::Follower(sender.subscribe());
+        }
+
+        // Only one result is ever sent
+        let (sender, _) = broadcast::channel(1);
+        pending.insert(key.clone(), sender.clone());
+
+        Joined::Leader(Leader {
+            in_flight: self.clone(),
+            key,
+            sender,
+            finished: false,
+        })
+    }
+
+    fn remove(&self, key: &PendingKey) {
+        let mut pending = match self.pending.lock() {
+            Ok(pending) => pending,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        pending.remove(key);
+    }
+}
+
+impl Leader {
+    /// Share the result with every follower
+    pub fn finish(mut self, result: PendingResult) {
+        // Later callers start a fresh resolution rather than following this one
+        self.in_flight.remove(&self.key);
+        self.finished = true;
+
+        // No followers is not an error
+        let _ = self.sender.send(result);
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        // Once finished the entry may belong to a newer resolution
+        if !self.finished {
+            self.in_flight.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,618 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    messages::{
+        idna,
+        packets::{ResourceRecord, ResourceRecordClass, ResourceRecordData, ResourceRecordType},
+    },
+    zone::ZoneFile,
+};
+
+use super::{errors::RootHintsError, query_log::QueryLogger};
+
+/// Default overall deadline for handling a single request
+const DEFAULT_HANDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time resolution is given before a stale answer is served instead, as suggested by
+/// RFC 8767
+const DEFAULT_STALE_ANSWER_TIMEOUT: Duration = Duration::from_millis(1800);
+
+/// Default time a DNS-over-TLS connection may sit idle before it's closed (RFC 7766)
+const DEFAULT_TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Port DNS-over-TLS is served on (RFC 7858)
+const DEFAULT_TLS_PORT: u16 = 853;
+
+/// Default largest UDP response, small enough to avoid IP fragmentation as recommended by DNS
+/// Flag Day 2020
+const DEFAULT_MAX_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Default cap on the number of referrals followed for a single recursion
+const DEFAULT_MAX_REFERRALS: usize = 32;
+
+/// Default TTL of answers made up by the server rather than resolved, short so changes apply quickly
+pub const DEFAULT_SYNTHESIZED_TTL: u32 = 60;
+
+/// A static record with its own TTL, if it has one
+type StaticRecord = (ResourceRecord, Option<u32>);
+
+/// Records pinned to fixed values, answered authoritatively before the cache is consulted
+#[derive(Debug, Clone, Default)]
+pub struct StaticRecords {
+    map: HashMap<(String, ResourceRecordType), Vec<StaticRecord>>,
+}
+
+/// Names are matched case-insensitively, with or without the trailing dot, internationalized
+/// names by their `xn--` form
+fn canonical_name(name: &str) -> String {
+    let mut name = idna::to_ascii(name.trim_end_matches('.')).to_ascii_lowercase();
+    name.push('.');
+    name
+}
+
+impl StaticRecords {
+    pub fn new() -> StaticRecords {
+        StaticRecords {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Add a record, keyed by its own domain and type, answered with its own TTL
+    pub fn insert(&mut self, mut record: ResourceRecord) {
+        let ttl = Some(record.time_to_live);
+        record.domain = idna::to_ascii(&record.domain);
+
+        self.map
+            .entry((canonical_name(&record.domain), record.record_type.clone()))
+            .or_default()
+            .push((record, ttl));
+    }
+
+    /// Add an internet class record without a TTL of its own, answered with the default TTL
+    pub fn insert_data(&mut self, domain: &str, data: ResourceRecordData) {
+        let record = ResourceRecord {
+            domain: idna::to_ascii(domain),
+            record_type: data.get_type(),
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 0,
+            data,
+        };
+
+        self.map
+            .entry((canonical_name(&record.domain), record.record_type.clone()))
+            .or_default()
+            .push((record, None));
+    }
+
+    /// Records for the name and type, any without their own TTL given the default
+    pub fn get(
+        &self,
+        record_type: &ResourceRecordType,
+        domain: &str,
+        default_ttl: u32,
+    ) -> Option<Vec<ResourceRecord>> {
+        let records = self
+            .map
+            .get(&(canonical_name(domain), record_type.clone()))?;
+
+        Some(
+            records
+                .iter()
+                .map(|(record, ttl)| ResourceRecord {
+                    time_to_live: ttl.unwrap_or(default_ttl),
+                    ..record.clone()
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Built-in root name servers and their IPv4 addresses
+const DEFAULT_ROOT_HINTS: [(&str, [u8; 4]); 13] = [
+    ("a.root-servers.net.", [198, 41, 0, 4]),
+    ("b.root-servers.net.", [170, 247, 170, 2]),
+    ("c.root-servers.net.", [192, 33, 4, 12]),
+    ("d.root-servers.net.", [199, 7, 91, 13]),
+    ("e.root-servers.net.", [192, 203, 230, 10]),
+    ("f.root-servers.net.", [192, 5, 5, 241]),
+    ("g.root-servers.net.", [192, 112, 36, 4]),
+    ("h.root-servers.net.", [198, 97, 190, 53]),
+    ("i.root-servers.net.", [192, 36, 148, 17]),
+    ("j.root-servers.net.", [192, 58, 128, 30]),
+    ("k.root-servers.net.", [193, 0, 14, 129]),
+    ("l.root-servers.net.", [199, 7, 83, 42]),
+    ("m.root-servers.net.", [202, 12, 27, 33]),
+];
+
+/// A root name server and its address
+#[derive(Debug, Clone)]
+pub struct RootHint {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// Root name servers, answered for queries of the root itself
+#[derive(Debug, Clone)]
+pub struct RootHints {
+    hints: Vec<RootHint>,
+}
+
+impl Default for RootHints {
+    fn default() -> Self {
+        RootHints {
+            hints: DEFAULT_ROOT_HINTS
+                .iter()
+                .map(|(name, addr)| RootHint {
+                    name: name.to_string(),
+                    addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::from(*addr)), 53),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl RootHints {
+    pub fn new() -> RootHints {
+        RootHints { hints: vec![] }
+    }
+
+    pub fn insert(&mut self, name: &str, addr: SocketAddr) {
+        let mut name = name.trim_end_matches('.').to_lowercase();
+        name.push('.');
+
+        self.hints.push(RootHint { name, addr });
+    }
+
+    /// Load root hints from a file, either in `named.root` format or a simple list
+    ///
+    /// ```text
+    /// ; named.root, NS records are implied by the addresses
+    /// .                        3600000      NS    A.ROOT-SERVERS.NET.
+    /// A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+    ///
+    /// ; simple list, an optional port may be given for querying the root itself, name servers
+    /// ; it refers to are queried on port 53
+    /// a.root-servers.net. 198.41.0.4
+    /// local-root. 127.0.0.1:5353
+    /// ```
+    pub fn load(path: &Path) -> Result<RootHints, RootHintsError> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| RootHintsError::ReadError(err.to_string()))?;
+
+        RootHints::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<RootHints, RootHintsError> {
+        let mut root_hints = RootHints::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            // Strip comments
+            let line = line.split(';').next().unwrap_or_default();
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+
+            let invalid = || RootHintsError::InvalidLineError(index + 1, line.trim().to_string());
+
+            let (name, addr) = match tokens.as_slice() {
+                [] => continue,
+                [name, addr] => (*name, addr.to_string()),
+                [name, .., record_type, addr] => match record_type.to_uppercase().as_str() {
+                    "A" | "AAAA" => (*name, addr.to_string()),
+                    "NS" => continue,
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            };
+
+            let addr = match addr.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => SocketAddr::new(addr.parse().map_err(|_| invalid())?, 53),
+            };
+
+            root_hints.insert(name, addr);
+        }
+
+        if root_hints.hints.is_empty() {
+            return Err(RootHintsError::NoHintsError);
+        }
+
+        Ok(root_hints)
+    }
+
+    pub fn hints(&self) -> &[RootHint] {
+        &self.hints
+    }
+}
+
+/// Upstream server requests are forwarded to instead of recursing from the root
+#[derive(Debug, Clone)]
+pub struct Forwarder {
+    pub addr: SocketAddr,
+
+    /// Name to verify the upstream certificate against, forwarding over TLS when set
+    pub tls_server_name: Option<String>,
+}
+
+/// Source prefix lengths client addresses are truncated to when forwarded upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnetConfig {
+    pub ipv4_prefix_length: u8,
+    pub ipv6_prefix_length: u8,
+}
+
+/// Options applied to the UDP listening socket before it's bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SocketOptions {
+    /// `SO_RCVBUF`, larger buffers drop fewer packets under load, the OS default if not set
+    pub receive_buffer_size: Option<usize>,
+
+    /// `SO_SNDBUF`, the OS default if not set
+    pub send_buffer_size: Option<usize>,
+
+    /// `SO_REUSEADDR`
+    pub reuse_address: bool,
+
+    /// `SO_REUSEPORT`, so several listeners can share the port, ignored where unsupported
+    pub reuse_port: bool,
+}
+
+/// How requests are spread over the configured forwarders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardMode {
+    /// Forward to the first forwarder only
+    #[default]
+    First,
+
+    /// Forward to every forwarder at once, answering with the first valid response
+    Race,
+}
+
+/// Order of the records within each answer set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnswerOrder {
+    /// The order they were received or configured in
+    #[default]
+    Fixed,
+
+    /// Rotated by one on each response, spreading clients over the records
+    RoundRobin,
+
+    /// Shuffled on each response
+    Random,
+}
+
+/// Configuration for the DNS-over-TLS listener
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Port the TLS listener binds to, on the address the server listens on
+    pub port: u16,
+
+    /// PEM encoded certificate chain
+    pub certificate_path: PathBuf,
+
+    /// PEM encoded private key
+    pub key_path: PathBuf,
+}
+
+/// Configuration for the server and its handlers
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Overall deadline for handling a single request, including any recursion
+    pub handle_timeout: Duration,
+
+    /// Pin recursion to start from this root name server rather than one from the hints
+    pub root_server: Option<SocketAddr>,
+
+    /// Root name servers recursion starts from, also answered for NS queries of the root
+    pub root_hints: RootHints,
+
+    /// Maximum number of referrals followed before recursion is abandoned
+    pub max_referrals: usize,
+
+    /// Records answered authoritatively without consulting the cache or recursing
+    pub static_records: StaticRecords,
+
+    /// TTL of static records without their own, and of other answers made up by the server
+    pub synthesized_ttl: u32,
+
+    /// TXT answer for `version.bind` queries in the CH class
+    pub version: String,
+
+    /// TXT answer for `hostname.bind` queries in the CH class
+    pub hostname: String,
+
+    /// DNS-over-TLS listener, disabled if not set
+    pub tls: Option<TlsConfig>,
+
+    /// Close DNS-over-TLS connections that take longer than this to complete the handshake or
+    /// send their next query
+    pub tls_idle_timeout: Duration,
+
+    /// Pad responses over encrypted transports to a multiple of this many bytes, disabled if not set
+    pub response_padding: Option<usize>,
+
+    /// Resolve questions that aren't answered locally, refusing them if disabled
+    pub recursion: bool,
+
+    /// Upstreams to forward to, recursing from the root server if empty
+    pub forwarders: Vec<Forwarder>,
+
+    /// How requests are spread over the forwarders
+    pub forward_mode: ForwardMode,
+
+    /// Order of the records within each answer set
+    pub answer_order: AnswerOrder,
+
+    /// Leave the authority and additional sections out of responses that have answers
+    pub minimal_responses: bool,
+
+    /// Hold back responses answered faster than this, so cache hits can't be told apart from
+    /// misses by timing. Disabled if not set
+    pub min_response_time: Option<Duration>,
+
+    /// Send the client's subnet with forwarded queries, disabled if not set
+    pub client_subnet: Option<ClientSubnetConfig>,
+
+    /// Refresh cache entries near expiry once served more than this many times, disabled if not set
+    pub prefetch_min_hits: Option<u32>,
+
+    /// How long past expiry cached answers may be served when recursion fails, disabled if not set
+    pub serve_stale: Option<Duration>,
+
+    /// How long resolution may take before a stale answer is served instead, the resolution
+    /// carries on to refresh the cache. Should be shorter than the handle timeout
+    pub stale_answer_timeout: Duration,
+
+    /// Log the time taken to answer each query
+    pub log_query_timing: bool,
+
+    /// Write a machine readable line for each answered query, disabled if not set
+    pub query_log: Option<Arc<QueryLogger>>,
+
+    /// Largest UDP response sent, whatever larger size the client advertises with EDNS. Also the
+    /// size advertised in responses
+    pub max_udp_payload_size: u16,
+
+    /// Options for the UDP listening socket
+    pub socket_options: SocketOptions,
+
+    /// UDP sockets bound to the listen address, each served by its own task. More than one share
+    /// the port with `SO_REUSEPORT`, so only platforms supporting it can use more than one.
+    pub listeners: usize,
+
+    /// Secret server cookies are derived from, random unless shared between servers
+    pub cookie_secret: [u8; 16],
+
+    /// Answer UDP queries carrying a client cookie but no valid server cookie with BADCOOKIE
+    pub require_cookies: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            handle_timeout: DEFAULT_HANDLE_TIMEOUT,
+            root_server: None,
+            root_hints: RootHints::default(),
+            max_referrals: DEFAULT_MAX_REFERRALS,
+            static_records: StaticRecords::new(),
+            synthesized_ttl: DEFAULT_SYNTHESIZED_TTL,
+            version: format!("dn-mess {}", env!("CARGO_PKG_VERSION")),
+            hostname: String::from("dn-mess"),
+            tls: None,
+            tls_idle_timeout: DEFAULT_TLS_IDLE_TIMEOUT,
+            response_padding: None,
+            recursion: true,
+            forwarders: vec![],
+            forward_mode: ForwardMode::default(),
+            answer_order: AnswerOrder::default(),
+            minimal_responses: false,
+            min_response_time: None,
+            client_subnet: None,
+            prefetch_min_hits: None,
+            serve_stale: None,
+            stale_answer_timeout: DEFAULT_STALE_ANSWER_TIMEOUT,
+            log_query_timing: true,
+            query_log: None,
+            max_udp_payload_size: DEFAULT_MAX_UDP_PAYLOAD_SIZE,
+            socket_options: SocketOptions::default(),
+            listeners: 1,
+            cookie_secret: rand::random(),
+            require_cookies: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn with_handle_timeout(mut self, handle_timeout: Duration) -> Self {
+        self.handle_timeout = handle_timeout;
+        self
+    }
+
+    pub fn with_root_server(mut self, root_server: SocketAddr) -> Self {
+        self.root_server = Some(root_server);
+        self
+    }
+
+    /// Replace the root hints with the given set
+    pub fn with_root_hints(mut self, root_hints: RootHints) -> Self {
+        self.root_hints = root_hints;
+        self
+    }
+
+    pub fn with_max_referrals(mut self, max_referrals: usize) -> Self {
+        self.max_referrals = max_referrals;
+        self
+    }
+
+    pub fn with_static_record(mut self, record: ResourceRecord) -> Self {
+        self.static_records.insert(record);
+        self
+    }
+
+    /// Answer every record of the zone, such as the PTR records of a reverse zone
+    pub fn with_zone(mut self, zone_file: ZoneFile) -> Self {
+        for record in zone_file.into_records() {
+            self.static_records.insert(record);
+        }
+        self
+    }
+
+    /// Answer the name with the data, using the synthesized TTL
+    pub fn with_static_data(mut self, domain: &str, data: ResourceRecordData) -> Self {
+        self.static_records.insert_data(domain, data);
+        self
+    }
+
+    pub fn with_synthesized_ttl(mut self, synthesized_ttl: u32) -> Self {
+        self.synthesized_ttl = synthesized_ttl;
+        self
+    }
+
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        self.hostname = hostname.to_string();
+        self
+    }
+
+    /// Enable DNS-over-TLS on port 853 of the listening address, with the given certificate
+    /// chain and private key
+    pub fn with_tls(mut self, certificate_path: PathBuf, key_path: PathBuf) -> Self {
+        self.tls = Some(TlsConfig {
+            port: DEFAULT_TLS_PORT,
+            certificate_path,
+            key_path,
+        });
+        self
+    }
+
+    pub fn with_tls_idle_timeout(mut self, tls_idle_timeout: Duration) -> Self {
+        self.tls_idle_timeout = tls_idle_timeout;
+        self
+    }
+
+    /// Pad encrypted responses to a multiple of the block size, RFC 8467 recommends 468 bytes
+    pub fn with_response_padding(mut self, block_size: usize) -> Self {
+        self.response_padding = Some(block_size);
+        self
+    }
+
+    /// Only answer from local data, refusing anything that would need recursion or forwarding
+    pub fn with_recursion(mut self, recursion: bool) -> Self {
+        self.recursion = recursion;
+        self
+    }
+
+    pub fn with_forwarder(mut self, addr: SocketAddr) -> Self {
+        self.forwarders.push(Forwarder {
+            addr,
+            tls_server_name: None,
+        });
+        self
+    }
+
+    /// Forward over DNS-over-TLS, verifying the upstream certificate against the given name
+    pub fn with_tls_forwarder(mut self, addr: SocketAddr, server_name: &str) -> Self {
+        self.forwarders.push(Forwarder {
+            addr,
+            tls_server_name: Some(server_name.to_string()),
+        });
+        self
+    }
+
+    pub fn with_forward_mode(mut self, forward_mode: ForwardMode) -> Self {
+        self.forward_mode = forward_mode;
+        self
+    }
+
+    /// Rotate or shuffle multi-record answers, spreading load over the records
+    pub fn with_answer_order(mut self, answer_order: AnswerOrder) -> Self {
+        self.answer_order = answer_order;
+        self
+    }
+
+    /// Keep responses small by only sending the sections needed to answer
+    pub fn with_minimal_responses(mut self, minimal_responses: bool) -> Self {
+        self.minimal_responses = minimal_responses;
+        self
+    }
+
+    /// Answer no faster than the given time after the request arrives
+    pub fn with_min_response_time(mut self, min_response_time: Duration) -> Self {
+        self.min_response_time = Some(min_response_time);
+        self
+    }
+
+    /// Send the client's subnet upstream, truncated to the given prefix lengths
+    ///
+    /// Loopback, private and link local clients are never sent, nor is a zero length prefix.
+    pub fn with_client_subnet(mut self, ipv4_prefix_length: u8, ipv6_prefix_length: u8) -> Self {
+        self.client_subnet = Some(ClientSubnetConfig {
+            ipv4_prefix_length,
+            ipv6_prefix_length,
+        });
+        self
+    }
+
+    pub fn with_prefetch(mut self, min_hits: u32) -> Self {
+        self.prefetch_min_hits = Some(min_hits);
+        self
+    }
+
+    /// Serve answers up to the window past expiry when they can't be resolved again (RFC 8767)
+    pub fn with_serve_stale(mut self, window: Duration) -> Self {
+        self.serve_stale = Some(window);
+        self
+    }
+
+    pub fn with_stale_answer_timeout(mut self, stale_answer_timeout: Duration) -> Self {
+        self.stale_answer_timeout = stale_answer_timeout;
+        self
+    }
+
+    pub fn with_query_timing(mut self, log_query_timing: bool) -> Self {
+        self.log_query_timing = log_query_timing;
+        self
+    }
+
+    /// Log every answered query with the logger, such as `QueryLogger::file`
+    pub fn with_query_log(mut self, query_log: QueryLogger) -> Self {
+        self.query_log = Some(Arc::new(query_log));
+        self
+    }
+
+    pub fn with_max_udp_payload_size(mut self, max_udp_payload_size: u16) -> Self {
+        self.max_udp_payload_size = max_udp_payload_size;
+        self
+    }
+
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Spread UDP queries over several sockets sharing the port, to scale across cores
+    pub fn with_listeners(mut self, listeners: usize) -> Self {
+        self.listeners = listeners.max(1);
+        self
+    }
+
+    pub fn with_cookie_secret(mut self, cookie_secret: [u8; 16]) -> Self {
+        self.cookie_secret = cookie_secret;
+        self
+    }
+
+    pub fn with_required_cookies(mut self, require_cookies: bool) -> Self {
+        self.require_cookies = require_cookies;
+        self
+    }
+}
@@ -4,6 +4,8 @@ use core::fmt;
 pub enum RecurseError {
     NoNameServerError,
     NoARecordError,
+    MaxReferralsError(usize),
+    NoRootServerError,
 }
 
 impl std::error::Error for RecurseError {}
@@ -13,6 +15,88 @@ impl fmt::Display for RecurseError {
         match self {
             RecurseError::NoNameServerError => write!(f, "No NS record provided"),
             RecurseError::NoARecordError => write!(f, "No A record provided"),
+            RecurseError::MaxReferralsError(count) => {
+                write!(f, "Exceeded maximum of {} referrals", count)
+            }
+            RecurseError::NoRootServerError => write!(f, "No root server provided"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    NoCertificateError,
+    NoPrivateKeyError,
+}
+
+impl std::error::Error for TlsError {}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::NoCertificateError => write!(f, "No certificate provided"),
+            TlsError::NoPrivateKeyError => write!(f, "No private key provided"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RootHintsError {
+    ReadError(String),
+    InvalidLineError(usize, String),
+    NoHintsError,
+}
+
+impl std::error::Error for RootHintsError {}
+
+impl fmt::Display for RootHintsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RootHintsError::ReadError(err) => write!(f, "Unable to read root hints: {}", err),
+            RootHintsError::InvalidLineError(line, text) => {
+                write!(f, "Invalid root hint on line {}: {}", line, text)
+            }
+            RootHintsError::NoHintsError => write!(f, "No root hints provided"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BlocklistError {
+    ReadError(String),
+    InvalidLineError(usize, String),
+}
+
+impl std::error::Error for BlocklistError {}
+
+impl fmt::Display for BlocklistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlocklistError::ReadError(err) => write!(f, "Unable to read blocklist: {}", err),
+            BlocklistError::InvalidLineError(line, text) => {
+                write!(f, "Invalid blocklist entry on line {}: {}", line, text)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SubnetError {
+    InvalidAddressError(String),
+    InvalidPrefixError(String),
+}
+
+impl std::error::Error for SubnetError {}
+
+impl fmt::Display for SubnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubnetError::InvalidAddressError(value) => {
+                write!(f, "Invalid subnet address: {}", value)
+            }
+            SubnetError::InvalidPrefixError(value) => {
+                write!(f, "Invalid subnet prefix length: {}", value)
+            }
         }
     }
 }
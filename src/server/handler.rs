@@ -0,0 +1,104 @@
+use std::{error::Error, fmt, future::Future, pin::Pin};
+
+use crate::messages::{Request, Response};
+
+use super::config::{ServerConfig, StaticRecords};
+
+pub type HandlerError = Box<dyn Error + Send + Sync>;
+pub type HandlerResult = Result<Handled, HandlerError>;
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = HandlerResult> + Send + 'a>>;
+
+/// Outcome of a single handler in the chain
+pub enum Handled {
+    /// The response is complete, later handlers are skipped
+    Answered(Response),
+
+    /// Not handled, the response is passed on to the next handler
+    Pass(Response),
+}
+
+/// Extension point for custom request handling, such as blocklists or split-horizon answers
+///
+/// Each request is given to the handlers passed to `Server::new` in order, then to the static
+/// records, with a response prepared from the request. The first handler to return
+/// `Handled::Answered` has its response sent, later handlers are skipped. A handler returning
+/// `Handled::Pass` hands its response, possibly modified, to the next handler. Requests passed
+/// by every handler are answered from the cache or by recursion.
+///
+/// Handlers are shared between the tasks serving requests, so must be `Send + Sync`.
+pub trait Handler: Send + Sync {
+    /// Take up settings from the server's configuration, called once as the server is created
+    fn configure(&mut self, _config: &ServerConfig) {}
+
+    fn handle<'a>(&'a self, request: &'a Request, response: Response) -> HandlerFuture<'a>;
+}
+
+/// Handlers run in order until one answers
+#[derive(Default)]
+pub struct HandlerChain {
+    handlers: Vec<Box<dyn Handler>>,
+}
+
+impl HandlerChain {
+    pub fn new() -> HandlerChain {
+        HandlerChain::default()
+    }
+
+    pub fn push(&mut self, handler: Box<dyn Handler>) {
+        self.handlers.push(handler);
+    }
+
+    pub async fn handle(&self, request: &Request, mut response: Response) -> HandlerResult {
+        for handler in &self.handlers {
+            response = match handler.handle(request, response).await? {
+                Handled::Answered(response) => return Ok(Handled::Answered(response)),
+                Handled::Pass(response) => response,
+            };
+        }
+
+        Ok(Handled::Pass(response))
+    }
+}
+
+impl fmt::Debug for HandlerChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HandlerChain({} handlers)", self.handlers.len())
+    }
+}
+
+/// Answers configured records authoritatively
+pub struct StaticRecordsHandler {
+    records: StaticRecords,
+    default_ttl: u32,
+}
+
+impl StaticRecordsHandler {
+    /// Records without a TTL of their own are answered with the default
+    pub fn new(records: StaticRecords, default_ttl: u32) -> StaticRecordsHandler {
+        StaticRecordsHandler {
+            records,
+            default_ttl,
+        }
+    }
+}
+
+impl Handler for StaticRecordsHandler {
+    fn handle<'a>(&'a self, request: &'a Request, mut response: Response) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let records = request.questions().first().and_then(|question| {
+                self.records
+                    .get(&question.question_type, &question.domain, self.default_ttl)
+            });
+
+            match records {
+                Some(records) => {
+                    response.set_answers(records);
+                    response.set_authoritative(true);
+
+                    Ok(Handled::Answered(response))
+                }
+                None => Ok(Handled::Pass(response)),
+            }
+        })
+    }
+}
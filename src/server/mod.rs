@@ -1,64 +1,462 @@
-use log::{error, info};
+use log::{error, info, warn};
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    future::{self, Future},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
 };
-use tokio::{join, net::UdpSocket};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time,
+};
+use tokio_rustls::TlsAcceptor;
 
+pub mod blocklist;
 pub mod cache;
+pub mod coalesce;
+pub mod config;
+pub mod cookie;
 pub mod errors;
+pub mod handler;
+pub mod query_log;
+pub mod selection;
+pub mod socket;
+pub mod split_horizon;
+pub mod task_errors;
+pub mod tls;
 
 use crate::messages::{
     client::Client,
     connection::Connection,
-    packets::{Message, ResourceRecordData, ResourceRecordType, ResponseCode},
+    packets::{
+        ClientSubnet, EdnsOption, ExtendedErrorCode, Message, Question, QuestionClass,
+        ResourceRecord, ResourceRecordClass, ResourceRecordData, ResourceRecordType, ResponseCode,
+    },
+    stream::StreamConnection,
     Request, Response,
 };
 
-use self::{cache::HashCache, errors::RecurseError};
+use self::{
+    cache::{HashCache, NegativeAnswer},
+    coalesce::{InFlight, Joined},
+    config::{
+        AnswerOrder, ClientSubnetConfig, ForwardMode, Forwarder, RootHints, ServerConfig, TlsConfig,
+    },
+    errors::RecurseError,
+    handler::{Handled, Handler, HandlerChain, StaticRecordsHandler},
+    selection::NameServerSelector,
+    task_errors::TaskErrors,
+};
 
 type ServerResult<T> = Result<T, Box<dyn std::error::Error>>;
 type Cache = Arc<HashCache>;
 
+/// TTL of root name server records answered from the hints, as in the root zone
+const ROOT_HINTS_TTL: u32 = 518400;
+
+/// Most distinct questions a query may ask, only the first is answered
+const MAX_QUESTIONS: usize = 4;
+
+/// Port name servers found through referrals are queried on, a hint's port only applies to the root
+const NAME_SERVER_PORT: u16 = 53;
+
 pub struct Server {
     base_handler: BaseHandler,
+    config: ServerConfig,
+}
+
+/// Records of every section to cache, each under its own name and type
+///
+/// OPT records belong to the message rather than any name so aren't cached.
+fn cached_records(message: Message) -> Vec<ResourceRecord> {
+    message
+        .answers
+        .into_iter()
+        .chain(message.authorities)
+        .chain(message.additional_records)
+        .filter(|record| record.record_type != ResourceRecordType::OPTRecord)
+        .collect()
+}
+
+/// Whether the address is only meaningful within the local network
+fn is_internal(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            address.is_loopback()
+                || address.is_private()
+                || address.is_link_local()
+                || address.is_unspecified()
+        }
+        IpAddr::V6(address) => {
+            let first = address.segments()[0];
+
+            // Unique local fc00::/7 and link local fe80::/10
+            address.is_loopback()
+                || address.is_unspecified()
+                || first & 0xFE00 == 0xFC00
+                || first & 0xFFC0 == 0xFE80
+        }
+    }
+}
+
+/// A claim on refreshing a cache entry in the background, released when dropped
+struct PrefetchClaim {
+    cache: Cache,
+    question: Question,
+}
+
+impl Drop for PrefetchClaim {
+    fn drop(&mut self) {
+        self.cache
+            .release_prefetch(self.question.question_type.clone(), &self.question.domain);
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct BaseHandler {
     cache: Cache,
+    root_server: Option<SocketAddr>,
+    root_hints: Arc<RootHints>,
+    selector: Arc<NameServerSelector>,
+    /// Port referral targets are queried on
+    name_server_port: u16,
+    in_flight: Arc<InFlight>,
+    max_referrals: usize,
+    handlers: Arc<HandlerChain>,
+    synthesized_ttl: u32,
+    version: String,
+    hostname: String,
+    recursion: bool,
+    forwarders: Arc<Vec<Forwarder>>,
+    forward_mode: ForwardMode,
+    answer_order: AnswerOrder,
+    minimal_responses: bool,
+    min_response_time: Option<Duration>,
+    /// Offset multi-record answers are rotated by, advanced on each response
+    rotation: Arc<AtomicUsize>,
+    client_subnet: Option<ClientSubnetConfig>,
+    prefetch_min_hits: Option<u32>,
+    stale_answer_timeout: Duration,
+    max_udp_payload_size: u16,
+    cookie_secret: [u8; 16],
+    require_cookies: bool,
+    /// Errors raised while serving requests, shared with the embedding application
+    errors: Arc<TaskErrors>,
 }
 
 impl BaseHandler {
-    fn new() -> BaseHandler {
+    #[cfg(test)]
+    fn new(config: &ServerConfig) -> BaseHandler {
+        BaseHandler::with_handlers(config, vec![])
+    }
+
+    /// Run the given handlers in order ahead of the static records
+    fn with_handlers(config: &ServerConfig, custom_handlers: Vec<Box<dyn Handler>>) -> BaseHandler {
+        let mut handlers = HandlerChain::new();
+
+        for mut handler in custom_handlers {
+            handler.configure(config);
+            handlers.push(handler);
+        }
+
+        handlers.push(Box::new(StaticRecordsHandler::new(
+            config.static_records.clone(),
+            config.synthesized_ttl,
+        )));
+
+        let mut cache = HashCache::new();
+
+        if let Some(window) = config.serve_stale {
+            cache = cache.with_serve_stale(window);
+        }
+
         BaseHandler {
-            cache: Arc::new(HashCache::new()),
+            cache: Arc::new(cache),
+            root_server: config.root_server,
+            root_hints: Arc::new(config.root_hints.clone()),
+            selector: Arc::new(NameServerSelector::new()),
+            name_server_port: NAME_SERVER_PORT,
+            in_flight: Arc::new(InFlight::new()),
+            max_referrals: config.max_referrals,
+            handlers: Arc::new(handlers),
+            synthesized_ttl: config.synthesized_ttl,
+            version: config.version.clone(),
+            hostname: config.hostname.clone(),
+            recursion: config.recursion,
+            forwarders: Arc::new(config.forwarders.clone()),
+            forward_mode: config.forward_mode,
+            answer_order: config.answer_order,
+            minimal_responses: config.minimal_responses,
+            min_response_time: config.min_response_time,
+            rotation: Arc::new(AtomicUsize::new(0)),
+            client_subnet: config.client_subnet,
+            prefetch_min_hits: config.prefetch_min_hits,
+            stale_answer_timeout: config.stale_answer_timeout,
+            max_udp_payload_size: config.max_udp_payload_size,
+            cookie_secret: config.cookie_secret,
+            require_cookies: config.require_cookies,
+            errors: Arc::new(TaskErrors::new()),
+        }
+    }
+
+    /// Echo the client cookie alongside the server cookie for the request's source
+    fn attach_cookie(&self, request: &Request, response: &mut Response) {
+        if let (Some((client_cookie, _)), Some(source)) = (request.cookie(), request.source()) {
+            let server_cookie = cookie::server_cookie(&self.cookie_secret, client_cookie, source);
+            response.set_cookie(client_cookie, &server_cookie);
+        }
+    }
+
+    /// Reject a UDP request with BADCOOKIE if cookies are required and its server cookie isn't valid
+    ///
+    /// The TC bit is set so the client may retry over TCP instead.
+    fn bad_cookie_response(&self, request: &Request) -> Option<Response> {
+        if !self.require_cookies {
+            return None;
+        }
+
+        let (client_cookie, server_cookie) = request.cookie()?;
+        let source = request.source()?;
+
+        if server_cookie == cookie::server_cookie(&self.cookie_secret, client_cookie, source) {
+            return None;
+        }
+
+        let mut response = request.response();
+        response.set_code(ResponseCode::BadCookie);
+        response.set_truncated(true);
+        response.set_recursion_available(self.recursion);
+        response.set_udp_payload_size(self.max_udp_payload_size);
+        self.attach_cookie(request, &mut response);
+
+        Some(response)
+    }
+
+    /// Answer a CH class question, only `version.bind` and `hostname.bind` TXT are supported
+    fn chaos_answer(&self, question: &Question) -> Option<ResourceRecord> {
+        if question.question_type != ResourceRecordType::TXTRecord {
+            return None;
+        }
+
+        let text = match question.domain.to_lowercase().as_str() {
+            "version.bind." => self.version.clone(),
+            "hostname.bind." => self.hostname.clone(),
+            _ => return None,
+        };
+
+        Some(ResourceRecord {
+            domain: question.domain.clone(),
+            record_type: ResourceRecordType::TXTRecord,
+            class: ResourceRecordClass::Chaos,
+            time_to_live: self.synthesized_ttl,
+            data: ResourceRecordData::TXT(vec![text.into_bytes()]),
+        })
+    }
+
+    /// Answer an NS query for the root from the root hints, with their addresses as glue
+    fn root_answer(&self, response: &mut Response) {
+        let mut answers = vec![];
+
+        for hint in self.root_hints.hints() {
+            answers.push(ResourceRecord {
+                domain: String::from("."),
+                record_type: ResourceRecordType::NSRecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: ROOT_HINTS_TTL,
+                data: ResourceRecordData::NS(hint.name.clone()),
+            });
+
+            let data = match hint.addr.ip() {
+                IpAddr::V4(addr) => ResourceRecordData::from_ipv4(addr),
+                IpAddr::V6(addr) => ResourceRecordData::from_ipv6(addr),
+            };
+
+            response.add_additional_record(ResourceRecord {
+                domain: hint.name.clone(),
+                record_type: data.get_type(),
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: ROOT_HINTS_TTL,
+                data,
+            });
         }
+
+        response.set_answers(answers);
     }
 
     fn cache_records(&self, message: Message) {
         // Get reference counted cache
         let write_cache = self.cache.clone();
+        let records = cached_records(message);
 
         // Put all message resource records at once
-        tokio::spawn(async move {
-            join!(
-                write_cache.put_resource_records(&message.answers),
-                write_cache.put_resource_records(&message.authorities),
-                write_cache.put_resource_records(&message.additional_records),
+        tokio::spawn(async move { write_cache.put_resource_records(&records).await });
+    }
+
+    /// Client subnet option for queries forwarded on behalf of the source, if enabled
+    ///
+    /// Internal sources are left out, as their addresses would only leak the local network's
+    /// layout upstream (RFC 7871 section 11.1), as is a zero length prefix which says nothing.
+    fn client_subnet_options(&self, source: Option<IpAddr>) -> Vec<EdnsOption> {
+        let (config, source) = match (self.client_subnet, source) {
+            (Some(config), Some(source)) if !is_internal(source) => (config, source),
+            _ => return vec![],
+        };
+
+        let prefix_length = match source {
+            IpAddr::V4(_) => config.ipv4_prefix_length,
+            IpAddr::V6(_) => config.ipv6_prefix_length,
+        };
+
+        if prefix_length == 0 {
+            return vec![];
+        }
+
+        vec![EdnsOption::ClientSubnet(ClientSubnet::new(
+            source,
+            prefix_length,
+        ))]
+    }
+
+    /// Forward the question to an upstream, over TLS if configured
+    async fn forward_request(
+        &self,
+        forwarder: &Forwarder,
+        question: &Question,
+        source: Option<IpAddr>,
+    ) -> ServerResult<Message> {
+        let client = match &forwarder.tls_server_name {
+            Some(server_name) => Client::dial_tls(forwarder.addr, server_name).await?,
+            None => Client::dial(forwarder.addr).await?,
+        };
+
+        client
+            .query_with_options(
+                &question.domain,
+                question.question_type.clone(),
+                self.client_subnet_options(source),
             )
-        });
+            .await
+    }
+
+    /// Forward the question to every forwarder at once, answering with the first valid response
+    ///
+    /// Returning drops the outstanding queries, cancelling them.
+    async fn race_forwarders(
+        &self,
+        question: &Question,
+        source: Option<IpAddr>,
+    ) -> ServerResult<Message> {
+        let mut pending: Vec<Pin<Box<dyn Future<Output = ServerResult<Message>> + Send + '_>>> =
+            self.forwarders
+                .iter()
+                .map(|forwarder| {
+                    Box::pin(self.forward_request(forwarder, question, source))
+                        as Pin<Box<dyn Future<Output = ServerResult<Message>> + Send>>
+                })
+                .collect();
+
+        // Kept as text, as boxed errors can't be held across the await
+        let mut last_error = String::from("No forwarders configured");
+
+        future::poll_fn(|cx| {
+            let mut index = 0;
+
+            while index < pending.len() {
+                match pending[index].as_mut().poll(cx) {
+                    // Upstream failures don't count as valid, wait on the others
+                    Poll::Ready(Ok(message))
+                        if !matches!(
+                            message.response_code,
+                            ResponseCode::ServerError | ResponseCode::Refused
+                        ) =>
+                    {
+                        return Poll::Ready(Ok(message))
+                    }
+                    Poll::Ready(Ok(message)) => {
+                        last_error = format!("Forwarder answered {:?}", message.response_code);
+                        drop(pending.swap_remove(index));
+                    }
+                    Poll::Ready(Err(err)) => {
+                        last_error = err.to_string();
+                        drop(pending.swap_remove(index));
+                    }
+                    Poll::Pending => index += 1,
+                }
+            }
+
+            if pending.is_empty() {
+                return Poll::Ready(Err(last_error.clone().into()));
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Choose one of the name servers named by NS records
+    fn choose_name_server<'a>(&self, ns_records: &[&'a ResourceRecord]) -> Option<&'a str> {
+        let names: Vec<&str> = ns_records
+            .iter()
+            .filter_map(|record| match &record.data {
+                ResourceRecordData::NS(domain) => Some(domain.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        let candidates: Vec<(&str, u16)> = names.iter().map(|name| (*name, 0)).collect();
+
+        self.selector.choose(&candidates).map(|index| names[index])
+    }
+
+    /// Query a name server, remembering it as unresponsive if the query fails
+    async fn query_name_server(
+        &self,
+        name_server: &str,
+        client: &Client,
+        domain: &str,
+        record_type: ResourceRecordType,
+    ) -> ServerResult<Message> {
+        let result = client.query(domain, record_type).await;
+
+        if result.is_err() {
+            self.selector.record_failure(name_server);
+        }
+
+        result
     }
 
     async fn recurse_request(&self, name: &str) -> ServerResult<Message> {
-        // Address for the root server
-        let mut name_server_address = SocketAddr::from(([198, 41, 0, 4], 53));
+        // Start from the pinned root server, or any from the hints
+        let (mut name_server, mut name_server_address) = match self.root_server {
+            Some(root_server) => (root_server.to_string(), root_server),
+            None => {
+                let hints = self.root_hints.hints();
+                let candidates: Vec<(&str, u16)> =
+                    hints.iter().map(|hint| (hint.name.as_str(), 0)).collect();
+
+                let hint = self
+                    .selector
+                    .choose(&candidates)
+                    .map(|index| &hints[index])
+                    .ok_or(RecurseError::NoRootServerError)?;
+
+                (hint.name.clone(), hint.addr)
+            }
+        };
 
         // Split the labels, reverse as we recurse from the base
         let labels = name.split('.').rev();
 
         let mut search_domain = String::from("");
 
+        // Number of upstream referrals followed so far
+        let mut referrals = 0;
+
         for label in labels {
             // Ignore if empty
             if label.is_empty() {
@@ -74,21 +472,17 @@ impl BaseHandler {
                 .get(ResourceRecordType::NSRecord, &search_domain)
                 .await
             {
-                // Get the first record, if none break here and continue
-                let ns_record = match ns_records.first() {
-                    Some(record) => record,
-                    None => break,
-                };
+                // Pick one of the name servers, if none break here and continue
+                let ns_records: Vec<&ResourceRecord> = ns_records.iter().collect();
 
-                // TODO put this as a response method
-                let name_server_domain = match &ns_record.data {
-                    ResourceRecordData::NS(domain) => domain,
-                    _ => break,
+                let name_server_domain = match self.choose_name_server(&ns_records) {
+                    Some(domain) => domain,
+                    None => break,
                 };
 
                 if let Some(a_records) = self
                     .cache
-                    .get(ResourceRecordType::ARecord, &name_server_domain)
+                    .get(ResourceRecordType::ARecord, name_server_domain)
                     .await
                 {
                     // Get the first record, if none break here and continue
@@ -97,50 +491,66 @@ impl BaseHandler {
                         None => break,
                     };
 
-                    match a_record.data {
+                    match a_record.data.as_ipv4() {
                         // Set the name server address to the new address
-                        crate::messages::packets::ResourceRecordData::A(value) => {
-                            name_server_address.set_ip(IpAddr::V4(Ipv4Addr::from(value)))
+                        Some(addr) => {
+                            name_server_address =
+                                SocketAddr::new(IpAddr::V4(addr), self.name_server_port)
                         }
-                        _ => break,
+                        None => break,
                     };
 
+                    name_server = name_server_domain.to_string();
+
                     // We have a cached value, continue on
                     continue;
                 }
             }
 
+            // Guard against pathological delegations chaining indefinitely
+            if referrals >= self.max_referrals {
+                return Err(Box::new(RecurseError::MaxReferralsError(referrals)));
+            }
+
+            referrals += 1;
+
             let client = Client::dial(name_server_address).await?;
 
-            let response = client
-                .query(&search_domain, ResourceRecordType::NSRecord)
+            let response = self
+                .query_name_server(
+                    &name_server,
+                    &client,
+                    &search_domain,
+                    ResourceRecordType::NSRecord,
+                )
                 .await?;
 
-            // Get NS record for the search domain
-            let ns_record = response
-                .get_record(&ResourceRecordType::NSRecord, &search_domain)
-                .ok_or_else(|| RecurseError::NoNameServerError)?;
-
-            // Get domain for name server
-            let name_server_domain = match &ns_record.data {
-                ResourceRecordData::NS(domain) => domain,
-                _ => return Err(Box::new(RecurseError::NoNameServerError)),
-            };
+            // Pick one of the name servers for the search domain
+            let name_server_domain = self
+                .choose_name_server(
+                    &response.get_records(&ResourceRecordType::NSRecord, &search_domain),
+                )
+                .ok_or(RecurseError::NoNameServerError)?;
 
             // Get an A record for the name server if provided
             let a_record =
-                match response.get_record(&ResourceRecordType::ARecord, &name_server_domain) {
+                match response.get_record(&ResourceRecordType::ARecord, name_server_domain) {
                     // If an A record is provided in the response, then use that
                     Some(record) => record.clone(),
                     // Perform another query if not
                     None => {
-                        let response = client
-                            .query(&name_server_domain, ResourceRecordType::ARecord)
+                        let response = self
+                            .query_name_server(
+                                &name_server,
+                                &client,
+                                name_server_domain,
+                                ResourceRecordType::ARecord,
+                            )
                             .await?;
 
                         let message = response
-                            .get_record(&ResourceRecordType::ARecord, &name_server_domain)
-                            .ok_or_else(|| RecurseError::NoARecordError)?
+                            .get_record(&ResourceRecordType::ARecord, name_server_domain)
+                            .ok_or(RecurseError::NoARecordError)?
                             .clone();
 
                         self.cache_records(response);
@@ -150,24 +560,226 @@ impl BaseHandler {
                 };
 
             // Get IP address from A record
-            match a_record.data {
+            match a_record.data.as_ipv4() {
                 // Set the name server address to the new address
-                crate::messages::packets::ResourceRecordData::A(value) => {
-                    name_server_address.set_ip(IpAddr::V4(Ipv4Addr::from(value)))
+                Some(addr) => {
+                    name_server_address = SocketAddr::new(IpAddr::V4(addr), self.name_server_port)
                 }
-                _ => return Err(Box::new(RecurseError::NoARecordError)),
+                None => return Err(Box::new(RecurseError::NoARecordError)),
             };
 
+            name_server = name_server_domain.to_string();
+
             // Cache all values
             self.cache_records(response);
         }
 
+        // A query for the root itself has no labels to search
+        if search_domain.is_empty() {
+            search_domain.push('.');
+        }
+
         // Finally get the A record
         let client = Client::dial(name_server_address).await?;
 
-        client
-            .query(&search_domain, ResourceRecordType::ARecord)
+        self.query_name_server(
+            &name_server,
+            &client,
+            &search_domain,
+            ResourceRecordType::ARecord,
+        )
+        .await
+    }
+
+    /// Resolve the question, giving up early if it's slow and there's a stale answer to serve
+    ///
+    /// The resolution runs in its own task, so one given up on still finishes and refreshes the
+    /// cache, and questions joined to it aren't left to resolve again (RFC 8767 section 5).
+    /// Errors are described as text, as they aren't Send.
+    async fn resolve_or_stale(
+        &self,
+        question: &Question,
+        source: Option<IpAddr>,
+    ) -> Result<Message, String> {
+        let base_handler = self.clone();
+        let resolving = question.clone();
+
+        let mut resolution = tokio::spawn(async move {
+            base_handler
+                .resolve(&resolving, source)
+                .await
+                .map_err(|err| err.to_string())
+        });
+
+        if let Ok(result) = time::timeout(self.stale_answer_timeout, &mut resolution).await {
+            return result.map_err(|err| err.to_string())?;
+        }
+
+        if self
+            .cache
+            .get_stale(question.question_type.clone(), &question.domain)
+            .await
+            .is_some()
+        {
+            let base_handler = self.clone();
+            let question = question.clone();
+
+            // Cache the answer once it arrives, the client is answered from the stale entry
+            tokio::spawn(async move {
+                if let Ok(Ok(message)) = resolution.await {
+                    base_handler.cache_resolution(&question, message).await;
+                }
+            });
+
+            return Err(format!("no answer within {:?}", self.stale_answer_timeout));
+        }
+
+        // Nothing to fall back on, keep waiting for the answer
+        resolution.await.map_err(|err| err.to_string())?
+    }
+
+    /// Cache a resolved answer, unless it's tailored to the client's subnet and can't be shared
+    /// with other clients
+    async fn cache_resolution(&self, question: &Question, message: Message) {
+        let scoped = message
+            .client_subnet()
+            .is_some_and(|subnet| subnet.scope_prefix_length > 0);
+
+        if scoped {
+            return;
+        }
+
+        if let Some(negative) = NegativeAnswer::from_response(&message) {
+            self.cache
+                .put_negative(question.question_type.clone(), &question.domain, negative)
+                .await;
+        }
+
+        self.cache_records(message);
+    }
+
+    /// Answer with a recently expired entry when the question couldn't be resolved
+    async fn serve_stale(
+        &self,
+        question: &Question,
+        err: String,
+        mut response: Response,
+    ) -> ServerResult<Response> {
+        let stale = self
+            .cache
+            .get_stale(question.question_type.clone(), &question.domain)
             .await
+            .ok_or(err.clone())?;
+
+        warn!("Serving stale answer after {}", err);
+
+        response.set_answers(stale);
+        response.set_extended_error(ExtendedErrorCode::StaleAnswer, "");
+        response.set_cached(true);
+
+        Ok(response)
+    }
+
+    /// Add the closest cached NS set enclosing the domain as a referral, with any cached glue
+    async fn refer(&self, domain: &str, response: &mut Response) {
+        let mut zone = domain;
+
+        let name_servers = loop {
+            if let Some(records) = self.cache.get(ResourceRecordType::NSRecord, zone).await {
+                if !records.is_empty() {
+                    break records;
+                }
+            }
+
+            match zone.split_once('.') {
+                Some((_, parent)) if !parent.is_empty() => zone = parent,
+                _ => return,
+            }
+        };
+
+        for name_server in &name_servers {
+            let name = match &name_server.data {
+                ResourceRecordData::NS(name) => name,
+                _ => continue,
+            };
+
+            for record_type in [ResourceRecordType::ARecord, ResourceRecordType::AAAARecord] {
+                for glue in self.cache.get(record_type, name).await.unwrap_or_default() {
+                    response.add_additional_record(glue);
+                }
+            }
+        }
+
+        response.set_authorities(name_servers);
+    }
+
+    /// Resolve the question, sharing one resolution between concurrent identical questions
+    ///
+    /// Answers tailored to the client's subnet differ between clients, so aren't shared.
+    async fn resolve(&self, question: &Question, source: Option<IpAddr>) -> ServerResult<Message> {
+        if self.client_subnet.is_some() {
+            return self.resolve_alone(question, source).await;
+        }
+
+        match self
+            .in_flight
+            .join(&question.domain, question.question_type.clone())
+        {
+            Joined::Leader(leader) => {
+                let result = self.resolve_alone(question, source).await;
+
+                leader.finish(match &result {
+                    Ok(message) => Ok(message.clone()),
+                    Err(err) => Err(err.to_string()),
+                });
+
+                result
+            }
+            Joined::Follower(mut receiver) => match receiver.recv().await {
+                Ok(result) => Ok(result?),
+                // The leading resolution was abandoned, resolve alone instead
+                Err(_) => self.resolve_alone(question, source).await,
+            },
+        }
+    }
+
+    /// Forward the question if configured, otherwise recurse from the root
+    async fn resolve_alone(
+        &self,
+        question: &Question,
+        source: Option<IpAddr>,
+    ) -> ServerResult<Message> {
+        match (self.forwarders.first(), self.forward_mode) {
+            (Some(_), ForwardMode::Race) => self.race_forwarders(question, source).await,
+            (Some(forwarder), ForwardMode::First) => {
+                self.forward_request(forwarder, question, source).await
+            }
+            (None, _) => self.recurse_request(&question.domain).await,
+        }
+    }
+
+    /// Resolve the question again in the background, caching the fresh records
+    fn prefetch(&self, question: Question) {
+        let base_handler = self.clone();
+
+        tokio::spawn(async move {
+            // Released however the refresh ends, so a failed refresh can be tried again
+            let _claim = PrefetchClaim {
+                cache: base_handler.cache.clone(),
+                question: question.clone(),
+            };
+
+            let records = match base_handler.resolve(&question, None).await {
+                Ok(message) => cached_records(message),
+                Err(err) => {
+                    error!("Prefetch of {} failed: {}", question.domain, err);
+                    return;
+                }
+            };
+
+            // Cached before the claim is released, so the entry isn't refreshed twice
+            base_handler.cache.put_resource_records(&records).await;
+        });
     }
 
     async fn handle(&self, request: &Request, mut response: Response) -> ServerResult<Response> {
@@ -175,10 +787,68 @@ impl BaseHandler {
             // Get first question
             Some(question) => question,
 
-            // If no questions, just return a blank answer
-            None => return Ok(response),
+            // A query without a question can't be answered, unless it only asks for a server
+            // cookie (RFC 7873), which is attached to the blank response
+            None => {
+                if request.cookie().is_none() {
+                    response.set_code(ResponseCode::FormatError);
+                }
+
+                return Ok(response);
+            }
+        };
+
+        // Repeats are already dropped, more questions than this is a malformed or abusive query
+        if request.questions().len() > MAX_QUESTIONS {
+            response.set_code(ResponseCode::FormatError);
+
+            return Ok(response);
+        }
+
+        // Unknown and reserved types can't be cached or resolved
+        if matches!(
+            question.question_type,
+            ResourceRecordType::Unimplemented(_) | ResourceRecordType::OPTRecord
+        ) {
+            response.set_code(ResponseCode::NotImplemented);
+
+            return Ok(response);
+        }
+
+        // CH class questions are answered locally, never cached or recursed
+        if matches!(question.class, QuestionClass::Chaos) {
+            match self.chaos_answer(question) {
+                Some(record) => {
+                    response.set_answers(vec![record]);
+                    response.set_authoritative(true);
+                }
+                None => response.set_code(ResponseCode::Refused),
+            }
+
+            return Ok(response);
+        }
+
+        // Static records and any other handlers take precedence over the cache and recursion
+        response = match self.handlers.handle(request, response).await {
+            Ok(Handled::Answered(response)) => return Ok(response),
+            Ok(Handled::Pass(response)) => response,
+            Err(err) => return Err(err),
         };
 
+        // The root name servers are known from the hints without recursing
+        if question.domain == "." && question.question_type == ResourceRecordType::NSRecord {
+            self.root_answer(&mut response);
+
+            return Ok(response);
+        }
+
+        // Without recursion only local data is answered, nothing is cached to fall back on
+        if !self.recursion {
+            response.set_code(ResponseCode::Refused);
+
+            return Ok(response);
+        }
+
         match self
             .cache
             .get(question.question_type.clone(), &question.domain)
@@ -187,35 +857,78 @@ impl BaseHandler {
             Some(records) => {
                 // Set answers from cache
                 response.set_answers(records);
+                response.set_cached(true);
+
+                // Refresh popular entries in the background before they expire
+                if let Some(min_hits) = self.prefetch_min_hits {
+                    if self
+                        .cache
+                        .claim_prefetch(question.question_type.clone(), &question.domain, min_hits)
+                        .await
+                    {
+                        self.prefetch(question.clone());
+                    }
+                }
 
                 // Send response
-                return Ok(response);
+                Ok(response)
             }
             None => {
+                // The name or type is known not to exist
+                if let Some(negative) = self
+                    .cache
+                    .get_negative(question.question_type.clone(), &question.domain)
+                    .await
+                {
+                    response.set_code(negative.response_code);
+                    response.set_authorities(vec![negative.soa]);
+                    response.set_cached(true);
+
+                    return Ok(response);
+                }
+
                 // Check that recursion is required
                 if request.recursion_desired() {
-                    // Recurse the request
-                    let recurse_response = self.recurse_request(&question.domain).await?;
+                    let recurse_response = if self.cache.serves_stale() {
+                        match self.resolve_or_stale(question, request.source()).await {
+                            Ok(recurse_response) => recurse_response,
+                            Err(err) => return self.serve_stale(question, err, response).await,
+                        }
+                    } else {
+                        self.resolve(question, request.source()).await?
+                    };
 
                     // Set answers
                     response.set_answers(recurse_response.answers.clone());
+                    response.copy_unknown_options(&recurse_response);
+
+                    // Pass on NXDOMAIN and NODATA with the SOA the negative TTL comes from
+                    if let Some(negative) = NegativeAnswer::from_response(&recurse_response) {
+                        response.set_code(negative.response_code);
+                        response.set_authorities(vec![negative.soa]);
+                    }
 
                     // Cache response
-                    self.cache_records(recurse_response);
+                    self.cache_resolution(question, recurse_response).await;
 
                     return Ok(response);
                 }
 
-                return Ok(response);
+                // Without recursion, point towards the answer with the best known delegation
+                self.refer(&question.domain, &mut response).await;
+
+                Ok(response)
             }
         }
     }
 }
 
 impl Server {
-    pub async fn new() -> Server {
+    /// Create a server passing requests through the given handlers before its own
+    pub async fn new(config: ServerConfig, handlers: Vec<Box<dyn Handler>>) -> Server {
         Server {
-            base_handler: BaseHandler::new(),
+            base_handler: BaseHandler::with_handlers(&config, handlers),
+            config,
         }
     }
 
@@ -223,54 +936,2526 @@ impl Server {
         info!("{}", message);
     }
 
-    pub async fn listen(self, port: u16) -> ServerResult<()> {
-        // Listen on given port
-        let listen_addr = SocketAddr::from(([0, 0, 0, 0], port));
+    /// Format the names queried, the result code and the time taken to answer
+    fn timing_line(request: &Request, response: &Response, elapsed: Duration) -> String {
+        let names: Vec<&str> = request
+            .questions()
+            .iter()
+            .map(|question| question.domain.as_str())
+            .collect();
 
-        info!("Listening on {}", listen_addr);
+        format!(
+            "Answered {} with {:?} in {:.3}ms",
+            names.join(","),
+            response.message().response_code,
+            elapsed.as_secs_f64() * 1000.0
+        )
+    }
 
-        // Wrap socket in reference count for use in both async moves
-        let socket = Arc::new(UdpSocket::bind(listen_addr).await?);
+    fn log_timing(request: &Request, response: &Response, received: Instant) {
+        info!(
+            "{}",
+            Server::timing_line(request, response, received.elapsed())
+        );
+    }
 
-        loop {
-            // Get a reference counted copy of the sockets
-            let socket = socket.clone();
+    /// Handle a request within the given deadline, answering with a server error on failure.
+    ///
+    /// Expiry drops the handler future, which cancels any in-flight upstream queries.
+    async fn respond(base_handler: &BaseHandler, request: &Request, timeout: Duration) -> Response {
+        let started = time::Instant::now();
+        let response = request.response();
 
-            // Wait for an incoming message
-            let (addr, message) = Connection::new().read_message(&socket).await?;
+        let handled = async {
+            // Queries breaking the basic invariants are answered with the reason, not handled
+            if let Err(err) = request.message().validate() {
+                warn!("Rejected message {}: {}", request.id(), err);
 
-            let base_handler = self.base_handler.clone();
+                let mut response = response;
+                response.set_code(err.response_code());
+                response.set_answers(vec![]);
+                response.set_authorities(vec![]);
 
-            // Spawn a new task and move all scoped variables into the task
-            tokio::spawn(async move {
-                let request = Request::new(message);
+                return Ok(response);
+            }
 
-                Server::log_message(request.message());
+            base_handler.handle(request, response).await
+        };
+
+        let mut response = match time::timeout(timeout, handled).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(err)) => {
+                base_handler
+                    .errors
+                    .report(format!("Handler error: {}", err));
+
+                let mut response = request.response();
+                response.set_code(ResponseCode::ServerError);
+                // The detail is logged, it may name upstreams or local errors clients shouldn't see
+                response.set_extended_error(ExtendedErrorCode::Other, "");
+                response
+            }
+            Err(_) => {
+                base_handler
+                    .errors
+                    .report(format!("Handler timed out after {:?}", timeout));
 
                 let mut response = request.response();
+                response.set_code(ResponseCode::ServerError);
+                response.set_extended_error(ExtendedErrorCode::NoReachableAuthority, "");
+                response
+            }
+        };
 
-                response = match base_handler.handle(&request, response).await {
-                    Ok(response) => response,
-                    Err(err) => {
-                        error!("Handler error {:?}", err);
+        response.set_recursion_available(base_handler.recursion);
+        response.set_udp_payload_size(base_handler.max_udp_payload_size);
+        base_handler.attach_cookie(request, &mut response);
+        response.dedup_answers();
+        response.normalize_order();
 
-                        let mut response = request.response();
-                        response.set_code(ResponseCode::ServerError);
-                        response
-                    }
-                };
+        match base_handler.answer_order {
+            AnswerOrder::Fixed => {}
+            AnswerOrder::RoundRobin => {
+                response.rotate_answers(base_handler.rotation.fetch_add(1, Ordering::Relaxed))
+            }
+            AnswerOrder::Random => response.shuffle_answers(),
+        }
 
-                Server::log_message(response.message());
+        if base_handler.minimal_responses {
+            response.minimize();
+        }
 
-                // Write response to socket
-                if let Some(err) = Connection::new()
-                    .write_message(&socket, response.message(), &addr)
-                    .await
-                    .err()
-                {
-                    error!("Error writing response {}: {}", request.id(), err);
-                }
-            });
+        // Fast answers, such as cache hits, wait out the rest of the floor
+        if let Some(min_response_time) = base_handler.min_response_time {
+            time::sleep_until(started + min_response_time).await;
         }
+
+        response
+    }
+
+    /// The cache shared by every listener, for flushing at runtime
+    pub fn cache(&self) -> Arc<HashCache> {
+        self.base_handler.cache.clone()
+    }
+
+    /// Errors raised by the tasks serving requests, for monitoring failures while serving
+    pub fn task_errors(&self) -> Arc<TaskErrors> {
+        self.base_handler.errors.clone()
+    }
+
+    /// Clear the cache whenever the process is sent SIGHUP
+    #[cfg(unix)]
+    pub fn flush_cache_on_hangup(&self) -> ServerResult<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        let cache = self.cache();
+
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("Clearing the cache on SIGHUP");
+                cache.clear().await;
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Listen on the given port on all interfaces
+    pub async fn listen(self, port: u16) -> ServerResult<()> {
+        self.listen_on(SocketAddr::from(([0, 0, 0, 0], port))).await
+    }
+
+    /// Bind the configured number of UDP sockets to the address, sharing the port between them
+    fn bind_listeners(&self, listen_addr: SocketAddr) -> std::io::Result<Vec<UdpSocket>> {
+        let mut options = self.config.socket_options;
+
+        if self.config.listeners > 1 {
+            options.reuse_port = true;
+        }
+
+        let first = socket::bind_udp(listen_addr, &options)?;
+
+        // The rest bind to the port the first was given, in case any port was asked for
+        let listen_addr = first.local_addr()?;
+        let mut sockets = vec![first];
+
+        for _ in 1..self.config.listeners {
+            sockets.push(socket::bind_udp(listen_addr, &options)?);
+        }
+
+        Ok(sockets)
+    }
+
+    /// Bind the DNS-over-TLS listener to its port on the same address as the UDP sockets
+    async fn bind_tls_listener(
+        &self,
+        tls_config: &TlsConfig,
+        listen_addr: SocketAddr,
+    ) -> std::io::Result<TcpListener> {
+        TcpListener::bind(SocketAddr::new(listen_addr.ip(), tls_config.port)).await
+    }
+
+    /// Listen on the given address only
+    pub async fn listen_on(self, listen_addr: SocketAddr) -> ServerResult<()> {
+        let mut sockets = self.bind_listeners(listen_addr)?;
+        let socket = sockets.pop().ok_or("No listening sockets bound")?;
+
+        // Every socket but the last is served in its own task, all sharing the handler and cache
+        for socket in sockets {
+            tokio::spawn(Server::serve_udp(
+                self.base_handler.clone(),
+                self.config.clone(),
+                socket,
+            ));
+        }
+
+        // Start the DNS-over-TLS listener alongside if configured
+        if let Some(tls_config) = &self.config.tls {
+            let acceptor = tls::load_acceptor(tls_config).map_err(|err| err.to_string())?;
+            let listener = self.bind_tls_listener(tls_config, listen_addr).await?;
+
+            tokio::spawn(Server::serve_tls(
+                self.base_handler.clone(),
+                self.config.clone(),
+                listener,
+                acceptor,
+            ));
+        }
+
+        self.serve(socket).await
+    }
+
+    /// Serve DNS-over-TLS connections arriving on the listener
+    pub async fn serve_tls(
+        base_handler: BaseHandler,
+        config: ServerConfig,
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+    ) {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Listening for TLS on {}", addr);
+        }
+
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Error accepting connection: {}", err);
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let base_handler = base_handler.clone();
+            let handle_timeout = config.handle_timeout;
+            let log_query_timing = config.log_query_timing;
+            let response_padding = config.response_padding;
+            let query_log = config.query_log.clone();
+            let idle_timeout = config.tls_idle_timeout;
+
+            // Each connection may carry several queries
+            tokio::spawn(async move {
+                let mut stream = match time::timeout(idle_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Err(_) => {
+                        info!("TLS handshake with {} timed out", addr);
+                        return;
+                    }
+                    Ok(Err(err)) => {
+                        base_handler
+                            .errors
+                            .report(format!("TLS handshake with {} failed: {}", addr, err));
+                        return;
+                    }
+                };
+
+                let mut connection = StreamConnection::new();
+
+                loop {
+                    let message =
+                        match time::timeout(idle_timeout, connection.read_message(&mut stream))
+                            .await
+                        {
+                            Ok(Ok(Some(message))) => message,
+                            // Closed by the client, or idle for too long
+                            Ok(Ok(None)) | Err(_) => return,
+                            Ok(Err(err)) => {
+                                base_handler.errors.report(format!(
+                                    "Error reading message from {}: {}",
+                                    addr, err
+                                ));
+                                return;
+                            }
+                        };
+
+                    let received = Instant::now();
+
+                    let request = Request::new(message).with_source(addr.ip());
+
+                    Server::log_message(request.message());
+
+                    let mut response =
+                        Server::respond(&base_handler, &request, handle_timeout).await;
+
+                    if let Some(block_size) = response_padding {
+                        response.pad(block_size);
+                    }
+
+                    Server::log_message(response.message());
+
+                    if let Err(err) = connection
+                        .write_message(&mut stream, response.message())
+                        .await
+                    {
+                        base_handler.errors.report(format!(
+                            "Error writing response {}: {}",
+                            request.id(),
+                            err
+                        ));
+                        return;
+                    }
+
+                    if log_query_timing {
+                        Server::log_timing(&request, &response, received);
+                    }
+
+                    if let Some(query_log) = &query_log {
+                        query_log.log(&request, &response);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Serve requests arriving on an already bound socket
+    pub async fn serve(self, socket: UdpSocket) -> ServerResult<()> {
+        Server::serve_udp(self.base_handler, self.config, socket).await;
+
+        Ok(())
+    }
+
+    /// Serve requests arriving on one of possibly several sockets sharing the handler
+    async fn serve_udp(base_handler: BaseHandler, config: ServerConfig, socket: UdpSocket) {
+        if let Ok(addr) = socket.local_addr() {
+            info!("Listening on {}", addr);
+        }
+
+        // Wrap socket in reference count for use in both async moves
+        let socket = Arc::new(socket);
+
+        // Requests are read one at a time, so share a buffer
+        let mut connection = Connection::new();
+
+        // Responses are written concurrently, each takes a connection from the pool and returns it
+        let writers: Arc<Mutex<Vec<Connection>>> = Arc::new(Mutex::new(Vec::new()));
+
+        loop {
+            // Get a reference counted copy of the sockets
+            let socket = socket.clone();
+            let writers = writers.clone();
+
+            // Wait for an incoming message, a malformed message shouldn't stop the server
+            let (addr, message) = match connection.read_message(&*socket).await {
+                Ok(read) => read,
+                Err(err) => {
+                    base_handler
+                        .errors
+                        .report(format!("Error reading message: {}", err));
+                    continue;
+                }
+            };
+
+            let received = Instant::now();
+
+            let base_handler = base_handler.clone();
+            let handle_timeout = config.handle_timeout;
+            let log_query_timing = config.log_query_timing;
+            let query_log = config.query_log.clone();
+            let max_udp_payload_size = config.max_udp_payload_size;
+
+            // Spawn a new task and move all scoped variables into the task
+            tokio::spawn(async move {
+                let request = Request::new(message).with_source(addr.ip());
+
+                Server::log_message(request.message());
+
+                let response = match base_handler.bad_cookie_response(&request) {
+                    Some(response) => response,
+                    None => Server::respond(&base_handler, &request, handle_timeout).await,
+                };
+
+                Server::log_message(response.message());
+
+                let mut writer = writers.lock().unwrap().pop().unwrap_or_default();
+
+                // Write response to socket
+                // Responses too large for the client's payload size are sent truncated
+                if let Some(err) = writer
+                    .write_message_within(
+                        &*socket,
+                        response.message(),
+                        &addr,
+                        request.udp_payload_size(max_udp_payload_size),
+                    )
+                    .await
+                    .err()
+                {
+                    base_handler.errors.report(format!(
+                        "Error writing response {}: {}",
+                        request.id(),
+                        err
+                    ));
+                } else if log_query_timing {
+                    Server::log_timing(&request, &response, received);
+                }
+
+                if let Some(query_log) = &query_log {
+                    query_log.log(&request, &response);
+                }
+
+                writers.lock().unwrap().push(writer);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    use super::*;
+    use crate::messages::packets::{EdnsOption, OptRecord, PacketType};
+
+    /// Spawn an upstream which delegates every query to a name server on itself
+    async fn spawn_self_referential_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+                let domain = message.questions[0].domain.clone();
+                let name_server = format!("ns.{}", domain);
+
+                message.packet_type = PacketType::Response;
+                message.answers = vec![
+                    ResourceRecord {
+                        domain,
+                        record_type: ResourceRecordType::NSRecord,
+                        class: ResourceRecordClass::InternetAddress,
+                        time_to_live: 300,
+                        data: ResourceRecordData::NS(name_server.clone()),
+                    },
+                    ResourceRecord {
+                        domain: name_server,
+                        record_type: ResourceRecordType::ARecord,
+                        class: ResourceRecordClass::InternetAddress,
+                        time_to_live: 300,
+                        data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+                    },
+                ];
+
+                Connection::new()
+                    .write_message(&socket, &message, &from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    /// Spawn an upstream answering every query with the given address after a delay
+    async fn spawn_stub_upstream(delay: Duration, address: Ipv4Addr) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+                time::sleep(delay).await;
+
+                message.packet_type = PacketType::Response;
+                message.answers = vec![ResourceRecord {
+                    domain: message.questions[0].domain.clone(),
+                    record_type: ResourceRecordType::ARecord,
+                    class: ResourceRecordClass::InternetAddress,
+                    time_to_live: 300,
+                    data: ResourceRecordData::A(address),
+                }];
+
+                Connection::new()
+                    .write_message(&socket, &message, &from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    /// In-memory upstream answering queries from a scripted set of records, so recursion can be
+    /// tested without network access
+    ///
+    /// Every scripted record matching the question's name and type is answered, with the scripted
+    /// addresses of any NS targets as glue. Questions with nothing scripted get NXDOMAIN. Any
+    /// name server address in the script should be `127.0.0.1`, with the resolver's
+    /// `name_server_port` set to the stub's port so referrals come back to it.
+    struct UpstreamStub {
+        records: HashMap<(String, ResourceRecordType), Vec<ResourceRecord>>,
+    }
+
+    impl UpstreamStub {
+        fn new() -> UpstreamStub {
+            UpstreamStub {
+                records: HashMap::new(),
+            }
+        }
+
+        fn with_record(mut self, domain: &str, data: ResourceRecordData) -> Self {
+            self.records
+                .entry((domain.to_string(), data.get_type()))
+                .or_default()
+                .push(ResourceRecord {
+                    domain: domain.to_string(),
+                    record_type: data.get_type(),
+                    class: ResourceRecordClass::InternetAddress,
+                    time_to_live: 300,
+                    data,
+                });
+            self
+        }
+
+        fn answer(&self, mut message: Message) -> Message {
+            let question = message.questions[0].clone();
+
+            message.packet_type = PacketType::Response;
+
+            let answers = match self
+                .records
+                .get(&(question.domain.clone(), question.question_type.clone()))
+            {
+                Some(answers) => answers.clone(),
+                None => {
+                    message.response_code = ResponseCode::NameError;
+                    return message;
+                }
+            };
+
+            message.additional_records = answers
+                .iter()
+                .filter_map(|record| match &record.data {
+                    ResourceRecordData::NS(target) => self
+                        .records
+                        .get(&(target.clone(), ResourceRecordType::ARecord)),
+                    _ => None,
+                })
+                .flatten()
+                .cloned()
+                .collect();
+            message.answers = answers;
+
+            message
+        }
+
+        /// Serve the script on an ephemeral port, returning its address
+        async fn spawn(self) -> SocketAddr {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+
+            tokio::spawn(async move {
+                loop {
+                    let (from, message) = Connection::new().read_message(&socket).await.unwrap();
+
+                    Connection::new()
+                        .write_message(&socket, &self.answer(message), &from)
+                        .await
+                        .unwrap();
+                }
+            });
+
+            addr
+        }
+    }
+
+    fn query(domain: &str) -> Request {
+        request(Question::new(domain, ResourceRecordType::ARecord))
+    }
+
+    fn request(question: Question) -> Request {
+        Request::new(query_message(question))
+    }
+
+    fn query_message(question: Question) -> Message {
+        Message {
+            id: 1,
+            packet_type: PacketType::Query,
+            op_code: 0,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![question],
+            answers: vec![],
+            authorities: vec![],
+            additional_records: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_respond_times_out_with_server_error() {
+        // Upstream that never responds
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ServerConfig::default()
+            .with_root_server(upstream.local_addr().unwrap())
+            .with_handle_timeout(Duration::from_millis(100));
+
+        let base_handler = BaseHandler::new(&config);
+
+        let start = Instant::now();
+        let response =
+            Server::respond(&base_handler, &query("example.com."), config.handle_timeout).await;
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(matches!(
+            response.message().response_code,
+            ResponseCode::ServerError
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_static_record_answered_authoritatively() {
+        let record = ResourceRecord {
+            domain: "dev.local.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+
+        let config = ServerConfig::default().with_static_record(record);
+        let base_handler = BaseHandler::new(&config);
+
+        let request = query("dev.local.");
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert!(response.message().authoritative_answer);
+        assert_eq!(response.message().answers.len(), 1);
+        assert_eq!(
+            response.message().answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_internationalized_static_name_matches_question() {
+        let config = ServerConfig::default().with_static_data(
+            "münchen.local.",
+            ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        );
+        let base_handler = BaseHandler::new(&config);
+
+        let request = query("MÜNCHEN.local");
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert_eq!(response.message().answers.len(), 1);
+        assert_eq!(
+            response.message().answers[0].domain,
+            "xn--mnchen-3ya.local."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reverse_zone_answers_ptr() {
+        let zone_file = crate::zone::parse_zone(
+            "$ORIGIN 3.2.1.IN-ADDR.ARPA.\n\
+             $TTL 3600\n\
+             4 IN PTR host.example.com.\n",
+        )
+        .unwrap();
+
+        let config = ServerConfig::default().with_zone(zone_file);
+        let base_handler = BaseHandler::new(&config);
+
+        // Matched regardless of case
+        let request = request(Question::new(
+            "4.3.2.1.in-addr.arpa.",
+            ResourceRecordType::PTRRecord,
+        ));
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert!(response.message().authoritative_answer);
+        assert_eq!(response.message().answers.len(), 1);
+        assert_eq!(response.message().answers[0].time_to_live, 3600);
+        assert_eq!(
+            response.message().answers[0].data,
+            ResourceRecordData::PTR("host.example.com.".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zone_apex_ns_and_soa_answered_authoritatively() {
+        let zone_file = crate::zone::parse_zone(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             @ IN SOA ns1 hostmaster 2024010101 7200 3600 1209600 300\n\
+             @ IN NS ns1\n\
+             @ IN NS ns2\n\
+             ns1 IN A 192.0.2.1\n",
+        )
+        .unwrap();
+
+        // Upstream that never responds, the apex must be answered without it
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ServerConfig::default()
+            .with_root_server(upstream.local_addr().unwrap())
+            .with_handle_timeout(Duration::from_millis(500))
+            .with_zone(zone_file);
+        let base_handler = BaseHandler::new(&config);
+
+        for (question_type, answers) in [
+            (ResourceRecordType::NSRecord, 2),
+            (ResourceRecordType::SOARecord, 1),
+        ] {
+            let request = request(Question::new("EXAMPLE.com", question_type.clone()));
+            let response = Server::respond(&base_handler, &request, config.handle_timeout).await;
+            let message = response.message();
+
+            assert_eq!(message.response_code, ResponseCode::None);
+            assert!(message.authoritative_answer);
+            assert_eq!(message.answers.len(), answers);
+            assert!(message.answers.iter().all(
+                |record| record.domain == "example.com." && record.record_type == question_type
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_handler_takes_precedence_over_recursion() {
+        let upstream = spawn_stub_upstream(Duration::ZERO, Ipv4Addr::new(2, 2, 2, 2)).await;
+
+        let record = ResourceRecord {
+            domain: "dev.local.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+
+        let config = ServerConfig::default()
+            .with_forwarder(upstream)
+            .with_static_record(record);
+        let base_handler = BaseHandler::new(&config);
+
+        // Answered by the static records handler, the upstream is never asked
+        let request = query("dev.local.");
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert!(response.message().authoritative_answer);
+        assert_eq!(
+            response.message().answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1))
+        );
+
+        // Passed through to the upstream
+        let request = query("example.com.");
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert!(!response.message().authoritative_answer);
+        assert_eq!(
+            response.message().answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(2, 2, 2, 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_carries_client_subnet() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        // Upstream answering with a scope covering the client's /24
+        tokio::spawn(async move {
+            let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+            let mut subnet = message.client_subnet().cloned().unwrap();
+            sender.send(subnet.clone()).unwrap();
+
+            subnet.scope_prefix_length = 24;
+            message.opt_mut().unwrap().options = vec![EdnsOption::ClientSubnet(subnet)];
+
+            message.packet_type = PacketType::Response;
+            message.answers = vec![ResourceRecord {
+                domain: message.questions[0].domain.clone(),
+                record_type: ResourceRecordType::ARecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 300,
+                data: ResourceRecordData::A(Ipv4Addr::new(2, 2, 2, 2)),
+            }];
+
+            Connection::new()
+                .write_message(&socket, &message, &from)
+                .await
+                .unwrap();
+        });
+
+        let config = ServerConfig::default()
+            .with_forwarder(upstream)
+            .with_client_subnet(24, 56);
+        let base_handler = BaseHandler::new(&config);
+
+        let request = query("example.com.").with_source("192.0.2.77".parse().unwrap());
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert_eq!(response.message().answers.len(), 1);
+
+        // Truncated to the configured prefix length
+        let subnet = receiver.recv().await.unwrap();
+        assert_eq!(subnet.source_prefix_length, 24);
+        assert_eq!(subnet.address, "192.0.2.0".parse::<IpAddr>().unwrap());
+
+        // Scoped to the client's subnet, so not cached for others
+        assert!(base_handler
+            .cache
+            .get(ResourceRecordType::ARecord, "example.com.")
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn test_client_subnet_left_out_for_internal_sources() {
+        let base_handler = BaseHandler::new(&ServerConfig::default().with_client_subnet(24, 56));
+
+        for source in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(
+                base_handler
+                    .client_subnet_options(Some(source.parse().unwrap()))
+                    .is_empty(),
+                "{}",
+                source
+            );
+        }
+
+        for source in ["192.0.2.77", "2001:db8::1"] {
+            assert_eq!(
+                base_handler
+                    .client_subnet_options(Some(source.parse().unwrap()))
+                    .len(),
+                1
+            );
+        }
+
+        // A zero length prefix carries nothing about the client
+        let base_handler = BaseHandler::new(&ServerConfig::default().with_client_subnet(0, 0));
+
+        assert!(base_handler
+            .client_subnet_options(Some("192.0.2.77".parse().unwrap()))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forward_preserves_unknown_options() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+
+        // Upstream answering with padding and an option from the experimental range
+        tokio::spawn(async move {
+            let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+            let mut opt = OptRecord::new(1232);
+            opt.options = vec![
+                EdnsOption::Unknown(12, vec![0; 8]),
+                EdnsOption::Unknown(65001, vec![1, 2, 3]),
+            ];
+
+            message.packet_type = PacketType::Response;
+            message.additional_records = vec![opt.to_resource_record()];
+            message.answers = vec![ResourceRecord {
+                domain: message.questions[0].domain.clone(),
+                record_type: ResourceRecordType::ARecord,
+                class: ResourceRecordClass::InternetAddress,
+                time_to_live: 300,
+                data: ResourceRecordData::A(Ipv4Addr::new(2, 2, 2, 2)),
+            }];
+
+            Connection::new()
+                .write_message(&socket, &message, &from)
+                .await
+                .unwrap();
+        });
+
+        let base_handler = BaseHandler::new(&ServerConfig::default().with_forwarder(upstream));
+
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let request = Request::new(message);
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        // Padding only applies to the upstream's own hop
+        assert_eq!(
+            response.message().opt().unwrap().options,
+            vec![EdnsOption::Unknown(65001, vec![1, 2, 3])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_non_recursive_miss_returns_cached_referral() {
+        let base_handler = BaseHandler::new(&ServerConfig::default());
+
+        let name_server = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::NSRecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data: ResourceRecordData::NS("ns1.example.com.".to_string()),
+        };
+        let glue = ResourceRecord {
+            domain: "ns1.example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data: ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+
+        base_handler
+            .cache
+            .put_resource_records(&[name_server.clone(), glue.clone()])
+            .await;
+
+        let mut message = query_message(Question::new(
+            "www.sub.example.com.",
+            ResourceRecordType::ARecord,
+        ));
+        message.recursion_desired = false;
+
+        let request = Request::new(message);
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert_eq!(response.message().response_code, ResponseCode::None);
+        assert!(response.message().answers.is_empty());
+        assert_eq!(response.message().authorities, vec![name_server]);
+        assert_eq!(response.message().additional_records, vec![glue]);
+    }
+
+    #[tokio::test]
+    async fn test_recursion_through_upstream_stub() {
+        let upstream = UpstreamStub::new()
+            .with_record(
+                "com.",
+                ResourceRecordData::NS("a.gtld-servers.net.".to_string()),
+            )
+            .with_record(
+                "a.gtld-servers.net.",
+                ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .with_record(
+                "example.com.",
+                ResourceRecordData::NS("a.iana-servers.net.".to_string()),
+            )
+            .with_record(
+                "a.iana-servers.net.",
+                ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .with_record(
+                "example.com.",
+                ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)),
+            )
+            .spawn()
+            .await;
+
+        let config = ServerConfig::default().with_root_server(upstream);
+        let mut base_handler = BaseHandler::new(&config);
+        base_handler.name_server_port = upstream.port();
+        let request = query("example.com.");
+
+        let response = Server::respond(&base_handler, &request, config.handle_timeout).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::None);
+        assert_eq!(
+            response.message().answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_referrals_use_name_server_port() {
+        let root = UpstreamStub::new()
+            .with_record(
+                "com.",
+                ResourceRecordData::NS("a.gtld-servers.net.".to_string()),
+            )
+            .with_record(
+                "a.gtld-servers.net.",
+                ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .spawn()
+            .await;
+
+        let referred = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ServerConfig::default()
+            .with_root_server(root)
+            .with_handle_timeout(Duration::from_millis(200));
+
+        let mut base_handler = BaseHandler::new(&config);
+        base_handler.name_server_port = referred.local_addr().unwrap().port();
+
+        let request = query("example.com.");
+
+        tokio::spawn(async move {
+            Server::respond(&base_handler, &request, config.handle_timeout).await;
+        });
+
+        // The root's own port isn't kept for the name server it refers to
+        let (_, message) = time::timeout(
+            Duration::from_secs(1),
+            Connection::new().read_message(&referred),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(message.questions[0].domain, "example.com.");
+    }
+
+    #[tokio::test]
+    async fn test_stale_answer_served_when_recursion_fails() {
+        // Recursion fails straight away without following any referral
+        let config = ServerConfig::default()
+            .with_root_server(SocketAddr::from(([127, 0, 0, 1], 53)))
+            .with_max_referrals(0)
+            .with_serve_stale(Duration::from_secs(3600));
+
+        let base_handler = BaseHandler::new(&config);
+
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)),
+        };
+        base_handler.cache.put_expiring(&record, -1).await;
+
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let response =
+            Server::respond(&base_handler, &Request::new(message), config.handle_timeout).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::None);
+        assert_eq!(response.message().answers.len(), 1);
+        assert_eq!(response.message().answers[0].data, record.data);
+        assert!(response.message().answers[0].time_to_live <= 30);
+        assert!(matches!(
+            response.message().opt().unwrap().options.as_slice(),
+            [EdnsOption::ExtendedError(ExtendedErrorCode::StaleAnswer, _)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stale_answer_served_when_upstream_silent() {
+        // Bound but never answered
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ServerConfig::default()
+            .with_root_server(upstream.local_addr().unwrap())
+            .with_handle_timeout(Duration::from_secs(2))
+            .with_serve_stale(Duration::from_secs(3600))
+            .with_stale_answer_timeout(Duration::from_millis(100));
+
+        let base_handler = BaseHandler::new(&config);
+
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)),
+        };
+        base_handler.cache.put_expiring(&record, -1).await;
+
+        let started = Instant::now();
+        let response =
+            Server::respond(&base_handler, &query("example.com."), config.handle_timeout).await;
+
+        assert!(started.elapsed() < config.handle_timeout);
+        assert_eq!(response.message().response_code, ResponseCode::None);
+        assert_eq!(response.message().answers[0].data, record.data);
+    }
+
+    #[tokio::test]
+    async fn test_stale_answer_refreshed_in_background() {
+        let upstream =
+            spawn_stub_upstream(Duration::from_millis(300), Ipv4Addr::new(2, 2, 2, 2)).await;
+
+        let config = ServerConfig::default()
+            .with_forwarder(upstream)
+            .with_handle_timeout(Duration::from_secs(2))
+            .with_serve_stale(Duration::from_secs(3600))
+            .with_stale_answer_timeout(Duration::from_millis(100));
+
+        let base_handler = BaseHandler::new(&config);
+
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(1, 1, 1, 1)),
+        };
+        base_handler.cache.put_expiring(&record, -1).await;
+
+        let response =
+            Server::respond(&base_handler, &query("example.com."), config.handle_timeout).await;
+
+        assert_eq!(response.message().answers[0].data, record.data);
+
+        // The slow resolution carries on after the stale answer and refreshes the entry
+        time::sleep(Duration::from_millis(600)).await;
+
+        let refreshed = base_handler
+            .cache
+            .get(ResourceRecordType::ARecord, "example.com.")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            refreshed[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(2, 2, 2, 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recursion_stops_after_max_referrals() {
+        let upstream = spawn_self_referential_upstream().await;
+
+        let config = ServerConfig::default()
+            .with_root_server(upstream)
+            .with_max_referrals(3);
+
+        let mut base_handler = BaseHandler::new(&config);
+        base_handler.name_server_port = upstream.port();
+
+        let err = base_handler
+            .recurse_request("a.b.c.d.e.example.com.")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<RecurseError>(),
+            Some(RecurseError::MaxReferralsError(3))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_chaos_version_bind() {
+        let config = ServerConfig::default().with_version("test-version");
+        let base_handler = BaseHandler::new(&config);
+
+        let request = request(Question {
+            domain: "version.bind.".to_string(),
+            question_type: ResourceRecordType::TXTRecord,
+            class: QuestionClass::Chaos,
+        });
+
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert_eq!(response.message().answers.len(), 1);
+        assert!(matches!(
+            response.message().answers[0].class,
+            ResourceRecordClass::Chaos
+        ));
+        assert_eq!(
+            response.message().answers[0].data,
+            ResourceRecordData::TXT(vec![b"test-version".to_vec()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_respond_timeout_sets_extended_error() {
+        // Upstream that never responds
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ServerConfig::default()
+            .with_root_server(upstream.local_addr().unwrap())
+            .with_handle_timeout(Duration::from_millis(100));
+
+        let base_handler = BaseHandler::new(&config);
+
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let response =
+            Server::respond(&base_handler, &Request::new(message), config.handle_timeout).await;
+
+        let opt = response.message().opt().unwrap();
+
+        assert!(matches!(
+            opt.options.as_slice(),
+            [EdnsOption::ExtendedError(
+                ExtendedErrorCode::NoReachableAuthority,
+                _
+            )]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handler_error_detail_not_sent() {
+        // Upstream answering with bytes that don't decode, so forwarding fails
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut datagram = [0; 512];
+
+            loop {
+                let (_, from) = socket.recv_from(&mut datagram).await.unwrap();
+                socket.send_to(&[0, 1, 2], from).await.unwrap();
+            }
+        });
+
+        let config = ServerConfig::default().with_forwarder(upstream);
+        let base_handler = BaseHandler::new(&config);
+
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let response =
+            Server::respond(&base_handler, &Request::new(message), config.handle_timeout).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::ServerError);
+        assert!(base_handler.errors.count() > 0);
+
+        let opt = response.message().opt().unwrap();
+
+        assert!(matches!(
+            opt.options.as_slice(),
+            [EdnsOption::ExtendedError(ExtendedErrorCode::Other, text)] if text.is_empty()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_question_type_not_implemented() {
+        // Upstream that never responds, it must not be consulted
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ServerConfig::default()
+            .with_root_server(upstream.local_addr().unwrap())
+            .with_handle_timeout(Duration::from_millis(500));
+        let base_handler = BaseHandler::new(&config);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // A query for example.com. with the unassigned QTYPE 0xFF01
+        let mut query = vec![0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00\xFF\x01\x00\x01");
+        client
+            .send_to(&query, server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (from, message) = Connection::new().read_message(&server).await.unwrap();
+
+        assert_eq!(
+            message.questions[0].question_type,
+            ResourceRecordType::Unimplemented(0xFF01)
+        );
+
+        let response =
+            Server::respond(&base_handler, &Request::new(message), config.handle_timeout).await;
+
+        assert_eq!(response.message().response_code.to_u16(), 4);
+        assert!(response.message().answers.is_empty());
+        assert!(base_handler
+            .cache
+            .get(ResourceRecordType::Unimplemented(0xFF01), "example.com.")
+            .await
+            .is_none());
+
+        Connection::new()
+            .write_message(&server, response.message(), &from)
+            .await
+            .unwrap();
+
+        let mut reply = [0; 512];
+        let (len, _) = client.recv_from(&mut reply).await.unwrap();
+
+        // The question is echoed exactly as it was asked
+        assert!(len >= query.len());
+        assert_eq!(reply[3] & 0x0F, 4);
+        assert_eq!(&reply[12..query.len()], &query[12..]);
+    }
+
+    #[tokio::test]
+    async fn test_authoritative_only_answers_static_records() {
+        let record = ResourceRecord {
+            domain: "dev.local.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+
+        let config = ServerConfig::default()
+            .with_recursion(false)
+            .with_static_record(record);
+        let base_handler = BaseHandler::new(&config);
+
+        let response =
+            Server::respond(&base_handler, &query("dev.local."), config.handle_timeout).await;
+
+        assert!(!response.message().recursion_available);
+        assert!(response.message().authoritative_answer);
+        assert_eq!(response.message().response_code, ResponseCode::None);
+        assert_eq!(response.message().answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_authoritative_only_refuses_recursion() {
+        // Upstream that never responds, it must not be consulted
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ServerConfig::default()
+            .with_recursion(false)
+            .with_root_server(upstream.local_addr().unwrap());
+        let base_handler = BaseHandler::new(&config);
+
+        let response =
+            Server::respond(&base_handler, &query("example.com."), config.handle_timeout).await;
+
+        assert!(!response.message().recursion_available);
+        assert!(!response.message().authoritative_answer);
+        assert_eq!(response.message().response_code, ResponseCode::Refused);
+        assert!(response.message().answers.is_empty());
+    }
+
+    fn cookie_request(client_cookie: &[u8], server_cookie: &[u8]) -> Request {
+        let mut opt = OptRecord::new(1232);
+        opt.options.push(EdnsOption::Cookie(
+            client_cookie.to_vec(),
+            server_cookie.to_vec(),
+        ));
+
+        let mut message = query_message(Question {
+            domain: "version.bind.".to_string(),
+            question_type: ResourceRecordType::TXTRecord,
+            class: QuestionClass::Chaos,
+        });
+        message.additional_records.push(opt.to_resource_record());
+
+        Request::new(message).with_source(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    #[tokio::test]
+    async fn test_respond_echoes_cookie() {
+        let config = ServerConfig::default();
+        let base_handler = BaseHandler::new(&config);
+
+        let request = cookie_request(&[1, 2, 3, 4, 5, 6, 7, 8], &[]);
+
+        let response = Server::respond(&base_handler, &request, config.handle_timeout).await;
+
+        let opt = response.message().opt().unwrap();
+
+        match opt.options.as_slice() {
+            [EdnsOption::Cookie(client, server)] => {
+                assert_eq!(client, &[1, 2, 3, 4, 5, 6, 7, 8]);
+                assert!((16..=40).contains(&(client.len() + server.len())));
+            }
+            options => panic!("Expected a cookie, found {:?}", options),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_without_question_is_format_error() {
+        let config = ServerConfig::default();
+        let base_handler = BaseHandler::new(&config);
+
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message.questions.clear();
+
+        let request = Request::new(message);
+        let response = Server::respond(&base_handler, &request, config.handle_timeout).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::FormatError);
+        assert!(response.message().answers.is_empty());
+
+        // A cookie probe carries no question either, but is answered
+        let mut message = cookie_request(&[1, 2, 3, 4, 5, 6, 7, 8], &[])
+            .message()
+            .clone();
+        message.questions.clear();
+
+        let request = Request::new(message).with_source(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let response = Server::respond(&base_handler, &request, config.handle_timeout).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::None);
+        assert!(response.message().questions.is_empty());
+        assert!(matches!(
+            response.message().opt().unwrap().options.as_slice(),
+            [EdnsOption::Cookie(..)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_required_cookie_rejects_invalid_server_cookie() {
+        let config = ServerConfig::default().with_required_cookies(true);
+        let base_handler = BaseHandler::new(&config);
+
+        let client_cookie = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let response = base_handler
+            .bad_cookie_response(&cookie_request(&client_cookie, &[0; 8]))
+            .unwrap();
+
+        assert!(matches!(
+            response.message().response_code,
+            ResponseCode::BadCookie
+        ));
+        assert!(response.message().truncation);
+
+        // Retrying with the issued server cookie is accepted
+        let server_cookie = match response.message().opt().unwrap().options.as_slice() {
+            [EdnsOption::Cookie(_, server)] => server.clone(),
+            options => panic!("Expected a cookie, found {:?}", options),
+        };
+
+        assert!(base_handler
+            .bad_cookie_response(&cookie_request(&client_cookie, &server_cookie))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_root_ns_answered_from_hints() {
+        let config = ServerConfig::default();
+        let base_handler = BaseHandler::new(&config);
+
+        let request = request(Question::new(".", ResourceRecordType::NSRecord));
+
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        let answers = &response.message().answers;
+
+        assert_eq!(answers.len(), 13);
+        assert!(answers.iter().all(|answer| answer.domain == "."));
+        assert_eq!(
+            answers[0].data,
+            ResourceRecordData::NS("a.root-servers.net.".to_string())
+        );
+        assert_eq!(
+            response.message().additional_records[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(198, 41, 0, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recursion_starts_from_root_hints() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let path = std::env::temp_dir().join(format!("dn-mess-hints-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "; test root\n.\t3600000\tNS\ttest-root.\ntest-root.\t{}\n",
+                upstream.local_addr().unwrap()
+            ),
+        )
+        .unwrap();
+
+        let config = ServerConfig::default()
+            .with_root_hints(RootHints::load(&path).unwrap())
+            .with_handle_timeout(Duration::from_millis(200));
+
+        std::fs::remove_file(&path).unwrap();
+
+        let base_handler = BaseHandler::new(&config);
+        let request = query("example.com");
+
+        tokio::spawn(async move {
+            Server::respond(&base_handler, &request, config.handle_timeout).await;
+        });
+
+        // The first query of the recursion arrives at the hinted root
+        let mut buf = [0; 512];
+        let (length, _) = time::timeout(Duration::from_secs(1), upstream.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(length > 0);
+    }
+
+    #[test]
+    fn test_root_hints_parse_named_root() {
+        let root_hints = RootHints::parse(
+            ";       This file holds the information on root name servers\n\
+             .                        3600000      NS    A.ROOT-SERVERS.NET.\n\
+             A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4\n\
+             A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30\n",
+        )
+        .unwrap();
+
+        let hints = root_hints.hints();
+
+        assert_eq!(hints.len(), 2);
+        assert_eq!(hints[0].name, "a.root-servers.net.");
+        assert_eq!(hints[0].addr, SocketAddr::from(([198, 41, 0, 4], 53)));
+        assert_eq!(hints[1].addr.port(), 53);
+    }
+
+    #[tokio::test]
+    async fn test_race_forwarders_answers_with_fastest() {
+        let slow = spawn_stub_upstream(Duration::from_millis(500), Ipv4Addr::new(1, 1, 1, 1)).await;
+        let fast = spawn_stub_upstream(Duration::ZERO, Ipv4Addr::new(2, 2, 2, 2)).await;
+
+        let config = ServerConfig::default()
+            .with_forwarder(slow)
+            .with_forwarder(fast)
+            .with_forward_mode(ForwardMode::Race);
+
+        let base_handler = BaseHandler::new(&config);
+        let request = query("example.com");
+
+        let started = Instant::now();
+
+        let response = base_handler
+            .handle(&request, request.response())
+            .await
+            .unwrap();
+
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(
+            response.message().answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(2, 2, 2, 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_questions_resolved_once() {
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        // Upstream counting the queries it receives
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        let counter = queries.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                message.packet_type = PacketType::Response;
+
+                Connection::new()
+                    .write_message(&socket, &message, &from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let base_handler = BaseHandler::new(&ServerConfig::default().with_forwarder(upstream));
+
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message.questions = (0..50)
+            .map(|index| {
+                let mut question = Question::new("example.com.", ResourceRecordType::ARecord);
+
+                // Repeats differing only in case are the same question
+                if index % 2 == 1 {
+                    question.domain = "EXAMPLE.com.".to_string();
+                }
+
+                question
+            })
+            .collect();
+
+        let request = Request::new(message.clone());
+        assert_eq!(request.questions().len(), 1);
+
+        let response = Server::respond(&base_handler, &request, Duration::from_secs(1)).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::None);
+        assert_eq!(response.message().questions.len(), 1);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // Too many distinct questions are refused without any work
+        message.questions = (0..=MAX_QUESTIONS)
+            .map(|index| {
+                Question::new(
+                    &format!("host{}.example.com.", index),
+                    ResourceRecordType::ARecord,
+                )
+            })
+            .collect();
+
+        let request = Request::new(message);
+        let response = Server::respond(&base_handler, &request, Duration::from_secs(1)).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::FormatError);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_queries_answered_with_reason() {
+        let base_handler = BaseHandler::new(&ServerConfig::default());
+
+        // A status query isn't supported
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message.op_code = 2;
+
+        let request = Request::new(message);
+        let response = Server::respond(&base_handler, &request, Duration::from_secs(1)).await;
+
+        assert_eq!(response.message().id, request.id());
+        assert_eq!(response.message().op_code, 2);
+        assert_eq!(
+            response.message().response_code,
+            ResponseCode::NotImplemented
+        );
+
+        // A query carrying answers is malformed, they aren't echoed back
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message.answers.push(ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        });
+
+        let request = Request::new(message);
+        let response = Server::respond(&base_handler, &request, Duration::from_secs(1)).await;
+
+        assert_eq!(response.message().response_code, ResponseCode::FormatError);
+        assert!(response.message().answers.is_empty());
+
+        // A class other than IN and CH isn't supported, the question is echoed as asked
+        let mut question = Question::new("example.com.", ResourceRecordType::ARecord);
+        question.class = QuestionClass::Unimplemented(0x00FE);
+
+        let request = Request::new(query_message(question.clone()));
+        let response = Server::respond(&base_handler, &request, Duration::from_secs(1)).await;
+
+        assert_eq!(
+            response.message().response_code,
+            ResponseCode::NotImplemented
+        );
+        assert_eq!(response.message().questions, vec![question]);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_refreshes_popular_entry_once() {
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        // Upstream counting the queries it receives
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        let counter = queries.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                message.packet_type = PacketType::Response;
+
+                Connection::new()
+                    .write_message(&socket, &message, &from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let config = ServerConfig::default()
+            .with_forwarder(upstream)
+            .with_prefetch(3);
+
+        let base_handler = BaseHandler::new(&config);
+
+        // Five seconds left of an hour
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+        base_handler.cache.put_expiring(&record, 5).await;
+
+        let request = query("example.com");
+
+        for _ in 0..10 {
+            let response = base_handler
+                .handle(&request, request.response())
+                .await
+                .unwrap();
+
+            assert_eq!(response.message().answers.len(), 1);
+        }
+
+        time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_prefetch_released() {
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        // Upstream failing every query it receives
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        let counter = queries.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                message.packet_type = PacketType::Response;
+                message.response_code = ResponseCode::ServerError;
+
+                Connection::new()
+                    .write_message(&socket, &message, &from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let config = ServerConfig::default()
+            .with_forwarder(upstream)
+            .with_prefetch(3);
+
+        let base_handler = BaseHandler::new(&config);
+
+        // Five seconds left of an hour
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+        base_handler.cache.put_expiring(&record, 5).await;
+
+        let request = query("example.com");
+
+        // The entry is still near expiry after the failed refresh, so is refreshed again
+        for _ in 0..2 {
+            for _ in 0..5 {
+                base_handler
+                    .handle(&request, request.response())
+                    .await
+                    .unwrap();
+            }
+
+            time::sleep(Duration::from_millis(200)).await;
+        }
+
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_one_query() {
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        // Slow upstream counting the queries it receives
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        let counter = queries.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (from, mut message) = Connection::new().read_message(&socket).await.unwrap();
+
+                counter.fetch_add(1, Ordering::SeqCst);
+                time::sleep(Duration::from_millis(100)).await;
+
+                message.packet_type = PacketType::Response;
+                message.answers = vec![ResourceRecord {
+                    domain: message.questions[0].domain.clone(),
+                    record_type: ResourceRecordType::ARecord,
+                    class: ResourceRecordClass::InternetAddress,
+                    time_to_live: 300,
+                    data: ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)),
+                }];
+
+                Connection::new()
+                    .write_message(&socket, &message, &from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let config = ServerConfig::default().with_forwarder(upstream);
+        let base_handler = BaseHandler::new(&config);
+
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let base_handler = base_handler.clone();
+
+                tokio::spawn(async move {
+                    let request = query("example.com.");
+                    Server::respond(&base_handler, &request, Duration::from_secs(2)).await
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            let response = task.await.unwrap();
+
+            assert_eq!(
+                response.message().answers[0].data,
+                ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34))
+            );
+        }
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_timing_line() {
+        let request = query("example.com");
+
+        let mut response = request.response();
+        response.set_code(ResponseCode::NameError);
+
+        assert_eq!(
+            Server::timing_line(&request, &response, Duration::from_micros(12_345)),
+            "Answered example.com. with NameError in 12.345ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_only_on_bound_address() {
+        let record = ResourceRecord {
+            domain: "dev.local.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+
+        let server = Server::new(ServerConfig::default().with_static_record(record), vec![]).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let _ = server.serve(socket).await;
+        });
+
+        // Served on the bound address
+        let client = Client::dial(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .unwrap();
+        let response = client
+            .query("dev.local.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.answers.len(), 1);
+
+        // Not served on another local address
+        let client = Client::dial(SocketAddr::from(([127, 0, 0, 2], port)))
+            .await
+            .unwrap();
+        let response = time::timeout(
+            Duration::from_millis(200),
+            client.query("dev.local.", ResourceRecordType::ARecord),
+        )
+        .await;
+
+        assert!(!matches!(response, Ok(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_rotates_answers() {
+        let mut config = ServerConfig::default().with_answer_order(AnswerOrder::RoundRobin);
+
+        for host in 1..=3 {
+            config = config.with_static_data(
+                "pool.local.",
+                ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, host)),
+            );
+        }
+
+        let base_handler = BaseHandler::new(&config);
+        let request = query("pool.local.");
+
+        let mut orders = vec![];
+
+        for _ in 0..4 {
+            let response = Server::respond(&base_handler, &request, Duration::from_secs(1)).await;
+
+            let hosts: Vec<u8> = response
+                .message()
+                .answers
+                .iter()
+                .map(|answer| answer.data.as_ipv4().unwrap().octets()[3])
+                .collect();
+
+            orders.push(hosts);
+        }
+
+        assert_eq!(
+            orders,
+            vec![vec![1, 2, 3], vec![2, 3, 1], vec![3, 1, 2], vec![1, 2, 3]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_static_answers_carry_configured_ttl() {
+        let record = ResourceRecord {
+            domain: "pinned.local.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 2)),
+        };
+
+        let config = ServerConfig::default()
+            .with_synthesized_ttl(120)
+            .with_static_data(
+                "dev.local.",
+                ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .with_static_record(record);
+        let server = Server::new(config, vec![]).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let _ = server.serve(socket).await;
+        });
+
+        let client = Client::dial(addr).await.unwrap();
+
+        // Without a TTL of its own the configured default is used
+        let response = client
+            .query("dev.local.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.answers[0].time_to_live, 120);
+
+        // A TTL given with the record is kept
+        let response = client
+            .query("pinned.local.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.answers[0].time_to_live, 3600);
+    }
+
+    /// Answers A queries for one name with a fixed address
+    struct RewriteHandler {
+        domain: String,
+        address: Ipv4Addr,
+    }
+
+    impl Handler for RewriteHandler {
+        fn handle<'a>(
+            &'a self,
+            request: &'a Request,
+            mut response: Response,
+        ) -> handler::HandlerFuture<'a> {
+            Box::pin(async move {
+                match request.questions().first() {
+                    Some(question) if question.domain == self.domain => {
+                        response.set_answers(vec![ResourceRecord {
+                            domain: question.domain.clone(),
+                            record_type: ResourceRecordType::ARecord,
+                            class: ResourceRecordClass::InternetAddress,
+                            time_to_live: 60,
+                            data: ResourceRecordData::A(self.address),
+                        }]);
+
+                        Ok(Handled::Answered(response))
+                    }
+                    _ => Ok(Handled::Pass(response)),
+                }
+            })
+        }
+    }
+
+    /// Answers every query with an address, the name server it came from and its glue
+    struct DelegatedHandler;
+
+    impl Handler for DelegatedHandler {
+        fn handle<'a>(
+            &'a self,
+            request: &'a Request,
+            mut response: Response,
+        ) -> handler::HandlerFuture<'a> {
+            Box::pin(async move {
+                let domain = request.questions()[0].domain.clone();
+
+                response.set_answers(vec![ResourceRecord {
+                    domain: domain.clone(),
+                    record_type: ResourceRecordType::ARecord,
+                    class: ResourceRecordClass::InternetAddress,
+                    time_to_live: 60,
+                    data: ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 10)),
+                }]);
+                response.set_authorities(vec![ResourceRecord {
+                    domain,
+                    record_type: ResourceRecordType::NSRecord,
+                    class: ResourceRecordClass::InternetAddress,
+                    time_to_live: 3600,
+                    data: ResourceRecordData::NS("ns1.example.com.".to_string()),
+                }]);
+                response.add_additional_record(ResourceRecord {
+                    domain: "ns1.example.com.".to_string(),
+                    record_type: ResourceRecordType::ARecord,
+                    class: ResourceRecordClass::InternetAddress,
+                    time_to_live: 3600,
+                    data: ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                });
+
+                Ok(Handled::Answered(response))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_minimal_responses_drop_authorities() {
+        let mut message = query_message(Question::new("example.com.", ResourceRecordType::ARecord));
+        message
+            .additional_records
+            .push(OptRecord::new(1232).to_resource_record());
+
+        let request = Request::new(message);
+
+        for minimal_responses in [false, true] {
+            let base_handler = BaseHandler::with_handlers(
+                &ServerConfig::default().with_minimal_responses(minimal_responses),
+                vec![Box::new(DelegatedHandler)],
+            );
+
+            let response = Server::respond(&base_handler, &request, Duration::from_secs(1)).await;
+            let message = response.message();
+
+            assert_eq!(message.answers.len(), 1);
+            assert_eq!(message.authorities.is_empty(), minimal_responses);
+            assert_eq!(
+                message.additional_records.len(),
+                if minimal_responses { 1 } else { 2 }
+            );
+
+            // The EDNS record is always kept
+            assert!(message.opt().is_some());
+        }
+    }
+
+    /// Fails every query
+    struct FailingHandler;
+
+    impl Handler for FailingHandler {
+        fn handle<'a>(&'a self, _: &'a Request, _: Response) -> handler::HandlerFuture<'a> {
+            Box::pin(async move { Err("backend unavailable".into()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_errors_reach_task_errors() {
+        let server = Server::new(ServerConfig::default(), vec![Box::new(FailingHandler)]).await;
+        let errors = server.task_errors();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let _ = server.serve(socket).await;
+        });
+
+        let client = Client::dial(addr).await.unwrap();
+        let response = client
+            .query("example.com.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.response_code, ResponseCode::ServerError);
+
+        // The error was recorded before the response was sent
+        assert_eq!(errors.count(), 1);
+        assert_eq!(
+            errors.last().unwrap().message,
+            "Handler error: backend unavailable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_min_response_time_delays_cache_hits() {
+        // Upstream that never responds, answers can only come from the cache
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let record = ResourceRecord {
+            domain: "example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 3600,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+
+        for min_response_time in [None, Some(Duration::from_millis(200))] {
+            let mut config =
+                ServerConfig::default().with_root_server(upstream.local_addr().unwrap());
+
+            if let Some(min_response_time) = min_response_time {
+                config = config.with_min_response_time(min_response_time);
+            }
+
+            let base_handler = BaseHandler::new(&config);
+            base_handler.cache.put_expiring(&record, 3600).await;
+
+            let start = Instant::now();
+            let response =
+                Server::respond(&base_handler, &query("example.com."), config.handle_timeout).await;
+
+            assert_eq!(response.message().answers.len(), 1);
+
+            match min_response_time {
+                Some(min_response_time) => assert!(start.elapsed() >= min_response_time),
+                None => assert!(start.elapsed() < Duration::from_millis(200)),
+            }
+        }
+    }
+
+    /// Query log destination passing on everything written to it
+    struct ChannelLog(tokio::sync::mpsc::UnboundedSender<Vec<u8>>);
+
+    impl std::io::Write for ChannelLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_log_line_per_query() {
+        let (writer, mut written) = tokio::sync::mpsc::unbounded_channel();
+
+        let config = ServerConfig::default()
+            .with_static_data(
+                "dev.local.",
+                ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .with_query_log(query_log::QueryLogger::new(
+                query_log::QueryLogFormat::Tsv,
+                Box::new(ChannelLog(writer)),
+            ));
+        let server = Server::new(config, vec![]).await;
+
+        // Answered from the cache, without recursing
+        server
+            .cache()
+            .put_expiring(
+                &ResourceRecord {
+                    domain: "example.com.".to_string(),
+                    record_type: ResourceRecordType::ARecord,
+                    class: ResourceRecordClass::InternetAddress,
+                    time_to_live: 300,
+                    data: ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                },
+                300,
+            )
+            .await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let _ = server.serve(socket).await;
+        });
+
+        let client = Client::dial(addr).await.unwrap();
+
+        for domain in ["dev.local.", "example.com."] {
+            client
+                .query(domain, ResourceRecordType::ARecord)
+                .await
+                .unwrap();
+        }
+
+        // Lines are written after the responses are sent, wait until both have been
+        let mut log = String::new();
+
+        while log.lines().count() < 2 {
+            let bytes = time::timeout(Duration::from_secs(1), written.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            log.push_str(std::str::from_utf8(&bytes).unwrap());
+        }
+
+        let lines: Vec<Vec<&str>> = log.lines().map(|line| line.split('\t').collect()).collect();
+
+        assert_eq!(lines.len(), 2);
+
+        for (fields, (domain, cached)) in lines
+            .iter()
+            .zip([("dev.local.", "false"), ("example.com.", "true")])
+        {
+            assert!(chrono::DateTime::parse_from_rfc3339(fields[0]).is_ok());
+            assert_eq!(fields[1..], ["127.0.0.1", domain, "A", "None", "1", cached]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_response_up_to_advertised_payload_size() {
+        let mut config = ServerConfig::default();
+
+        for index in 0..4 {
+            config = config.with_static_data(
+                "big.local.",
+                ResourceRecordData::TXT(vec![index.to_string().repeat(200).into_bytes()]),
+            );
+        }
+
+        let server = Server::new(config, vec![]).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let _ = server.serve(socket).await;
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = [0u8; 4096];
+
+        for payload_size in [None, Some(4096)] {
+            let mut message =
+                query_message(Question::new("big.local.", ResourceRecordType::TXTRecord));
+
+            if let Some(payload_size) = payload_size {
+                message
+                    .additional_records
+                    .push(OptRecord::new(payload_size).to_resource_record());
+            }
+
+            Connection::new()
+                .write_message(&client, &message, &addr)
+                .await
+                .unwrap();
+
+            let (len, _) = client.recv_from(&mut datagram).await.unwrap();
+            let truncated = datagram[2] & 0x02 != 0;
+            let answer_count = u16::from_be_bytes([datagram[6], datagram[7]]);
+
+            match payload_size {
+                // Only the classic 512 bytes without EDNS
+                None => {
+                    assert!(len <= 512);
+                    assert!(truncated);
+                    assert_eq!(answer_count, 0);
+                }
+                // Capped at the configured size, which is advertised back
+                Some(_) => {
+                    assert!(len > 512 && len <= 1232);
+                    assert!(!truncated);
+                    assert_eq!(answer_count, 4);
+
+                    // The OPT record's root owner and type, then 1232 in place of its class
+                    assert!(datagram[..len]
+                        .windows(5)
+                        .any(|window| window == [0x00, 0x00, 0x29, 0x04, 0xD0]));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_handler_rewrites_answers() {
+        let record = ResourceRecord {
+            domain: "dev.local.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+
+        let rewrite = RewriteHandler {
+            domain: "dev.local.".to_string(),
+            address: Ipv4Addr::new(10, 0, 0, 1),
+        };
+
+        let server = Server::new(
+            ServerConfig::default().with_static_record(record),
+            vec![Box::new(rewrite)],
+        )
+        .await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let _ = server.serve(socket).await;
+        });
+
+        // The custom handler runs ahead of the static records
+        let client = Client::dial(addr).await.unwrap();
+        let response = client
+            .query("dev.local.", ResourceRecordType::ARecord)
+            .await
+            .unwrap();
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn test_tls_listener_bound_to_listen_address() {
+        let mut config = ServerConfig::default().with_tls("cert.pem".into(), "key.pem".into());
+        let tls_config = config.tls.as_mut().unwrap();
+
+        assert_eq!(tls_config.port, 853);
+
+        tls_config.port = 0;
+        let tls_config = tls_config.clone();
+
+        let server = Server::new(config, vec![]).await;
+        let listener = server
+            .bind_tls_listener(&tls_config, "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        // Only reachable on the address the server was asked to listen on
+        assert_eq!(
+            listener.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reuse_port_listeners_share_queries() {
+        let config = ServerConfig::default().with_listeners(2).with_static_data(
+            "dev.local.",
+            ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        );
+        let server = Server::new(config.clone(), vec![]).await;
+
+        let sockets = server
+            .bind_listeners("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = sockets[0].local_addr().unwrap();
+
+        assert_eq!(sockets.len(), 2);
+        assert_eq!(sockets[1].local_addr().unwrap(), addr);
+
+        // Queries from many source ports, so the kernel spreads them over both sockets
+        let mut clients = vec![];
+
+        for _ in 0..32 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            Connection::new()
+                .write_message(
+                    &client,
+                    &query_message(Question::new("dev.local.", ResourceRecordType::ARecord)),
+                    &addr,
+                )
+                .await
+                .unwrap();
+
+            clients.push(client);
+        }
+
+        // Each socket has queries waiting before any are served
+        for socket in &sockets {
+            let mut buf = [0; 512];
+
+            time::timeout(Duration::from_secs(1), socket.peek_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        for socket in sockets {
+            tokio::spawn(Server::serve_udp(
+                server.base_handler.clone(),
+                config.clone(),
+                socket,
+            ));
+        }
+
+        // Every query is answered whichever socket it arrived on
+        for client in &clients {
+            let (from, response) = time::timeout(
+                Duration::from_secs(1),
+                Connection::new().read_message(client),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+            assert_eq!(from, addr);
+            assert_eq!(
+                response.answers[0].data,
+                ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls() {
+        use tokio::io::AsyncReadExt;
+
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let directory = std::env::temp_dir().join(format!("dn-mess-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let certificate_path = directory.join("cert.pem");
+        let key_path = directory.join("key.pem");
+        std::fs::write(&certificate_path, certificate.cert.pem()).unwrap();
+        std::fs::write(&key_path, certificate.key_pair.serialize_pem()).unwrap();
+
+        let record = ResourceRecord {
+            domain: "dev.local.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+
+        let config = ServerConfig::default()
+            .with_static_record(record)
+            .with_tls(certificate_path, key_path)
+            .with_tls_idle_timeout(Duration::from_millis(100));
+
+        let acceptor = tls::load_acceptor(config.tls.as_ref().unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(Server::serve_tls(
+            BaseHandler::new(&config),
+            config.clone(),
+            listener,
+            acceptor,
+        ));
+
+        // Client trusting only the self signed certificate
+        let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+        roots.add(certificate.cert.der().clone()).unwrap();
+
+        let client_config = tokio_rustls::rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+
+        let mut connection = StreamConnection::new();
+        connection
+            .write_message(
+                &mut stream,
+                &query_message(Question::new("dev.local.", ResourceRecordType::ARecord)),
+            )
+            .await
+            .unwrap();
+
+        let response = connection.read_message(&mut stream).await.unwrap().unwrap();
+
+        // Connections are closed once idle, or stalled in the handshake
+        let mut buf = [0; 1];
+        let read = time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(read.map_or(true, |length| length == 0));
+
+        let mut stalled = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let read = time::timeout(Duration::from_secs(1), stalled.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(read.map_or(true, |length| length == 0));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(response.is_response());
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(127, 0, 0, 1))
+        );
     }
 }
@@ -0,0 +1,207 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::error;
+
+use crate::messages::{Request, Response};
+
+/// Layout of each query log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryLogFormat {
+    /// One JSON object per line
+    JsonLines,
+
+    /// Tab separated fields in a fixed order, `-` for a missing field
+    ///
+    /// ```text
+    /// timestamp  client  name  type  rcode  answers  cached
+    /// ```
+    Tsv,
+}
+
+/// Writes a line for every answered query, for machines rather than people to read
+///
+/// Each line has the time, the client's address, the name and type asked for, the response code,
+/// the number of answers and whether they came from the cache. Lines are written by a thread of
+/// their own, so a slow disk doesn't hold up the tasks answering queries.
+pub struct QueryLogger {
+    format: QueryLogFormat,
+    lines: Sender<String>,
+}
+
+/// Quote a string as JSON
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for character in value.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            character if character.is_control() => {
+                quoted.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => quoted.push(character),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Write lines as they arrive until the logger is dropped, flushing once no more are waiting
+fn write_lines(lines: Receiver<String>, writer: Box<dyn Write + Send>) {
+    let mut writer = BufWriter::new(writer);
+
+    while let Ok(line) = lines.recv() {
+        let result = std::iter::once(line)
+            .chain(lines.try_iter())
+            .try_for_each(|line| writeln!(writer, "{}", line))
+            .and_then(|_| writer.flush());
+
+        if let Err(err) = result {
+            error!("Error writing query log: {}", err);
+        }
+    }
+}
+
+impl QueryLogger {
+    pub fn new(format: QueryLogFormat, writer: Box<dyn Write + Send>) -> QueryLogger {
+        let (lines, receiver) = mpsc::channel();
+
+        thread::spawn(move || write_lines(receiver, writer));
+
+        QueryLogger { format, lines }
+    }
+
+    pub fn stdout(format: QueryLogFormat) -> QueryLogger {
+        QueryLogger::new(format, Box::new(io::stdout()))
+    }
+
+    /// Append to the file at the path, creating it if needed
+    pub fn file(path: &Path, format: QueryLogFormat) -> io::Result<QueryLogger> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(QueryLogger::new(format, Box::new(file)))
+    }
+
+    /// Format the line logged for the answered request
+    pub fn line(&self, time: DateTime<Utc>, request: &Request, response: &Response) -> String {
+        let timestamp = time.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let client = request.source().map(|source| source.to_string());
+        let question = request.questions().first();
+        let name = question.map(|question| question.domain.as_str());
+        let record_type = question.map(|question| question.question_type.mnemonic());
+        let response_code = format!("{:?}", response.message().response_code);
+        let answers = response.message().answers.len();
+        let cached = response.is_cached();
+
+        match self.format {
+            QueryLogFormat::JsonLines => {
+                let optional =
+                    |value: Option<&str>| value.map(json_string).unwrap_or("null".into());
+
+                format!(
+                    "{{\"timestamp\":{},\"client\":{},\"name\":{},\"type\":{},\"rcode\":{},\
+                     \"answers\":{},\"cached\":{}}}",
+                    json_string(&timestamp),
+                    optional(client.as_deref()),
+                    optional(name),
+                    optional(record_type.as_deref()),
+                    json_string(&response_code),
+                    answers,
+                    cached
+                )
+            }
+            QueryLogFormat::Tsv => [
+                timestamp.as_str(),
+                client.as_deref().unwrap_or("-"),
+                // Labels may hold tabs and newlines, which would break up the line
+                &name.map_or("-".into(), |name| name.replace(char::is_control, "?")),
+                record_type.as_deref().unwrap_or("-"),
+                &response_code,
+                &answers.to_string(),
+                &cached.to_string(),
+            ]
+            .join("\t"),
+        }
+    }
+
+    /// Queue the line for the answered request to be written, failures are logged rather than
+    /// returned so a full disk doesn't stop queries being answered
+    pub fn log(&self, request: &Request, response: &Response) {
+        if self
+            .lines
+            .send(self.line(Utc::now(), request, response))
+            .is_err()
+        {
+            error!("Query log writer has stopped");
+        }
+    }
+}
+
+impl fmt::Debug for QueryLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QueryLogger({:?})", self.format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::packets::{Message, Question, ResourceRecordType};
+
+    #[test]
+    fn test_line_formats() {
+        let request = Request::new(Message::query(
+            1,
+            Question::new("example.com.", ResourceRecordType::AAAARecord),
+        ))
+        .with_source("192.0.2.1".parse().unwrap());
+
+        let mut response = request.response();
+        response.set_cached(true);
+
+        let time: DateTime<Utc> = "2024-01-02T03:04:05Z".parse().unwrap();
+
+        assert_eq!(
+            QueryLogger::new(QueryLogFormat::JsonLines, Box::new(io::sink()))
+                .line(time, &request, &response),
+            "{\"timestamp\":\"2024-01-02T03:04:05.000Z\",\"client\":\"192.0.2.1\",\
+             \"name\":\"example.com.\",\"type\":\"AAAA\",\"rcode\":\"None\",\"answers\":0,\
+             \"cached\":true}"
+        );
+
+        // No source or question
+        let request = Request::new(Message::default());
+
+        assert_eq!(
+            QueryLogger::new(QueryLogFormat::Tsv, Box::new(io::sink())).line(
+                time,
+                &request,
+                &request.response()
+            ),
+            "2024-01-02T03:04:05.000Z\t-\t-\t-\tNone\t0\tfalse"
+        );
+
+        let request = Request::new(Message::query(
+            1,
+            Question::new("a\tb.example.", ResourceRecordType::ARecord),
+        ));
+
+        assert!(QueryLogger::new(QueryLogFormat::Tsv, Box::new(io::sink()))
+            .line(time, &request, &request.response())
+            .contains("\ta?b.example.\t"));
+    }
+
+    #[test]
+    fn test_json_string_escapes() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+}
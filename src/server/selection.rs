@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+/// How long a name server is deprioritized after failing to respond
+const FAILURE_PENALTY: Duration = Duration::from_secs(60);
+
+/// Picks name servers at random, steering away from those that recently failed
+#[derive(Debug, Default)]
+pub struct NameServerSelector {
+    failures: Mutex<HashMap<String, Instant>>,
+}
+
+impl NameServerSelector {
+    pub fn new() -> NameServerSelector {
+        NameServerSelector {
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record that the named server failed to respond
+    ///
+    /// Failures whose penalty has passed are dropped, so only recent failures are kept.
+    pub fn record_failure(&self, name: &str) {
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.retain(|_, failed| failed.elapsed() < FAILURE_PENALTY);
+            failures.insert(name.to_lowercase(), Instant::now());
+        }
+    }
+
+    fn recently_failed(&self, name: &str) -> bool {
+        match self.failures.lock() {
+            Ok(failures) => failures
+                .get(&name.to_lowercase())
+                .is_some_and(|failed| failed.elapsed() < FAILURE_PENALTY),
+            Err(_) => false,
+        }
+    }
+
+    /// Choose the index of a candidate by name and SRV style weight (RFC 2782)
+    ///
+    /// Candidates that recently failed are only chosen if every candidate has.
+    pub fn choose(&self, candidates: &[(&str, u16)]) -> Option<usize> {
+        let live: Vec<usize> = (0..candidates.len())
+            .filter(|index| !self.recently_failed(candidates[*index].0))
+            .collect();
+
+        let pool: Vec<usize> = if live.is_empty() {
+            (0..candidates.len()).collect()
+        } else {
+            live
+        };
+
+        // Offset weights by one so zero weighted candidates still have a chance
+        let total: u32 = pool
+            .iter()
+            .map(|index| candidates[*index].1 as u32 + 1)
+            .sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = rand::thread_rng().gen_range(0..total);
+
+        for index in pool {
+            let weight = candidates[index].1 as u32 + 1;
+
+            if point < weight {
+                return Some(index);
+            }
+
+            point -= weight;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_varies_between_candidates() {
+        let selector = NameServerSelector::new();
+        let candidates = [("a.example.", 0), ("b.example.", 0), ("c.example.", 0)];
+
+        let chosen: Vec<usize> = (0..100)
+            .map(|_| selector.choose(&candidates).unwrap())
+            .collect();
+
+        assert!(chosen.iter().any(|index| *index != 0));
+    }
+
+    #[test]
+    fn test_choose_avoids_failed_candidates() {
+        let selector = NameServerSelector::new();
+        let candidates = [("a.example.", 0), ("b.example.", 0)];
+
+        selector.record_failure("a.example.");
+
+        for _ in 0..100 {
+            assert_eq!(selector.choose(&candidates), Some(1));
+        }
+
+        // With every candidate failed, they are all back in the running
+        selector.record_failure("b.example.");
+
+        assert!(selector.choose(&candidates).is_some());
+    }
+
+    #[test]
+    fn test_expired_failures_dropped() {
+        let selector = NameServerSelector::new();
+
+        if let Some(failed) = Instant::now().checked_sub(FAILURE_PENALTY) {
+            selector
+                .failures
+                .lock()
+                .unwrap()
+                .insert("a.example.".to_string(), failed);
+        }
+
+        selector.record_failure("b.example.");
+
+        let failures = selector.failures.lock().unwrap();
+
+        assert_eq!(failures.len(), 1);
+        assert!(failures.contains_key("b.example."));
+    }
+
+    #[test]
+    fn test_choose_honors_weights() {
+        let selector = NameServerSelector::new();
+        let candidates = [("light.example.", 0), ("heavy.example.", 999)];
+
+        let heavy = (0..1000)
+            .filter(|_| selector.choose(&candidates) == Some(1))
+            .count();
+
+        assert!(heavy > 900);
+    }
+
+    #[test]
+    fn test_choose_without_candidates() {
+        assert_eq!(NameServerSelector::new().choose(&[]), None);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{io, net::SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use super::config::SocketOptions;
+
+/// Bind a UDP socket with the options applied before binding
+///
+/// `SO_REUSEPORT` is only set on platforms supporting it, elsewhere it's ignored.
+pub fn bind_udp(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if options.reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    if options.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+
+    if let Some(size) = options.receive_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use socket2::SockRef;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_with_buffer_sizes() {
+        let options = SocketOptions {
+            receive_buffer_size: Some(1 << 20),
+            send_buffer_size: Some(1 << 18),
+            ..SocketOptions::default()
+        };
+
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let socket_ref = SockRef::from(&socket);
+
+        // The OS may round the sizes or cap them at its own limits, but they're applied
+        assert!(socket_ref.recv_buffer_size().unwrap() > 0);
+        assert!(socket_ref.send_buffer_size().unwrap() > 0);
+        assert!(socket.local_addr().unwrap().port() > 0);
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn test_reuse_port_allows_several_listeners() {
+        let options = SocketOptions {
+            reuse_address: true,
+            reuse_port: true,
+            ..SocketOptions::default()
+        };
+
+        let first = bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = first.local_addr().unwrap();
+
+        let second = bind_udp(addr, &options).unwrap();
+
+        assert!(SockRef::from(&second).reuse_port().unwrap());
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        // Without the option the port is taken
+        assert!(bind_udp(addr, &SocketOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{net::IpAddr, str::FromStr};
+
+use crate::messages::{packets::ResourceRecord, Request, Response};
+
+use super::{
+    config::{StaticRecords, DEFAULT_SYNTHESIZED_TTL},
+    errors::SubnetError,
+    handler::{Handled, Handler, HandlerFuture},
+};
+
+/// An address range in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_length: u8,
+}
+
+impl Subnet {
+    pub fn new(addr: IpAddr, prefix_length: u8) -> Result<Subnet, SubnetError> {
+        let max_length = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix_length > max_length {
+            return Err(SubnetError::InvalidPrefixError(prefix_length.to_string()));
+        }
+
+        Ok(Subnet {
+            addr,
+            prefix_length,
+        })
+    }
+
+    /// Whether the address falls in this subnet, IPv4 and IPv6 subnets never overlap
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(subnet), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_length as u32)
+                    .unwrap_or(0);
+
+                u32::from(subnet) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(subnet), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_length as u32)
+                    .unwrap_or(0);
+
+                u128::from(subnet) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = SubnetError;
+
+    /// Parse CIDR notation, a bare address is a subnet of just that address
+    fn from_str(value: &str) -> Result<Subnet, SubnetError> {
+        let (addr, prefix_length) = match value.split_once('/') {
+            Some((addr, prefix_length)) => (addr, Some(prefix_length)),
+            None => (value, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| SubnetError::InvalidAddressError(addr.to_string()))?;
+
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .parse()
+                .map_err(|_| SubnetError::InvalidPrefixError(prefix_length.to_string()))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+
+        Subnet::new(addr, prefix_length)
+    }
+}
+
+/// Records answered to clients in any of the view's subnets
+#[derive(Debug, Clone)]
+struct View {
+    subnets: Vec<Subnet>,
+    records: StaticRecords,
+}
+
+/// Answers the same names differently depending on the client's address
+///
+/// Views are checked in the order they were added, the first with a subnet containing the client
+/// and a record for the question answers it. Other requests, and requests without a known
+/// source, are passed on.
+#[derive(Debug, Clone, Default)]
+pub struct SplitHorizonHandler {
+    views: Vec<View>,
+}
+
+impl SplitHorizonHandler {
+    pub fn new() -> SplitHorizonHandler {
+        SplitHorizonHandler::default()
+    }
+
+    /// Add a view answering the records to clients in the given subnets
+    pub fn with_view(mut self, subnets: Vec<Subnet>, records: Vec<ResourceRecord>) -> Self {
+        let mut static_records = StaticRecords::new();
+
+        for record in records {
+            static_records.insert(record);
+        }
+
+        self.views.push(View {
+            subnets,
+            records: static_records,
+        });
+        self
+    }
+}
+
+impl Handler for SplitHorizonHandler {
+    fn handle<'a>(&'a self, request: &'a Request, mut response: Response) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let (question, source) = match (request.questions().first(), request.source()) {
+                (Some(question), Some(source)) => (question, source),
+                _ => return Ok(Handled::Pass(response)),
+            };
+
+            let records = self
+                .views
+                .iter()
+                .filter(|view| view.subnets.iter().any(|subnet| subnet.contains(source)))
+                .find_map(|view| {
+                    // Every view record has its own TTL
+                    view.records.get(
+                        &question.question_type,
+                        &question.domain,
+                        DEFAULT_SYNTHESIZED_TTL,
+                    )
+                });
+
+            match records {
+                Some(records) => {
+                    response.set_answers(records);
+                    response.set_authoritative(true);
+
+                    Ok(Handled::Answered(response))
+                }
+                None => Ok(Handled::Pass(response)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::messages::packets::{
+        Message, PacketType, Question, ResourceRecordClass, ResourceRecordData, ResourceRecordType,
+        ResponseCode,
+    };
+
+    fn query(source: &str) -> Request {
+        Request::new(Message {
+            id: 1,
+            packet_type: PacketType::Query,
+            op_code: 0,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::None,
+            questions: vec![Question::new(
+                "intranet.example.com.",
+                ResourceRecordType::ARecord,
+            )],
+            answers: vec![],
+            authorities: vec![],
+            additional_records: vec![],
+        })
+        .with_source(source.parse().unwrap())
+    }
+
+    fn record(address: Ipv4Addr) -> ResourceRecord {
+        ResourceRecord {
+            domain: "intranet.example.com.".to_string(),
+            record_type: ResourceRecordType::ARecord,
+            class: ResourceRecordClass::InternetAddress,
+            time_to_live: 300,
+            data: ResourceRecordData::A(address),
+        }
+    }
+
+    async fn answer(handler: &SplitHorizonHandler, source: &str) -> Option<ResourceRecordData> {
+        let request = query(source);
+
+        match handler.handle(&request, request.response()).await.unwrap() {
+            Handled::Answered(response) => Some(response.message().answers[0].data.clone()),
+            Handled::Pass(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_internal_and_external_answers() {
+        let handler = SplitHorizonHandler::new()
+            .with_view(
+                vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+                vec![record(Ipv4Addr::new(10, 0, 0, 1))],
+            )
+            .with_view(
+                vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()],
+                vec![record(Ipv4Addr::new(93, 184, 216, 34))],
+            );
+
+        assert_eq!(
+            answer(&handler, "10.20.30.40").await,
+            Some(ResourceRecordData::A(Ipv4Addr::new(10, 0, 0, 1)))
+        );
+        assert_eq!(
+            answer(&handler, "fd12::1").await,
+            Some(ResourceRecordData::A(Ipv4Addr::new(10, 0, 0, 1)))
+        );
+        assert_eq!(
+            answer(&handler, "192.0.2.1").await,
+            Some(ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)))
+        );
+        assert_eq!(
+            answer(&handler, "2001:db8::1").await,
+            Some(ResourceRecordData::A(Ipv4Addr::new(93, 184, 216, 34)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_client_passes() {
+        let handler = SplitHorizonHandler::new().with_view(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec![record(Ipv4Addr::new(10, 0, 0, 1))],
+        );
+
+        assert_eq!(answer(&handler, "192.0.2.1").await, None);
+    }
+
+    #[test]
+    fn test_subnet_parse_and_contains() {
+        let subnet: Subnet = "192.168.1.0/24".parse().unwrap();
+
+        assert!(subnet.contains("192.168.1.200".parse().unwrap()));
+        assert!(!subnet.contains("192.168.2.1".parse().unwrap()));
+        assert!(!subnet.contains("::ffff:192.168.1.1".parse().unwrap()));
+
+        let host: Subnet = "192.0.2.1".parse().unwrap();
+        assert!(host.contains("192.0.2.1".parse().unwrap()));
+        assert!(!host.contains("192.0.2.2".parse().unwrap()));
+
+        assert!(matches!(
+            "10.0.0.0/33".parse::<Subnet>(),
+            Err(SubnetError::InvalidPrefixError(_))
+        ));
+        assert!(matches!(
+            "example/8".parse::<Subnet>(),
+            Err(SubnetError::InvalidAddressError(_))
+        ));
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use log::error;
+
+/// Number of recent errors kept, older errors are only counted
+const RECENT_ERRORS: usize = 32;
+
+/// An error raised while serving a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskError {
+    pub time: SystemTime,
+    pub message: String,
+}
+
+/// Errors raised by the tasks serving requests, for the embedding application to observe
+///
+/// Tasks only log their errors, they are also recorded here so failure rates can be watched and
+/// alerted on. Errors are kept as their description, as the errors themselves aren't `Send`.
+#[derive(Debug, Default)]
+pub struct TaskErrors {
+    count: AtomicU64,
+    recent: Mutex<VecDeque<TaskError>>,
+}
+
+impl TaskErrors {
+    pub fn new() -> TaskErrors {
+        TaskErrors::default()
+    }
+
+    pub fn record(&self, message: String) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        let mut recent = match self.recent.lock() {
+            Ok(recent) => recent,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if recent.len() == RECENT_ERRORS {
+            recent.pop_front();
+        }
+
+        recent.push_back(TaskError {
+            time: SystemTime::now(),
+            message,
+        });
+    }
+
+    /// Log the error and record it, so what is logged and what is recorded can't differ
+    pub fn report(&self, message: String) {
+        error!("{}", message);
+        self.record(message);
+    }
+
+    /// Number of errors recorded since the server started
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The most recent errors, oldest first
+    pub fn recent(&self) -> Vec<TaskError> {
+        match self.recent.lock() {
+            Ok(recent) => recent.iter().cloned().collect(),
+            Err(poisoned) => poisoned.into_inner().iter().cloned().collect(),
+        }
+    }
+
+    pub fn last(&self) -> Option<TaskError> {
+        self.recent().pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_errors_bounded() {
+        let errors = TaskErrors::new();
+
+        for index in 0..RECENT_ERRORS + 3 {
+            errors.record(format!("error {}", index));
+        }
+
+        let recent = errors.recent();
+
+        assert_eq!(errors.count(), RECENT_ERRORS as u64 + 3);
+        assert_eq!(recent.len(), RECENT_ERRORS);
+        assert_eq!(recent[0].message, "error 3");
+        assert_eq!(
+            errors.last().unwrap().message,
+            format!("error {}", RECENT_ERRORS + 2)
+        );
+    }
+
+    #[test]
+    fn test_report_records() {
+        let errors = TaskErrors::new();
+
+        errors.report("Handler error: refused".to_string());
+
+        assert_eq!(errors.count(), 1);
+        assert_eq!(errors.last().unwrap().message, "Handler error: refused");
+    }
+}
@@ -0,0 +1,27 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use tokio_rustls::{rustls, TlsAcceptor};
+
+use super::{config::TlsConfig, errors::TlsError};
+
+type TlsResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Build a TLS acceptor from the PEM encoded certificate chain and private key in the config
+pub fn load_acceptor(config: &TlsConfig) -> TlsResult<TlsAcceptor> {
+    let certificates =
+        rustls_pemfile::certs(&mut BufReader::new(File::open(&config.certificate_path)?))
+            .collect::<Result<Vec<_>, _>>()?;
+
+    if certificates.is_empty() {
+        return Err(Box::new(TlsError::NoCertificateError));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&config.key_path)?))?
+        .ok_or(TlsError::NoPrivateKeyError)?;
+
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
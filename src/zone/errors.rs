@@ -0,0 +1,62 @@
+use core::fmt;
+
+#[derive(Debug)]
+pub enum ZoneParserError {
+    NoOrigin,
+    NoTtl,
+    NoDomain,
+    NoType,
+    InvalidType,
+    UnsupportedType(String),
+    InvalidTtl(String),
+    InvalidData(String),
+    UnterminatedString,
+    UnbalancedParentheses,
+    /// An owner name which isn't the origin or below it, only raised when parsing strictly
+    OutOfZone(String),
+    /// An error on the given line of the file, the first line of a multi-line record
+    At {
+        line: usize,
+        source: Box<ZoneParserError>,
+    },
+}
+
+impl ZoneParserError {
+    /// Attach the line the error occurred on
+    pub fn at(self, line: usize) -> ZoneParserError {
+        ZoneParserError::At {
+            line,
+            source: Box::new(self),
+        }
+    }
+}
+
+impl std::error::Error for ZoneParserError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ZoneParserError::At { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ZoneParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoneParserError::NoOrigin => write!(f, "No origin provided"),
+            ZoneParserError::NoTtl => write!(f, "No TTL provided"),
+            ZoneParserError::NoDomain => write!(f, "No domain provided"),
+            ZoneParserError::NoType => write!(f, "No type provided"),
+            ZoneParserError::InvalidType => write!(f, "Invalid type provided"),
+            ZoneParserError::UnsupportedType(value) => {
+                write!(f, "Unsupported type provided: {}", value)
+            }
+            ZoneParserError::InvalidTtl(value) => write!(f, "Invalid TTL: {}", value),
+            ZoneParserError::InvalidData(value) => write!(f, "Invalid record data: {}", value),
+            ZoneParserError::UnterminatedString => write!(f, "Unterminated quoted string"),
+            ZoneParserError::UnbalancedParentheses => write!(f, "Unbalanced parentheses"),
+            ZoneParserError::OutOfZone(value) => write!(f, "Name outside the zone: {}", value),
+            ZoneParserError::At { line, source } => write!(f, "Line {}: {}", line, source),
+        }
+    }
+}
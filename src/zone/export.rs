@@ -0,0 +1,169 @@
+use crate::messages::packets::{ResourceRecord, ResourceRecordData, ResourceRecordType, SOARecord};
+
+/// Shorten a name relative to the origin, the origin itself becoming `@`
+fn relative_name(name: &str, origin: &str) -> String {
+    if name.eq_ignore_ascii_case(origin) {
+        return String::from("@");
+    }
+
+    let suffix = format!(".{}", origin);
+
+    match name.len().checked_sub(suffix.len()) {
+        Some(index) if index > 0 && name[index..].eq_ignore_ascii_case(&suffix) => {
+            name[..index].to_string()
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Shorten any names held in the record data relative to the origin
+fn relative_data(data: &ResourceRecordData, origin: &str) -> ResourceRecordData {
+    match data {
+        ResourceRecordData::CName(value) => ResourceRecordData::CName(relative_name(value, origin)),
+        ResourceRecordData::NS(value) => ResourceRecordData::NS(relative_name(value, origin)),
+        ResourceRecordData::PTR(value) => ResourceRecordData::PTR(relative_name(value, origin)),
+        ResourceRecordData::MX(preference, exchange) => {
+            ResourceRecordData::MX(*preference, relative_name(exchange, origin))
+        }
+        ResourceRecordData::SRV {
+            priority,
+            weight,
+            port,
+            target,
+        } => ResourceRecordData::SRV {
+            priority: *priority,
+            weight: *weight,
+            port: *port,
+            target: relative_name(target, origin),
+        },
+        ResourceRecordData::SOA(value) => ResourceRecordData::SOA(SOARecord {
+            master_name: relative_name(&value.master_name, origin),
+            mail_name: relative_name(&value.mail_name, origin),
+            ..value.clone()
+        }),
+        data => data.clone(),
+    }
+}
+
+/// Render the records of a zone as a master file
+///
+/// The SOA comes first, the remaining records are grouped by name then type in the order they
+/// first appear. Names are written relative to the origin.
+pub fn export_zone(origin: &str, ttl: u32, records: &[ResourceRecord]) -> String {
+    let mut zone = format!("$ORIGIN {}\n$TTL {}\n", origin, ttl);
+
+    let (soa_records, other_records): (Vec<&ResourceRecord>, Vec<&ResourceRecord>) = records
+        .iter()
+        .partition(|record| record.record_type == ResourceRecordType::SOARecord);
+
+    // Names and their types in order of first appearance
+    let mut groups: Vec<(&str, Vec<&ResourceRecordType>)> = vec![];
+
+    for record in other_records.iter() {
+        let index = match groups
+            .iter()
+            .position(|(domain, _)| domain.eq_ignore_ascii_case(&record.domain))
+        {
+            Some(index) => index,
+            None => {
+                groups.push((&record.domain, vec![]));
+                groups.len() - 1
+            }
+        };
+
+        if !groups[index].1.contains(&&record.record_type) {
+            groups[index].1.push(&record.record_type);
+        }
+    }
+
+    let mut ordered = soa_records;
+
+    for (domain, record_types) in groups {
+        for record_type in record_types {
+            ordered.extend(other_records.iter().filter(|record| {
+                record.domain.eq_ignore_ascii_case(domain) && record.record_type == *record_type
+            }));
+        }
+    }
+
+    for record in ordered {
+        let relative = ResourceRecord {
+            domain: relative_name(&record.domain, origin),
+            data: relative_data(&record.data, origin),
+            ..record.clone()
+        };
+
+        zone.push_str(&relative.to_presentation());
+        zone.push('\n');
+    }
+
+    zone
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::zone::parse_zone;
+
+    const ZONE: &str = "$ORIGIN example.com.
+$TTL 3600
+www IN A 192.0.2.10
+@ IN NS ns1
+@ IN SOA ns1 hostmaster (
+    2024010101 ; serial
+    7200       ; refresh
+    3600       ; retry
+    1209600    ; expire
+    300 )      ; minimum
+ns1 IN A 192.0.2.1
+@ IN MX 10 mail.example.net.
+www 300 IN AAAA 2001:db8::10
+@ IN NS ns2.example.net.
+alias IN CNAME www
+@ IN TXT \"v=spf1 -all\" \"say \\\"hi\\\"\"
+_sip._tcp IN SRV 10 60 5060 www
+";
+
+    #[test]
+    fn test_export_round_trip() {
+        let zone_file = parse_zone(ZONE).unwrap();
+
+        let exported = export_zone("example.com.", 3600, zone_file.records());
+
+        let reparsed = parse_zone(&exported).unwrap();
+
+        let records: HashSet<&ResourceRecord> = zone_file.records().iter().collect();
+        let reparsed_records: HashSet<&ResourceRecord> = reparsed.records().iter().collect();
+
+        assert_eq!(reparsed.records().len(), zone_file.records().len());
+        assert_eq!(records, reparsed_records);
+    }
+
+    #[test]
+    fn test_export_orders_and_shortens() {
+        let zone_file = parse_zone(ZONE).unwrap();
+
+        let exported = export_zone("example.com.", 3600, zone_file.records());
+        let lines: Vec<&str> = exported.lines().collect();
+
+        assert_eq!(lines[0], "$ORIGIN example.com.");
+        assert_eq!(lines[1], "$TTL 3600");
+        assert_eq!(lines[2], "@ 3600 IN SOA ns1 hostmaster (");
+        assert_eq!(
+            &lines[9..],
+            [
+                "www 3600 IN A 192.0.2.10",
+                "www 300 IN AAAA 2001:db8::10",
+                "@ 3600 IN NS ns1",
+                "@ 3600 IN NS ns2.example.net.",
+                "@ 3600 IN MX 10 mail.example.net.",
+                "@ 3600 IN TXT \"v=spf1 -all\" \"say \\\"hi\\\"\"",
+                "ns1 3600 IN A 192.0.2.1",
+                "alias 3600 IN CNAME www",
+                "_sip._tcp 3600 IN SRV 10 60 5060 www",
+            ]
+        );
+    }
+}
@@ -0,0 +1,43 @@
+pub mod errors;
+pub mod export;
+mod parser;
+
+pub use parser::{parse_zone, parse_zone_strict, ZoneFile};
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        messages::packets::{ResourceRecord, ResourceRecordData, ResourceRecordType},
+        zone::parse_zone,
+    };
+
+    #[test]
+    fn test_parse_zone_into_records() {
+        let zone_file = parse_zone(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             www IN A 192.0.2.10\n\
+             @ IN MX 10 mail\n",
+        )
+        .unwrap();
+
+        assert_eq!(zone_file.origin(), Some("example.com."));
+        assert_eq!(zone_file.ttl(), Some(3600));
+
+        let records: Vec<ResourceRecord> = zone_file.into_records();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].domain, "www.example.com.");
+        assert_eq!(records[0].record_type, ResourceRecordType::ARecord);
+        assert_eq!(
+            records[0].data,
+            ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 10))
+        );
+        assert_eq!(
+            records[1].data,
+            ResourceRecordData::MX(10, "mail.example.com.".to_string())
+        );
+    }
+}
@@ -0,0 +1,766 @@
+use std::str::FromStr;
+
+use crate::messages::{
+    idna, label_bytes,
+    packets::{ResourceRecord, ResourceRecordClass, ResourceRecordData, SOARecord},
+};
+
+use super::errors::ZoneParserError;
+
+type ParserResult<T> = Result<T, ZoneParserError>;
+
+/// Records of a master file along with its directives
+pub struct ZoneFile {
+    origin: Option<String>,
+    ttl: Option<u32>,
+    records: Vec<ResourceRecord>,
+}
+
+impl ZoneFile {
+    /// Origin set by the last `$ORIGIN` directive
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_deref()
+    }
+
+    /// Default TTL set by the last `$TTL` directive
+    pub fn ttl(&self) -> Option<u32> {
+        self.ttl
+    }
+
+    pub fn records(&self) -> &[ResourceRecord] {
+        &self.records
+    }
+
+    pub fn into_records(self) -> Vec<ResourceRecord> {
+        self.records
+    }
+}
+
+/// An entry of a master file, parenthesized continuations are joined onto one entry
+struct Entry {
+    /// Line the entry starts on
+    line: usize,
+    /// Entry started with whitespace, reusing the previous owner name
+    blank_owner: bool,
+    /// Words of the entry, quoted strings are kept whole
+    tokens: Vec<String>,
+}
+
+/// Split a master file into entries, removing comments and parentheses
+fn entries(contents: &str) -> ParserResult<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut tokens = vec![];
+    let mut depth = 0;
+    let mut start_line = 1;
+    let mut blank_owner = false;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+
+        // Leading whitespace is only meaningful at the start of an entry
+        if depth == 0 {
+            start_line = line_number;
+            blank_owner = line.starts_with(char::is_whitespace);
+        }
+
+        let mut chars = line.chars();
+        let mut word = String::new();
+
+        while let Some(character) = chars.next() {
+            match character {
+                // Comment runs to the end of the line
+                ';' => break,
+                '"' => {
+                    flush(&mut word, &mut tokens);
+
+                    let mut text = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => unescape(&mut chars, &mut text)
+                                .map_err(|err| err.at(line_number))?,
+                            Some(character) => text.push(character),
+                            None => return Err(ZoneParserError::UnterminatedString.at(line_number)),
+                        }
+                    }
+
+                    // Decimal escapes are kept, for the record data to read as bytes
+                    tokens.push(text);
+                }
+                '(' => {
+                    flush(&mut word, &mut tokens);
+                    depth += 1;
+                }
+                ')' => {
+                    flush(&mut word, &mut tokens);
+
+                    if depth == 0 {
+                        return Err(ZoneParserError::UnbalancedParentheses.at(line_number));
+                    }
+
+                    depth -= 1;
+                }
+                character if character.is_whitespace() => flush(&mut word, &mut tokens),
+                character => word.push(character),
+            }
+        }
+
+        flush(&mut word, &mut tokens);
+
+        if depth == 0 && !tokens.is_empty() {
+            entries.push(Entry {
+                line: start_line,
+                blank_owner,
+                tokens: std::mem::take(&mut tokens),
+            });
+        }
+    }
+
+    // Unclosed parentheses are reported where the record started
+    if depth != 0 {
+        return Err(ZoneParserError::UnbalancedParentheses.at(start_line));
+    }
+
+    Ok(entries)
+}
+
+fn flush(word: &mut String, tokens: &mut Vec<String>) {
+    if !word.is_empty() {
+        tokens.push(std::mem::take(word));
+    }
+}
+
+/// Read an escape following a backslash into the text, either `\X` or the decimal `\DDD`
+///
+/// `\DDD` escapes are kept for `label_bytes` to read as the byte they stand for, so bytes of
+/// multibyte characters aren't read as characters of their own.
+fn unescape(chars: &mut std::str::Chars, text: &mut String) -> ParserResult<()> {
+    let character = chars.next().ok_or(ZoneParserError::UnterminatedString)?;
+
+    if !character.is_ascii_digit() {
+        // A backslash of its own is kept as an escape, so it isn't read as starting one
+        match character {
+            '\\' => text.push_str("\\092"),
+            character => text.push(character),
+        }
+
+        return Ok(());
+    }
+
+    let mut digits = String::from(character);
+
+    for _ in 0..2 {
+        digits.push(chars.next().ok_or(ZoneParserError::UnterminatedString)?);
+    }
+
+    if digits.parse::<u8>().is_err() {
+        return Err(ZoneParserError::InvalidData(format!("\\{}", digits)));
+    }
+
+    text.push('\\');
+    text.push_str(&digits);
+
+    Ok(())
+}
+
+/// Expand a name relative to the origin, `@` being the origin itself
+fn name(text: &str, origin: Option<&str>) -> ParserResult<String> {
+    if text == "@" {
+        return origin.map(str::to_string).ok_or(ZoneParserError::NoOrigin);
+    }
+
+    // Internationalized names are held in their `xn--` form, as on the wire
+    let text = idna::to_ascii(&text.to_lowercase());
+
+    if text.ends_with('.') {
+        return Ok(text);
+    }
+
+    let origin = origin.ok_or(ZoneParserError::NoOrigin)?;
+
+    // Avoid doubling the dot for names relative to the root
+    match origin {
+        "." => Ok(format!("{}.", text)),
+        origin => Ok(format!("{}.{}", text, origin)),
+    }
+}
+
+fn number<T: FromStr>(text: &str) -> ParserResult<T> {
+    text.parse()
+        .map_err(|_| ZoneParserError::InvalidData(text.to_string()))
+}
+
+fn parse_ttl(text: &str) -> ParserResult<u32> {
+    text.parse()
+        .map_err(|_| ZoneParserError::InvalidTtl(text.to_string()))
+}
+
+/// Parse the fields of a record entry following the owner, `[<ttl>] [<class>] <type> <rdata>`
+fn parse_record(
+    domain: String,
+    tokens: &[String],
+    origin: Option<&str>,
+    ttl: Option<u32>,
+    previous_class: Option<&ResourceRecordClass>,
+) -> ParserResult<ResourceRecord> {
+    let mut tokens = tokens.iter();
+
+    let mut next = tokens.next().ok_or(ZoneParserError::NoType)?;
+
+    // TTL and class are both optional and may come in either order
+    let mut time_to_live = None;
+    let mut class = None;
+
+    loop {
+        if time_to_live.is_none() && next.chars().all(|character| character.is_ascii_digit()) {
+            time_to_live = Some(parse_ttl(next)?);
+        } else if class.is_none() && next.eq_ignore_ascii_case("IN") {
+            class = Some(ResourceRecordClass::InternetAddress);
+        } else if class.is_none() && next.eq_ignore_ascii_case("CH") {
+            class = Some(ResourceRecordClass::Chaos);
+        } else {
+            break;
+        }
+
+        next = tokens.next().ok_or(ZoneParserError::NoType)?;
+    }
+
+    let time_to_live = match time_to_live {
+        Some(time_to_live) => time_to_live,
+        None => ttl.ok_or(ZoneParserError::NoTtl)?,
+    };
+
+    // An omitted class is the last one given, internet if there wasn't one
+    let class = class
+        .or_else(|| previous_class.cloned())
+        .unwrap_or(ResourceRecordClass::InternetAddress);
+
+    // Type mnemonics are alphanumeric, anything else is likely misplaced record data
+    if !next
+        .chars()
+        .all(|character| character.is_ascii_alphanumeric())
+    {
+        return Err(ZoneParserError::InvalidType);
+    }
+
+    let record_type = next.to_uppercase();
+    let rdata: Vec<&String> = tokens.collect();
+
+    let field = |index: usize| -> ParserResult<&str> {
+        rdata
+            .get(index)
+            .map(|token| token.as_str())
+            .ok_or_else(|| ZoneParserError::InvalidData(format!("{} record", record_type)))
+    };
+
+    let data = match record_type.as_str() {
+        "A" => ResourceRecordData::A(number(field(0)?)?),
+        "AAAA" => ResourceRecordData::AAAA(number(field(0)?)?),
+        "CNAME" => ResourceRecordData::CName(name(field(0)?, origin)?),
+        "NS" => ResourceRecordData::NS(name(field(0)?, origin)?),
+        "PTR" => ResourceRecordData::PTR(name(field(0)?, origin)?),
+        "MX" => ResourceRecordData::MX(number(field(0)?)?, name(field(1)?, origin)?),
+        "SRV" => ResourceRecordData::SRV {
+            priority: number(field(0)?)?,
+            weight: number(field(1)?)?,
+            port: number(field(2)?)?,
+            target: name(field(3)?, origin)?,
+        },
+        "SOA" => ResourceRecordData::SOA(SOARecord {
+            master_name: name(field(0)?, origin)?,
+            mail_name: name(field(1)?, origin)?,
+            serial: number(field(2)?)?,
+            refresh: number(field(3)?)?,
+            retry: number(field(4)?)?,
+            expire: number(field(5)?)?,
+            minimum: number(field(6)?)?,
+        }),
+        // Each token is its own character string
+        "TXT" => ResourceRecordData::TXT(rdata.iter().map(|token| label_bytes(token)).collect()),
+        "SPF" => ResourceRecordData::SPF(rdata.iter().map(|token| label_bytes(token)).collect()),
+        _ => return Err(ZoneParserError::UnsupportedType(next.clone())),
+    };
+
+    Ok(ResourceRecord {
+        domain,
+        record_type: data.get_type(),
+        class,
+        time_to_live,
+        data,
+    })
+}
+
+/// Whether the name is the origin or below it
+fn in_zone(name: &str, origin: &str) -> bool {
+    if origin == "." || name.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+
+    let suffix = format!(".{}", origin);
+
+    // Compare bytes, the suffix may not start on a character boundary of the name
+    name.len() > suffix.len()
+        && name.as_bytes()[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes())
+}
+
+/// Parse a master file (RFC 1035 section 5)
+pub fn parse_zone(contents: &str) -> ParserResult<ZoneFile> {
+    parse(contents, false)
+}
+
+/// Parse a master file, rejecting records owned by names outside the zone
+///
+/// The zone is the first `$ORIGIN` of the file, later `$ORIGIN` directives only change how
+/// relative names are expanded. Records before the first `$ORIGIN` are rejected, there being no
+/// zone to check them against.
+pub fn parse_zone_strict(contents: &str) -> ParserResult<ZoneFile> {
+    parse(contents, true)
+}
+
+fn parse(contents: &str, strict: bool) -> ParserResult<ZoneFile> {
+    let mut zone_file = ZoneFile {
+        origin: None,
+        ttl: None,
+        records: vec![],
+    };
+
+    let mut apex: Option<String> = None;
+
+    for entry in entries(contents)? {
+        let record_count = zone_file.records.len();
+
+        parse_entry(&mut zone_file, &entry).map_err(|err| err.at(entry.line))?;
+
+        if apex.is_none() {
+            apex = zone_file.origin.clone();
+        }
+
+        let record = match zone_file.records.get(record_count) {
+            Some(record) if strict => record,
+            _ => continue,
+        };
+
+        let apex = apex
+            .as_deref()
+            .ok_or_else(|| ZoneParserError::NoOrigin.at(entry.line))?;
+
+        if !in_zone(&record.domain, apex) {
+            return Err(ZoneParserError::OutOfZone(record.domain.clone()).at(entry.line));
+        }
+    }
+
+    Ok(zone_file)
+}
+
+/// Apply a directive or add a record to the zone file
+fn parse_entry(zone_file: &mut ZoneFile, entry: &Entry) -> ParserResult<()> {
+    let tokens = &entry.tokens;
+
+    let directive_value = || {
+        tokens
+            .get(1)
+            .map(|token| token.as_str())
+            .ok_or_else(|| ZoneParserError::InvalidData(tokens[0].clone()))
+    };
+
+    match tokens[0].as_str() {
+        "$ORIGIN" => {
+            zone_file.origin = Some(name(directive_value()?, zone_file.origin.as_deref())?)
+        }
+        "$TTL" => zone_file.ttl = Some(parse_ttl(directive_value()?)?),
+        _ => {
+            let previous = zone_file.records.last();
+
+            // Entries without an owner belong to the previous record's owner
+            let (domain, tokens) = if entry.blank_owner {
+                let domain = previous
+                    .map(|record| record.domain.clone())
+                    .ok_or(ZoneParserError::NoDomain)?;
+
+                (domain, &tokens[..])
+            } else {
+                (name(&tokens[0], zone_file.origin.as_deref())?, &tokens[1..])
+            };
+
+            let record = parse_record(
+                domain,
+                tokens,
+                zone_file.origin.as_deref(),
+                zone_file.ttl,
+                previous.map(|record| &record.class),
+            )?;
+
+            zone_file.records.push(record);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::messages::packets::ResourceRecordType;
+
+    #[test]
+    fn test_parse_relative_names() {
+        let zone_file = parse_zone(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             @ IN NS ns1\n\
+             www 300 IN CNAME @\n",
+        )
+        .unwrap();
+
+        assert_eq!(zone_file.origin.as_deref(), Some("example.com."));
+        assert_eq!(zone_file.records[0].domain, "example.com.");
+        assert_eq!(
+            zone_file.records[0].data,
+            ResourceRecordData::NS("ns1.example.com.".to_string())
+        );
+        assert_eq!(zone_file.records[0].time_to_live, 3600);
+        assert_eq!(zone_file.records[1].domain, "www.example.com.");
+        assert_eq!(zone_file.records[1].time_to_live, 300);
+        assert_eq!(
+            zone_file.records[1].data,
+            ResourceRecordData::CName("example.com.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_internationalized_names() {
+        let zone_file = parse_zone(
+            "$ORIGIN bücher.example.\n\
+             münchen 300 IN A 192.0.2.1\n",
+        )
+        .unwrap();
+
+        assert_eq!(zone_file.origin.as_deref(), Some("xn--bcher-kva.example."));
+        assert_eq!(
+            zone_file.records[0].domain,
+            "xn--mnchen-3ya.xn--bcher-kva.example."
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_line_soa() {
+        let zone_file = parse_zone(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             @ IN SOA ns1 hostmaster ( ; primary and contact\n\
+             \t2024010101 ; serial\n\
+             \t7200 3600 1209600 300 )\n\
+             @ IN TXT \"v=spf1 -all\" \"say \\\"hi\\\"\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(zone_file.records.len(), 2);
+        assert_eq!(
+            zone_file.records[0].record_type,
+            ResourceRecordType::SOARecord
+        );
+        assert_eq!(
+            zone_file.records[0].data,
+            ResourceRecordData::SOA(SOARecord {
+                master_name: "ns1.example.com.".to_string(),
+                mail_name: "hostmaster.example.com.".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            })
+        );
+        assert_eq!(
+            zone_file.records[1].data,
+            ResourceRecordData::TXT(vec![b"v=spf1 -all".to_vec(), b"say \"hi\"".to_vec()])
+        );
+    }
+
+    #[test]
+    fn test_parse_unbalanced_parentheses() {
+        assert!(matches!(
+            parse_zone("$ORIGIN example.com.\n@ 300 IN SOA ns1 hostmaster ( 1 2 3 4 5\n"),
+            Err(ZoneParserError::At { line: 2, source }) if matches!(*source, ZoneParserError::UnbalancedParentheses)
+        ));
+    }
+
+    #[test]
+    fn test_parse_unsupported_type() {
+        let err = parse_zone("$ORIGIN example.com.\n$TTL 3600\nwww IN BOGUS 192.0.2.1\n")
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            &err,
+            ZoneParserError::At { source, .. }
+                if matches!(source.as_ref(), ZoneParserError::UnsupportedType(token) if token == "BOGUS")
+        ));
+        assert!(err.to_string().contains("BOGUS"));
+    }
+
+    #[test]
+    fn test_parse_missing_and_invalid_type() {
+        assert!(matches!(
+            parse_zone("$ORIGIN example.com.\n$TTL 3600\nwww IN\n"),
+            Err(ZoneParserError::At { source, .. }) if matches!(*source, ZoneParserError::NoType)
+        ));
+        assert!(matches!(
+            parse_zone("$ORIGIN example.com.\n$TTL 3600\nwww IN 192.0.2.1\n"),
+            Err(ZoneParserError::At { source, .. }) if matches!(*source, ZoneParserError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_line() {
+        let err = parse_zone(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             @ IN SOA ns1 hostmaster (\n\
+             \t2024010101 7200 3600 1209600 300 )\n\
+             www IN A not-an-address\n",
+        )
+        .err()
+        .unwrap();
+
+        assert!(matches!(err, ZoneParserError::At { line: 5, .. }));
+        assert!(err.to_string().starts_with("Line 5: "));
+    }
+
+    fn parse_line(line: &str) -> ResourceRecord {
+        parse_zone(&format!("$ORIGIN example.com.\n$TTL 3600\n{}\n", line))
+            .unwrap()
+            .into_records()
+            .remove(0)
+    }
+
+    #[test]
+    fn test_parse_txt_escapes() {
+        // Decimal escapes are bytes, the two bytes of a UTF-8 character make up the character
+        assert_eq!(
+            parse_line("@ TXT \"caf\\195\\169\" \"\\065\" \"\\\\065\"").data,
+            ResourceRecordData::TXT(vec![
+                "café".as_bytes().to_vec(),
+                b"A".to_vec(),
+                b"\\065".to_vec()
+            ])
+        );
+
+        // Presented TXT data escapes non-ASCII bytes, reading it back gives the same string
+        let record = parse_line("@ TXT \"münchen\"");
+        let presented = record.to_presentation();
+
+        assert!(presented.contains("\\195\\188"));
+        assert_eq!(parse_line(&presented).data, record.data);
+
+        // Character strings are bytes, which needn't make up UTF-8
+        let record = parse_line("@ TXT \"\\200\"");
+
+        assert_eq!(record.data, ResourceRecordData::TXT(vec![vec![200]]));
+        assert_eq!(parse_line(&record.to_presentation()).data, record.data);
+
+        // Escapes must be a byte
+        assert!(parse_zone("$ORIGIN example.com.\n$TTL 3600\n@ TXT \"\\256\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_class_and_type() {
+        let record = parse_line("www IN A 192.0.2.1");
+
+        assert!(matches!(record.class, ResourceRecordClass::InternetAddress));
+        assert_eq!(record.time_to_live, 3600);
+    }
+
+    #[test]
+    fn test_parse_bare_type() {
+        let record = parse_line("www A 192.0.2.1");
+
+        assert!(matches!(record.class, ResourceRecordClass::InternetAddress));
+        assert_eq!(record.time_to_live, 3600);
+        assert_eq!(
+            record.data,
+            ResourceRecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn test_parse_ttl_then_class() {
+        let record = parse_line("www 300 IN A 192.0.2.1");
+
+        assert!(matches!(record.class, ResourceRecordClass::InternetAddress));
+        assert_eq!(record.time_to_live, 300);
+    }
+
+    #[test]
+    fn test_parse_class_then_ttl() {
+        let record = parse_line("www IN 300 A 192.0.2.1");
+
+        assert!(matches!(record.class, ResourceRecordClass::InternetAddress));
+        assert_eq!(record.time_to_live, 300);
+    }
+
+    #[test]
+    fn test_parse_class_defaults_to_previous() {
+        let records = parse_zone(
+            "$ORIGIN bind.\n\
+             $TTL 0\n\
+             version CH TXT \"dn-mess\"\n\
+             hostname TXT \"dn-mess\"\n",
+        )
+        .unwrap()
+        .into_records();
+
+        assert!(matches!(records[0].class, ResourceRecordClass::Chaos));
+        assert!(matches!(records[1].class, ResourceRecordClass::Chaos));
+    }
+
+    #[test]
+    fn test_parse_blank_owner_continuation() {
+        let records = parse_zone(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             @ IN NS ns1\n\
+             www IN A 192.0.2.1\n\
+             \tIN AAAA 2001:db8::1\n    \
+                 300 IN TXT \"web\"\n\
+             mail IN A 192.0.2.2\n",
+        )
+        .unwrap()
+        .into_records();
+
+        let domains: Vec<&str> = records
+            .iter()
+            .map(|record| record.domain.as_str())
+            .collect();
+
+        assert_eq!(
+            domains,
+            [
+                "example.com.",
+                "www.example.com.",
+                "www.example.com.",
+                "www.example.com.",
+                "mail.example.com.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_ttl_directive_anywhere() {
+        let zone_file = parse_zone(
+            "$TTL 3600\n\
+             $ORIGIN example.com.\n\
+             www IN A 192.0.2.1\n\
+             $TTL 60\n\
+             mail IN A 192.0.2.2\n\
+             ftp 120 IN A 192.0.2.3\n\
+             $TTL 7200\n\
+             ns1 IN A 192.0.2.4\n",
+        )
+        .unwrap();
+
+        // The last definition is kept, each record uses the one in effect when it was read
+        assert_eq!(zone_file.ttl(), Some(7200));
+
+        let ttls: Vec<u32> = zone_file
+            .records()
+            .iter()
+            .map(|record| record.time_to_live)
+            .collect();
+
+        assert_eq!(ttls, [3600, 60, 120, 7200]);
+
+        // Records with their own TTL don't need a default before them
+        let records = parse_zone(
+            "$ORIGIN example.com.\n\
+             www 300 IN A 192.0.2.1\n\
+             $TTL 60\n\
+             mail IN A 192.0.2.2\n",
+        )
+        .unwrap()
+        .into_records();
+
+        assert_eq!(records[0].time_to_live, 300);
+        assert_eq!(records[1].time_to_live, 60);
+    }
+
+    #[test]
+    fn test_parse_origin_directive_mid_file() {
+        let zone_file = parse_zone(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             www IN CNAME @\n\
+             $ORIGIN sub.example.com.\n\
+             host IN A 192.0.2.1\n\
+             @ IN MX 10 mail\n\
+             $ORIGIN deeper\n\
+             leaf IN A 192.0.2.2\n",
+        )
+        .unwrap();
+
+        // A relative origin is expanded against the one before it
+        assert_eq!(zone_file.origin(), Some("deeper.sub.example.com."));
+
+        let records = zone_file.into_records();
+
+        assert_eq!(records[0].domain, "www.example.com.");
+        assert_eq!(
+            records[0].data,
+            ResourceRecordData::CName("example.com.".to_string())
+        );
+        assert_eq!(records[1].domain, "host.sub.example.com.");
+        assert_eq!(records[2].domain, "sub.example.com.");
+        assert_eq!(
+            records[2].data,
+            ResourceRecordData::MX(10, "mail.sub.example.com.".to_string())
+        );
+        assert_eq!(records[3].domain, "leaf.deeper.sub.example.com.");
+    }
+
+    #[test]
+    fn test_strict_rejects_out_of_zone_records() {
+        let contents = "$ORIGIN example.com.\n\
+                        $TTL 3600\n\
+                        www IN A 192.0.2.1\n\
+                        mail.example.com. IN A 192.0.2.2\n\
+                        www.example.org. IN A 192.0.2.3\n";
+
+        assert!(matches!(
+            parse_zone_strict(contents),
+            Err(ZoneParserError::At { line: 5, source })
+                if matches!(source.as_ref(), ZoneParserError::OutOfZone(name) if name == "www.example.org.")
+        ));
+
+        // Lenient parsing keeps the record
+        assert_eq!(parse_zone(contents).unwrap().records().len(), 3);
+
+        // Names below a later origin are still in the zone, a suffix match isn't enough
+        assert!(parse_zone_strict(
+            "$ORIGIN example.com.\n\
+             $TTL 3600\n\
+             $ORIGIN sub.example.com.\n\
+             @ IN A 192.0.2.1\n\
+             host IN A 192.0.2.2\n",
+        )
+        .is_ok());
+        assert!(matches!(
+            parse_zone_strict("$ORIGIN example.com.\n$TTL 3600\nnotexample.com. IN A 192.0.2.1\n"),
+            Err(ZoneParserError::At { source, .. }) if matches!(*source, ZoneParserError::OutOfZone(_))
+        ));
+        assert!(matches!(
+            parse_zone_strict("$TTL 3600\nwww.example.com. IN A 192.0.2.1\n"),
+            Err(ZoneParserError::At { source, .. }) if matches!(*source, ZoneParserError::NoOrigin)
+        ));
+    }
+
+    #[test]
+    fn test_parse_blank_owner_without_previous() {
+        assert!(matches!(
+            parse_zone("$ORIGIN example.com.\n$TTL 3600\n  IN A 192.0.2.1\n"),
+            Err(ZoneParserError::At { source, .. }) if matches!(*source, ZoneParserError::NoDomain)
+        ));
+    }
+}